@@ -0,0 +1,57 @@
+use std::fmt;
+
+use chrono::{DateTime, Local};
+use serde::Serialize;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum AnomalyKind {
+    IncompleteFragments,
+    OverlappingFragments,
+    OversizedDatagram,
+    RogueRouterAdvertisement,
+    RogueDhcpServer,
+    ArpMacChange,
+    ArpAddressConflict,
+    ArpReplyFlood,
+    ConnectionTimedOut
+}
+
+impl fmt::Display for AnomalyKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AnomalyKind::IncompleteFragments => write!(f, "Incomplete fragments"),
+            AnomalyKind::OverlappingFragments => write!(f, "Overlapping fragments"),
+            AnomalyKind::OversizedDatagram => write!(f, "Oversized fragmented datagram"),
+            AnomalyKind::RogueRouterAdvertisement => write!(f, "Rogue router advertisement"),
+            AnomalyKind::RogueDhcpServer => write!(f, "Rogue DHCP server"),
+            AnomalyKind::ArpMacChange => write!(f, "ARP MAC change"),
+            AnomalyKind::ArpAddressConflict => write!(f, "ARP address conflict"),
+            AnomalyKind::ArpReplyFlood => write!(f, "Unsolicited ARP replies"),
+            AnomalyKind::ConnectionTimedOut => write!(f, "TCP connection timed out")
+        }
+    }
+}
+
+/// Something suspicious noticed while parsing, reported at the end of every report
+#[derive(Debug, Clone, Serialize)]
+pub struct Anomaly {
+    pub timestamp: DateTime<Local>,
+    pub kind: AnomalyKind,
+    pub description: String
+}
+
+impl Anomaly {
+    pub fn new(timestamp: DateTime<Local>, kind: AnomalyKind, description: String) -> Self {
+        Anomaly {
+            timestamp,
+            kind,
+            description
+        }
+    }
+}
+
+impl fmt::Display for Anomaly {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "| {0: <15}\t| {1: <25}\t| {2}", self.timestamp, self.kind, self.description)
+    }
+}
@@ -1,3 +1,5 @@
+use std::net::IpAddr;
+
 use clap::Parser;
 
 #[derive(Parser)]
@@ -11,9 +13,9 @@ pub struct Args {
     #[arg(short, long, default_value = "report")]
     pub filename: String,
 
-    /// Name of the interface to be used for the sniffing
-    #[arg(short, long, default_value = "listview__")]
-    pub interface: String,
+    /// Name of the interface to be used for the sniffing, repeat it to sniff on several interfaces at once ("any" for all of them)
+    #[arg(short, long)]
+    pub interface: Vec<String>,
 
     /// Set the interface in promiscuous mode
     #[arg(short, long, action)]
@@ -25,5 +27,41 @@ pub struct Args {
 
     /// Set report file type to csv instead of default txt
     #[arg(long, action)]
-    pub csv: bool
+    pub csv: bool,
+
+    /// Read packets from a pcap/pcapng file instead of a live interface ("-" reads from stdin)
+    #[arg(short, long)]
+    pub read: Option<String>,
+
+    /// Save every captured frame in a pcap savefile next to the reports (<filename>-<seq_num>.pcap)
+    #[arg(short, long, action)]
+    pub savefile: bool,
+
+    /// Rotate the savefile once it grows over the given size (in MB)
+    #[arg(long)]
+    pub savefile_size: Option<u64>,
+
+    /// Address of a legitimate IPv6 router, repeat it for several routers (by default the first one advertising on each interface)
+    #[arg(long)]
+    pub router: Vec<IpAddr>,
+
+    /// Address of a legitimate DHCP server, repeat it for several servers (by default the first one answering on each interface)
+    #[arg(long)]
+    pub dhcp_server: Vec<IpAddr>,
+
+    /// Write the reassembled payload of every TCP connection in the "streams" folder next to the reports
+    #[arg(long, action)]
+    pub dump_streams: bool,
+
+    /// Write the names resolved by the observed DNS answers next to the reports (<filename>-dns-cache.txt)
+    #[arg(long, action)]
+    pub dns_cache: bool,
+
+    /// File mapping ports to applications in the /etc/services format, extending or overriding the built-in table
+    #[arg(long)]
+    pub services: Option<String>,
+
+    /// File of MAC address vendors in the IEEE oui.txt or Wireshark manuf format, extending or overriding the bundled list
+    #[arg(long)]
+    pub oui: Option<String>
 }
@@ -0,0 +1,360 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::net::Ipv4Addr;
+
+use chrono::{DateTime, Duration, Local};
+use pktparse::arp::{ArpPacket, Operation};
+use pktparse::ethernet::MacAddress;
+use serde::Serialize;
+
+use crate::anomaly::{Anomaly, AnomalyKind};
+use crate::utils::{join_values, mac_to_str};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArpOperation {
+    Request,
+    Reply,
+    /// Announcement of the sender's own address, request or reply
+    Gratuitous,
+    /// Address conflict detection (RFC 5227), sent without an address
+    Probe,
+    Other(u16)
+}
+
+impl fmt::Display for ArpOperation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ArpOperation::Request => write!(f, "request"),
+            ArpOperation::Reply => write!(f, "reply"),
+            ArpOperation::Gratuitous => write!(f, "gratuitous"),
+            ArpOperation::Probe => write!(f, "probe"),
+            ArpOperation::Other(raw) => write!(f, "operation {}", raw)
+        }
+    }
+}
+
+/// Content of an ARP packet (Ethernet and IPv4 addresses)
+#[derive(Debug, Clone)]
+pub struct ArpMessage {
+    pub operation: ArpOperation,
+    pub sender_mac: MacAddress,
+    pub sender_addr: Ipv4Addr,
+    pub target_mac: MacAddress,
+    pub target_addr: Ipv4Addr
+}
+
+impl ArpMessage {
+    pub fn from_packet(packet: &ArpPacket) -> Self {
+        let operation = match packet.operation {
+            _ if packet.src_addr.is_unspecified() => ArpOperation::Probe,
+            Operation::Request | Operation::Reply if packet.src_addr == packet.dest_addr => ArpOperation::Gratuitous,
+            Operation::Request => ArpOperation::Request,
+            Operation::Reply => ArpOperation::Reply,
+            Operation::Other(raw) => ArpOperation::Other(raw)
+        };
+
+        ArpMessage {
+            operation,
+            sender_mac: packet.src_mac,
+            sender_addr: packet.src_addr,
+            target_mac: packet.dest_mac,
+            target_addr: packet.dest_addr
+        }
+    }
+}
+
+/// Address claimed by a MAC on a segment
+#[derive(Debug, Clone, Serialize)]
+pub struct ArpEntry {
+    pub interface: String,
+    pub vlan: String,
+    pub address: Ipv4Addr,
+    pub mac: String,
+    /// Times the address moved to another MAC
+    pub changes: u32,
+    pub first_seen: DateTime<Local>,
+    pub last_seen: DateTime<Local>
+}
+
+impl fmt::Display for ArpEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "| {0: <2}\t| {1: <4}\t| {2: <15}\t| {3: <17}\t| {4: <4}\t| {5: <15}\t| {6: <15}", self.interface, self.vlan, self.address, self.mac, self.changes, self.first_seen, self.last_seen)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct SegmentKey {
+    interface: String,
+    vlan: Vec<u16>
+}
+
+struct Binding {
+    entry: ArpEntry,
+    mac: MacAddress,
+    /// MAC the address had before the last change, and when it was last seen
+    previous: Option<(MacAddress, DateTime<Local>)>
+}
+
+struct ReplyCounter {
+    window_start: DateTime<Local>,
+    count: u32,
+    reported: bool
+}
+
+/// IP to MAC bindings seen during the session, on every segment (interface and VLAN)
+pub struct ArpTable {
+    pub max_entries: usize,
+    /// Both MACs claiming the address within this time are a conflict rather than a change
+    pub conflict_window: Duration,
+    /// Unsolicited replies allowed on a segment within `flood_window`
+    pub flood_threshold: u32,
+    pub flood_window: Duration,
+    bindings: HashMap<(SegmentKey, Ipv4Addr), Binding>,
+    /// Requests waiting for their reply, by segment, requester and requested address
+    requests: HashMap<(SegmentKey, Ipv4Addr, Ipv4Addr), DateTime<Local>>,
+    unsolicited: HashMap<SegmentKey, ReplyCounter>
+}
+
+impl ArpTable {
+    pub fn new(max_entries: usize, conflict_window: Duration, flood_threshold: u32, flood_window: Duration) -> Self {
+        ArpTable {
+            max_entries,
+            conflict_window,
+            flood_threshold,
+            flood_window,
+            bindings: HashMap::new(),
+            requests: HashMap::new(),
+            unsolicited: HashMap::new()
+        }
+    }
+
+    /// Learn the binding of the sender, reporting the addresses moving between MACs and the
+    /// replies nobody asked for
+    pub fn update(&mut self, interface: &str, vlan: &[u16], message: &ArpMessage, timestamp: DateTime<Local>, anomalies: &mut Vec<Anomaly>) {
+        let segment = SegmentKey { interface: interface.to_string(), vlan: vlan.to_vec() };
+        let segment_name = match vlan.is_empty() {
+            true => interface.to_string(),
+            false => format!("{} (VLAN {})", interface, join_values(vlan, "."))
+        };
+
+        match message.operation {
+            ArpOperation::Request => {
+                if self.requests.len() >= self.max_entries {
+                    self.expire_requests(timestamp);
+                }
+                if self.requests.len() < self.max_entries {
+                    self.requests.insert((segment.clone(), message.sender_addr, message.target_addr), timestamp);
+                }
+            },
+            ArpOperation::Reply => {
+                let request = self.requests.remove(&(segment.clone(), message.target_addr, message.sender_addr));
+                let solicited = request.map(|asked| timestamp - asked <= self.flood_window).unwrap_or(false);
+                if !solicited {
+                    self.count_unsolicited(&segment, &segment_name, timestamp, anomalies);
+                }
+            },
+            ArpOperation::Gratuitous => self.count_unsolicited(&segment, &segment_name, timestamp, anomalies),
+            // Probes have no sender address to learn
+            ArpOperation::Probe | ArpOperation::Other(_) => return
+        }
+
+        self.learn(segment, &segment_name, message, timestamp, anomalies);
+    }
+
+    fn learn(&mut self, segment: SegmentKey, segment_name: &str, message: &ArpMessage, timestamp: DateTime<Local>, anomalies: &mut Vec<Anomaly>) {
+        let key = (segment, message.sender_addr);
+        if !self.bindings.contains_key(&key) && self.bindings.len() >= self.max_entries {
+            // Make room dropping the binding seen the longest time ago
+            let oldest = self.bindings.iter()
+                .min_by_key(|(_, binding)| binding.entry.last_seen)
+                .map(|(key, _)| key.clone());
+            if let Some(oldest) = oldest {
+                self.bindings.remove(&oldest);
+            }
+        }
+
+        let binding = self.bindings.entry(key.clone()).or_insert_with(|| Binding {
+            entry: ArpEntry {
+                interface: key.0.interface.clone(),
+                vlan: join_values(&key.0.vlan, "."),
+                address: message.sender_addr,
+                mac: mac_to_str(message.sender_mac),
+                changes: 0,
+                first_seen: timestamp,
+                last_seen: timestamp
+            },
+            mac: message.sender_mac,
+            previous: None
+        });
+
+        if binding.mac != message.sender_mac {
+            // The previous MAC claiming the address back soon after is still alive: two hosts share it
+            let (kind, description) = match binding.previous {
+                Some((previous, seen)) if previous == message.sender_mac && timestamp - seen <= self.conflict_window => (
+                    AnomalyKind::ArpAddressConflict,
+                    format!("{} on {} claimed by {} and {}", message.sender_addr, segment_name, mac_to_str(binding.mac), mac_to_str(message.sender_mac))
+                ),
+                _ => (
+                    AnomalyKind::ArpMacChange,
+                    format!("{} on {} moved from {} to {} ({})", message.sender_addr, segment_name, mac_to_str(binding.mac), mac_to_str(message.sender_mac), message.operation)
+                )
+            };
+            anomalies.push(Anomaly::new(timestamp, kind, description));
+
+            binding.previous = Some((binding.mac, binding.entry.last_seen));
+            binding.mac = message.sender_mac;
+            binding.entry.mac = mac_to_str(message.sender_mac);
+            binding.entry.changes += 1;
+        }
+        binding.entry.last_seen = timestamp;
+    }
+
+    fn count_unsolicited(&mut self, segment: &SegmentKey, segment_name: &str, timestamp: DateTime<Local>, anomalies: &mut Vec<Anomaly>) {
+        let counter = self.unsolicited.entry(segment.clone()).or_insert(ReplyCounter {
+            window_start: timestamp,
+            count: 0,
+            reported: false
+        });
+        if timestamp - counter.window_start > self.flood_window {
+            *counter = ReplyCounter { window_start: timestamp, count: 0, reported: false };
+        }
+        counter.count += 1;
+
+        // Reported once per window
+        if counter.count > self.flood_threshold && !counter.reported {
+            counter.reported = true;
+            anomalies.push(Anomaly::new(
+                timestamp,
+                AnomalyKind::ArpReplyFlood,
+                format!("more than {} unsolicited ARP replies in {}s on {}", self.flood_threshold, self.flood_window.num_seconds(), segment_name)
+            ));
+        }
+    }
+
+    fn expire_requests(&mut self, now: DateTime<Local>) {
+        let window = self.flood_window;
+        self.requests.retain(|_, asked| now - *asked <= window);
+    }
+
+    /// Drop the requests left without a reply
+    pub fn expire(&mut self, now: DateTime<Local>) {
+        self.expire_requests(now);
+    }
+
+    /// Every binding of the table, ordered by segment and address
+    pub fn entries(&self) -> Vec<ArpEntry> {
+        let mut entries: Vec<ArpEntry> = self.bindings.values().map(|binding| binding.entry.clone()).collect();
+        entries.sort_by(|a, b| a.interface.cmp(&b.interface).then(a.vlan.cmp(&b.vlan)).then(a.address.cmp(&b.address)));
+        entries
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HOST: MacAddress = MacAddress([0x02, 0, 0, 0, 0, 0x01]);
+    const ROUTER: MacAddress = MacAddress([0x02, 0, 0, 0, 0, 0xfe]);
+    const ATTACKER: MacAddress = MacAddress([0x02, 0, 0, 0, 0, 0x66]);
+    const BROADCAST: MacAddress = MacAddress([0; 6]);
+
+    fn message(operation: ArpOperation, sender_mac: MacAddress, sender_addr: &str, target_mac: MacAddress, target_addr: &str) -> ArpMessage {
+        ArpMessage {
+            operation,
+            sender_mac,
+            sender_addr: sender_addr.parse().unwrap(),
+            target_mac,
+            target_addr: target_addr.parse().unwrap()
+        }
+    }
+
+    fn table() -> ArpTable {
+        ArpTable::new(64, Duration::seconds(5), 3, Duration::seconds(10))
+    }
+
+    fn kinds(anomalies: &[Anomaly]) -> Vec<AnomalyKind> {
+        anomalies.iter().map(|anomaly| anomaly.kind).collect()
+    }
+
+    #[test]
+    fn solicited_replies_are_learnt_quietly() {
+        let mut table = table();
+        let mut anomalies = Vec::new();
+        let now = Local::now();
+
+        table.update("eth0", &[], &message(ArpOperation::Request, HOST, "10.0.0.1", BROADCAST, "10.0.0.254"), now, &mut anomalies);
+        table.update("eth0", &[], &message(ArpOperation::Reply, ROUTER, "10.0.0.254", HOST, "10.0.0.1"), now, &mut anomalies);
+
+        assert!(anomalies.is_empty());
+        let entries = table.entries();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[1].address, "10.0.0.254".parse::<Ipv4Addr>().unwrap());
+        assert_eq!(entries[1].mac, mac_to_str(ROUTER));
+    }
+
+    #[test]
+    fn address_moving_to_another_mac() {
+        let mut table = table();
+        let mut anomalies = Vec::new();
+        let now = Local::now();
+
+        table.update("eth0", &[10], &message(ArpOperation::Gratuitous, ROUTER, "10.0.0.254", BROADCAST, "10.0.0.254"), now, &mut anomalies);
+        table.update("eth0", &[10], &message(ArpOperation::Gratuitous, ATTACKER, "10.0.0.254", BROADCAST, "10.0.0.254"), now + Duration::seconds(1), &mut anomalies);
+
+        assert_eq!(kinds(&anomalies), [AnomalyKind::ArpMacChange]);
+        assert!(anomalies[0].description.contains("on eth0 (VLAN 10)"));
+        assert_eq!(table.entries()[0].changes, 1);
+        assert_eq!(table.entries()[0].mac, mac_to_str(ATTACKER));
+        // The same address on another VLAN is another binding
+        table.update("eth0", &[20], &message(ArpOperation::Gratuitous, ROUTER, "10.0.0.254", BROADCAST, "10.0.0.254"), now + Duration::seconds(1), &mut anomalies);
+        assert_eq!(anomalies.len(), 1);
+    }
+
+    #[test]
+    fn previous_mac_claiming_the_address_back_is_a_conflict() {
+        let mut table = table();
+        let mut anomalies = Vec::new();
+        let now = Local::now();
+        let gratuitous = |mac| message(ArpOperation::Gratuitous, mac, "10.0.0.254", BROADCAST, "10.0.0.254");
+
+        table.update("eth0", &[], &gratuitous(ROUTER), now, &mut anomalies);
+        table.update("eth0", &[], &gratuitous(ATTACKER), now + Duration::seconds(1), &mut anomalies);
+        table.update("eth0", &[], &gratuitous(ROUTER), now + Duration::seconds(2), &mut anomalies);
+        // Out of the conflict window, only a change
+        table.update("eth0", &[], &gratuitous(ATTACKER), now + Duration::seconds(30), &mut anomalies);
+
+        assert_eq!(kinds(&anomalies), [AnomalyKind::ArpMacChange, AnomalyKind::ArpAddressConflict, AnomalyKind::ArpMacChange]);
+        assert_eq!(anomalies[1].description, format!("10.0.0.254 on eth0 claimed by {} and {}", mac_to_str(ATTACKER), mac_to_str(ROUTER)));
+    }
+
+    #[test]
+    fn unsolicited_replies_flood_reported_once_per_window() {
+        let mut table = table();
+        let mut anomalies = Vec::new();
+        let now = Local::now();
+
+        for i in 0..10 {
+            let reply = message(ArpOperation::Reply, ATTACKER, "10.0.0.254", HOST, "10.0.0.1");
+            table.update("eth0", &[], &reply, now + Duration::milliseconds(i * 100), &mut anomalies);
+        }
+        assert_eq!(kinds(&anomalies), [AnomalyKind::ArpReplyFlood]);
+
+        // The next window starts counting again
+        for i in 0..4 {
+            let reply = message(ArpOperation::Reply, ATTACKER, "10.0.0.254", HOST, "10.0.0.1");
+            table.update("eth0", &[], &reply, now + Duration::seconds(20) + Duration::milliseconds(i * 100), &mut anomalies);
+        }
+        assert_eq!(anomalies.len(), 2);
+    }
+
+    #[test]
+    fn probes_are_not_learnt() {
+        let mut table = table();
+        let mut anomalies = Vec::new();
+
+        table.update("eth0", &[], &message(ArpOperation::Probe, HOST, "0.0.0.0", BROADCAST, "10.0.0.1"), Local::now(), &mut anomalies);
+
+        assert!(anomalies.is_empty());
+        assert!(table.entries().is_empty());
+    }
+}
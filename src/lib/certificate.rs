@@ -0,0 +1,125 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use chrono::{DateTime, Local, TimeZone};
+use x509_parser::extensions::GeneralName;
+use x509_parser::parse_x509_certificate;
+use x509_parser::x509::X509Name;
+
+/// Metadata of an X.509 certificate sent in clear text during a TLS handshake
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CertificateInfo {
+    /// Common name of the subject, the whole distinguished name if it has none
+    pub subject: String,
+    /// DNS names and addresses of the subject alternative names extension
+    pub sans: Vec<String>,
+    pub issuer: String,
+    pub not_before: DateTime<Local>,
+    pub not_after: DateTime<Local>,
+    /// Issued by its own subject: the issuer name is the subject name, the signature is not
+    /// checked against the key of the certificate
+    pub self_signed: bool
+}
+
+impl CertificateInfo {
+    pub fn is_expired(&self, now: DateTime<Local>) -> bool {
+        now > self.not_after
+    }
+
+    pub fn is_not_yet_valid(&self, now: DateTime<Local>) -> bool {
+        now < self.not_before
+    }
+}
+
+fn name_to_str(name: &X509Name) -> String {
+    name.iter_common_name()
+        .next()
+        .and_then(|common_name| common_name.as_str().ok())
+        .map(|common_name| common_name.to_string())
+        .unwrap_or(name.to_string())
+}
+
+/// Parse a DER encoded certificate
+pub fn parse_certificate(der: &[u8]) -> Option<CertificateInfo> {
+    let (_, certificate) = parse_x509_certificate(der).ok()?;
+    let validity = certificate.validity();
+
+    let mut sans = Vec::new();
+    if let Ok(Some(extension)) = certificate.subject_alternative_name() {
+        for name in &extension.value.general_names {
+            match name {
+                GeneralName::DNSName(dns_name) => sans.push(dns_name.to_string()),
+                GeneralName::IPAddress(address) => {
+                    let address = match address.len() {
+                        4 => IpAddr::V4(Ipv4Addr::from(<[u8; 4]>::try_from(*address).unwrap())),
+                        16 => IpAddr::V6(Ipv6Addr::from(<[u8; 16]>::try_from(*address).unwrap())),
+                        _ => continue
+                    };
+                    sans.push(address.to_string());
+                },
+                _ => ()
+            }
+        }
+    }
+
+    Some(CertificateInfo {
+        subject: name_to_str(certificate.subject()),
+        sans,
+        issuer: name_to_str(certificate.issuer()),
+        not_before: Local.timestamp_opt(validity.not_before.timestamp(), 0).single()?,
+        not_after: Local.timestamp_opt(validity.not_after.timestamp(), 0).single()?,
+        self_signed: certificate.subject().as_raw() == certificate.issuer().as_raw()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Utc;
+
+    // Issued for www.example.com by "Example Root CA", valid in 2025
+    const ISSUED: &[u8] = include_bytes!("../../tests/data/issued.der");
+    // Issued by "C=IT, O=Example Router" to itself, without a common name, valid from 2024 to 2034
+    const SELF_SIGNED: &[u8] = include_bytes!("../../tests/data/self-signed.der");
+
+    fn time(year: i32) -> DateTime<Local> {
+        Local.timestamp_opt(Utc.with_ymd_and_hms(year, 1, 1, 0, 0, 0).unwrap().timestamp(), 0).unwrap()
+    }
+
+    #[test]
+    fn certificate_issued_by_a_ca() {
+        let certificate = parse_certificate(ISSUED).unwrap();
+
+        assert_eq!(certificate.subject, "www.example.com");
+        assert_eq!(certificate.issuer, "Example Root CA");
+        assert_eq!(certificate.sans, ["www.example.com", "example.com", "192.0.2.10", "2001:db8::10"]);
+        assert_eq!(certificate.not_before, time(2025));
+        assert_eq!(certificate.not_after, time(2026));
+        assert!(!certificate.self_signed);
+    }
+
+    #[test]
+    fn validity() {
+        let certificate = parse_certificate(ISSUED).unwrap();
+
+        assert!(certificate.is_not_yet_valid(time(2024)));
+        assert!(!certificate.is_not_yet_valid(time(2025)) && !certificate.is_expired(time(2025)));
+        assert!(certificate.is_expired(time(2027)));
+    }
+
+    #[test]
+    fn self_signed_certificate_without_common_name() {
+        let certificate = parse_certificate(SELF_SIGNED).unwrap();
+
+        assert!(certificate.self_signed);
+        // The whole distinguished name stands for the missing common name
+        assert_eq!(certificate.subject, "C=IT, O=Example Router");
+        assert_eq!(certificate.issuer, certificate.subject);
+        assert!(certificate.sans.is_empty());
+    }
+
+    #[test]
+    fn not_a_certificate() {
+        assert!(parse_certificate(&ISSUED[..ISSUED.len() / 2]).is_none());
+        assert!(parse_certificate(b"-----BEGIN CERTIFICATE-----").is_none());
+    }
+}
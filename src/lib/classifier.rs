@@ -0,0 +1,360 @@
+use std::collections::HashMap;
+use std::fs::read_to_string;
+use std::io;
+
+use chrono::{DateTime, Duration, Local};
+
+use crate::connection::ConnectionKey;
+use crate::dns::parse_dns;
+use crate::http::looks_like_http;
+use crate::parser::Packet;
+use crate::tls::looks_like_tls;
+
+/// Applications by TCP and UDP port, the built-in ones extended (or overridden) by a services file
+pub struct ServiceTable {
+    tcp: HashMap<u16, String>,
+    udp: HashMap<u16, String>
+}
+
+impl ServiceTable {
+    /// The built-in table of well-known ports
+    pub fn builtin() -> Self {
+        let tcp = [
+            (21, "ftp"),
+            (22, "ssh"),
+            (23, "telnet"),
+            (25, "smtp"),
+            (53, "DNS"),
+            (80, "http"),
+            (110, "POP3"),
+            (143, "IMAP"),
+            (194, "IRC"),
+            (443, "https"),
+            (445, "SMB"),
+            (587, "smtp"),
+            (853, "DNSOverTLS"),
+            (993, "IMAPS"),
+            (995, "POP3S"),
+            (3389, "RDP"),
+            (5060, "SIP")
+        ];
+        let udp = [
+            (53, "DNS"),
+            (67, "DHCP"),
+            (68, "DHCP"),
+            (69, "TFTP"),
+            (123, "NTP"),
+            (161, "SNMP"),
+            (162, "SNMP"),
+            (443, "QUIC"),
+            (1900, "SSDP"),
+            (3478, "STUN"),
+            (5060, "SIP"),
+            (5353, "mDNS"),
+            (5355, "LLMNR")
+        ];
+
+        ServiceTable {
+            tcp: tcp.iter().map(|(port, name)| (*port, name.to_string())).collect(),
+            udp: udp.iter().map(|(port, name)| (*port, name.to_string())).collect()
+        }
+    }
+
+    /// Add the services of a file in the /etc/services format (`<name> <port>/<tcp|udp> [aliases] [# comment]`),
+    /// replacing the built-in ones on the same port
+    pub fn load(&mut self, path: &str) -> io::Result<()> {
+        let content = read_to_string(path)?;
+        for (number, line) in content.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let invalid = || io::Error::new(io::ErrorKind::InvalidData, format!("line {}: expected <name> <port>/<tcp|udp>", number + 1));
+            let mut fields = line.split_whitespace();
+            let name = fields.next().ok_or_else(invalid)?;
+            let (port, protocol) = fields.next().and_then(|field| field.split_once('/')).ok_or_else(invalid)?;
+            let port = port.parse::<u16>().map_err(|_| invalid())?;
+            let table = match protocol.to_ascii_lowercase().as_str() {
+                "tcp" => &mut self.tcp,
+                "udp" => &mut self.udp,
+                // e.g. sctp and ddp in /etc/services
+                _ => continue
+            };
+            table.insert(port, name.to_string());
+        }
+        Ok(())
+    }
+
+    pub fn lookup(&self, tcp: bool, port: u16) -> Option<&str> {
+        let table = if tcp { &self.tcp } else { &self.udp };
+        table.get(&port).map(|name| name.as_str())
+    }
+}
+
+/// Recognize the application from the first bytes of a TCP or UDP payload
+pub fn payload_signature(tcp: bool, payload: &[u8]) -> Option<&'static str> {
+    let starts_with_any = |prefixes: &[&[u8]]| prefixes.iter().any(|prefix| payload.starts_with(prefix));
+    let contains = |needle: &[u8]| payload.windows(needle.len()).any(|window| window.eq_ignore_ascii_case(needle));
+
+    if tcp {
+        if looks_like_http(payload) {
+            return Some("http");
+        }
+        if looks_like_tls(payload) {
+            return Some("tls");
+        }
+        if payload.starts_with(b"SSH-") {
+            return Some("ssh");
+        }
+        if payload.starts_with(b"\x13BitTorrent protocol") {
+            return Some("BitTorrent");
+        }
+        // Greetings of the servers and first commands of the clients
+        if (payload.starts_with(b"220") && contains(b"SMTP")) || starts_with_any(&[b"EHLO ", b"HELO "]) {
+            return Some("smtp");
+        }
+        if payload.starts_with(b"220") && contains(b"FTP") {
+            return Some("ftp");
+        }
+        if payload.starts_with(b"+OK") {
+            return Some("POP3");
+        }
+        if payload.starts_with(b"* OK") {
+            return Some("IMAP");
+        }
+        if starts_with_any(&[b"INVITE sip:", b"REGISTER sip:", b"OPTIONS sip:", b"SIP/2.0 "]) {
+            return Some("SIP");
+        }
+        // SMB1/SMB2 header after the NetBIOS session header
+        if payload.len() >= 8 && payload[0] == 0 && (&payload[4..8] == b"\xffSMB" || &payload[4..8] == b"\xfeSMB") {
+            return Some("SMB");
+        }
+        // TPKT header followed by a X.224 Connection Request/Confirm
+        if payload.len() >= 6 && payload[0] == 3 && payload[1] == 0 && (payload[5] == 0xe0 || payload[5] == 0xd0) {
+            return Some("RDP");
+        }
+        // DNS over TCP: the message prefixed by its length
+        if payload.len() > 2 && u16::from_be_bytes([payload[0], payload[1]]) as usize == payload.len() - 2 && parse_dns(&payload[2..], false).is_some() {
+            return Some("DNS");
+        }
+        return None;
+    }
+
+    // QUIC long header packets (Initial, Handshake, ...) carry the version: v1, v2 or a draft
+    if payload.len() >= 5 && payload[0] & 0xc0 == 0xc0 {
+        let version = u32::from_be_bytes([payload[1], payload[2], payload[3], payload[4]]);
+        if version == 0x00000001 || version == 0x6b3343cf || version >> 8 == 0xff0000 {
+            return Some("QUIC");
+        }
+    }
+    // BOOTP with the DHCP magic cookie
+    if payload.len() >= 240 && payload[236..240] == [0x63, 0x82, 0x53, 0x63] {
+        return Some("DHCP");
+    }
+    // STUN magic cookie
+    if payload.len() >= 20 && payload[4..8] == [0x21, 0x12, 0xa4, 0x42] {
+        return Some("STUN");
+    }
+    if starts_with_any(&[b"M-SEARCH * ", b"NOTIFY * "]) {
+        return Some("SSDP");
+    }
+    if starts_with_any(&[b"INVITE sip:", b"REGISTER sip:", b"OPTIONS sip:", b"SIP/2.0 "]) {
+        return Some("SIP");
+    }
+    // Bencoded DHT queries and responses
+    if payload.starts_with(b"d1:") && (contains(b"1:y1:q") || contains(b"1:y1:r")) {
+        return Some("BitTorrent");
+    }
+    // Client, server or broadcast NTPv3/v4 packet with no extension
+    if payload.len() == 48 && matches!((payload[0] >> 3) & 0x07, 3 | 4) && matches!(payload[0] & 0x07, 1..=5) {
+        return Some("NTP");
+    }
+    None
+}
+
+struct Verdict {
+    application: String,
+    last_seen: DateTime<Local>
+}
+
+/// Application of the TCP and UDP flows: from the ports first, then from the payload. A flow
+/// recognized by its payload keeps the verdict (both directions) until it goes idle
+pub struct Classifier {
+    pub services: ServiceTable,
+    pub timeout: Duration,
+    pub max_flows: usize,
+    verdicts: HashMap<(ConnectionKey, bool), Verdict>
+}
+
+impl Classifier {
+    pub fn new(services: ServiceTable, timeout: Duration, max_flows: usize) -> Self {
+        Classifier {
+            services,
+            timeout,
+            max_flows,
+            verdicts: HashMap::new()
+        }
+    }
+
+    /// Application of a TCP or UDP packet, "unknown" if it can't be told. The lower port is
+    /// checked first, it's usually the one of the server.
+    pub fn classify(&mut self, packet: &Packet, payload: &[u8]) -> String {
+        let tcp = match packet.transport.as_str() {
+            "TCP" => true,
+            "UDP" => false,
+            _ => return packet.application.clone()
+        };
+        let (src_port, dest_port) = match (packet.src_port, packet.dest_port) {
+            (Some(src_port), Some(dest_port)) => (src_port, dest_port),
+            _ => return packet.application.clone()
+        };
+
+        let service = self.services.lookup(tcp, src_port.min(dest_port))
+            .or_else(|| self.services.lookup(tcp, src_port.max(dest_port)));
+        if let Some(service) = service {
+            return service.to_string();
+        }
+
+        let key = (ConnectionKey::new(&packet.interface, &packet.vlan, (packet.src_addr, src_port), (packet.dest_addr, dest_port)), tcp);
+        if let Some(verdict) = self.verdicts.get_mut(&key) {
+            verdict.last_seen = packet.timestamp;
+            return verdict.application.clone();
+        }
+
+        // DNS has already been decoded, off its ports too
+        let signature = match packet.dns {
+            Some(_) => Some("DNS"),
+            None => payload_signature(tcp, payload)
+        };
+        let application = match signature {
+            Some(application) => application.to_string(),
+            None => return "unknown".to_string()
+        };
+
+        if self.verdicts.len() >= self.max_flows {
+            // Make room dropping the flow idle for the longest time
+            let oldest = self.verdicts.iter()
+                .min_by_key(|(_, verdict)| verdict.last_seen)
+                .map(|(key, _)| key.clone());
+            if let Some(oldest) = oldest {
+                self.verdicts.remove(&oldest);
+            }
+        }
+        self.verdicts.insert(key, Verdict { application: application.clone(), last_seen: packet.timestamp });
+        application
+    }
+
+    /// Drop the verdicts of the flows idle for longer than the timeout
+    pub fn expire(&mut self, now: DateTime<Local>) {
+        let timeout = self.timeout;
+        self.verdicts.retain(|_, verdict| now - verdict.last_seen <= timeout);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{remove_file, write};
+
+    use pcap::Linktype;
+
+    use crate::parser::{Endpoints, Frame};
+
+    fn packet(transport: &str, source: (&str, u16), destination: (&str, u16), timestamp: DateTime<Local>) -> Packet {
+        let frame = Frame { timestamp, ..Frame::fixture(Linktype::ETHERNET, Vec::new()) };
+        let endpoints = Endpoints::with_ports(source.0.parse().unwrap(), source.1, destination.0.parse().unwrap(), destination.1);
+        Packet::new(&frame, endpoints, 0, transport.to_string())
+    }
+
+    fn classifier() -> Classifier {
+        Classifier::new(ServiceTable::builtin(), Duration::seconds(120), 16)
+    }
+
+    #[test]
+    fn builtin_ports() {
+        let services = ServiceTable::builtin();
+
+        assert_eq!(services.lookup(true, 443), Some("https"));
+        assert_eq!(services.lookup(false, 443), Some("QUIC"));
+        assert_eq!(services.lookup(false, 22), None);
+    }
+
+    #[test]
+    fn both_directions_get_the_port_of_the_server() {
+        let mut classifier = classifier();
+        let now = Local::now();
+
+        let request = packet("TCP", ("192.0.2.1", 51234), ("192.0.2.2", 443), now);
+        let response = packet("TCP", ("192.0.2.2", 443), ("192.0.2.1", 51234), now);
+
+        assert_eq!(classifier.classify(&request, b""), "https");
+        assert_eq!(classifier.classify(&response, b""), "https");
+        // The lower port wins when both are known
+        assert_eq!(classifier.classify(&packet("UDP", ("192.0.2.1", 5353), ("192.0.2.2", 53), now), b""), "DNS");
+    }
+
+    #[test]
+    fn services_file() {
+        let path = std::env::temp_dir().join(format!("swiffer-services-{}", std::process::id()));
+        write(&path, "# Network services\n\nhttp-alt\t8080/tcp\twebcache   # WWW caching service\nhttps-custom 443/tcp\nsyslog 514/udp\nsctp-only 9/sctp\n").unwrap();
+        let mut services = ServiceTable::builtin();
+
+        services.load(path.to_str().unwrap()).unwrap();
+
+        assert_eq!(services.lookup(true, 8080), Some("http-alt"));
+        assert_eq!(services.lookup(true, 443), Some("https-custom"));
+        assert_eq!(services.lookup(false, 514), Some("syslog"));
+        assert_eq!(services.lookup(false, 443), Some("QUIC"));
+        assert_eq!(services.lookup(true, 9), None);
+
+        write(&path, "http 80/tcp\nbroken port\n").unwrap();
+        let error = services.load(path.to_str().unwrap()).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert!(error.to_string().starts_with("line 2:"));
+        remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn payload_signatures() {
+        assert_eq!(payload_signature(true, b"GET / HTTP/1.1\r\nHost: example.com\r\n\r\n"), Some("http"));
+        assert_eq!(payload_signature(true, b"HTTP/1.1 200 OK\r\n"), Some("http"));
+        assert_eq!(payload_signature(true, &[0x16, 0x03, 0x01, 0x02, 0x00, 0x01, 0x00, 0x01, 0xfc]), Some("tls"));
+        assert_eq!(payload_signature(true, b"SSH-2.0-OpenSSH_9.6\r\n"), Some("ssh"));
+        assert_eq!(payload_signature(true, b"220 mail.example.com ESMTP Postfix\r\n"), Some("smtp"));
+        // Application data records are not the start of a handshake
+        assert_eq!(payload_signature(true, &[0x17, 0x03, 0x03, 0x00, 0x20]), None);
+        assert_eq!(payload_signature(false, b"SSH-2.0-OpenSSH_9.6\r\n"), None);
+        assert_eq!(payload_signature(true, b""), None);
+    }
+
+    #[test]
+    fn flows_keep_the_verdict_of_their_payload_until_idle() {
+        let mut classifier = classifier();
+        let now = Local::now();
+        let client_hello = [0x16, 0x03, 0x01, 0x02, 0x00, 0x01, 0x00, 0x01, 0xfc];
+
+        // Off the known ports, nothing to tell from the handshake
+        assert_eq!(classifier.classify(&packet("TCP", ("192.0.2.1", 51234), ("192.0.2.2", 8443), now), b""), "unknown");
+        assert_eq!(classifier.classify(&packet("TCP", ("192.0.2.1", 51234), ("192.0.2.2", 8443), now), &client_hello), "tls");
+        // The encrypted records that follow, both ways
+        let response = packet("TCP", ("192.0.2.2", 8443), ("192.0.2.1", 51234), now + Duration::seconds(100));
+        assert_eq!(classifier.classify(&response, &[0x17, 0x03, 0x03, 0x00, 0x20]), "tls");
+        // The same ports over UDP are another flow
+        assert_eq!(classifier.classify(&packet("UDP", ("192.0.2.1", 51234), ("192.0.2.2", 8443), now), b""), "unknown");
+
+        classifier.expire(now + Duration::seconds(200));
+        assert_eq!(classifier.classify(&response, b""), "tls");
+        classifier.expire(now + Duration::seconds(300));
+        assert_eq!(classifier.classify(&response, b""), "unknown");
+    }
+
+    #[test]
+    fn other_transports_keep_their_application() {
+        let mut classifier = classifier();
+        let mut icmp = packet("ICMP", ("192.0.2.1", 0), ("192.0.2.2", 0), Local::now());
+        icmp.application = "ping".to_string();
+
+        assert_eq!(classifier.classify(&icmp, b""), "ping");
+    }
+}
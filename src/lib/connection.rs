@@ -0,0 +1,321 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::net::IpAddr;
+
+use chrono::{DateTime, Duration, Local};
+use serde::Serialize;
+
+use crate::parser::Packet;
+
+/// Lifecycle of a TCP connection, as seen from the wire
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum TcpState {
+    #[serde(rename = "SYN_SENT")]
+    SynSent,
+    #[serde(rename = "SYN_RECEIVED")]
+    SynReceived,
+    #[serde(rename = "ESTABLISHED")]
+    Established,
+    #[serde(rename = "FIN_WAIT")]
+    FinWait,
+    #[serde(rename = "CLOSED")]
+    Closed,
+    #[serde(rename = "RST")]
+    Reset,
+    #[serde(rename = "REFUSED")]
+    Refused,
+    #[serde(rename = "TIMED_OUT")]
+    TimedOut
+}
+
+impl TcpState {
+    /// The connection is over, a new SYN starts a new one
+    pub fn is_finished(&self) -> bool {
+        matches!(self, TcpState::Closed | TcpState::Reset | TcpState::Refused | TcpState::TimedOut)
+    }
+}
+
+impl fmt::Display for TcpState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TcpState::SynSent => write!(f, "SYN_SENT"),
+            TcpState::SynReceived => write!(f, "SYN_RECEIVED"),
+            TcpState::Established => write!(f, "ESTABLISHED"),
+            TcpState::FinWait => write!(f, "FIN_WAIT"),
+            TcpState::Closed => write!(f, "CLOSED"),
+            TcpState::Reset => write!(f, "RST"),
+            TcpState::Refused => write!(f, "REFUSED"),
+            TcpState::TimedOut => write!(f, "TIMED_OUT")
+        }
+    }
+}
+
+/// Both directions of a connection share the same key
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ConnectionKey {
+    pub interface: String,
+    pub vlan: Vec<u16>,
+    pub low: (IpAddr, u16),
+    pub high: (IpAddr, u16)
+}
+
+impl ConnectionKey {
+    pub fn new(interface: &str, vlan: &[u16], a: (IpAddr, u16), b: (IpAddr, u16)) -> Self {
+        ConnectionKey {
+            interface: interface.to_string(),
+            vlan: vlan.to_vec(),
+            low: a.min(b),
+            high: a.max(b)
+        }
+    }
+
+    /// The endpoint that is not `initiator`
+    pub fn peer(&self, initiator: (IpAddr, u16)) -> (IpAddr, u16) {
+        if self.low == initiator { self.high } else { self.low }
+    }
+}
+
+pub struct Connection {
+    pub state: TcpState,
+    /// Endpoint that sent the first SYN (or the first segment seen, for connections picked up midstream)
+    pub initiator: (IpAddr, u16),
+    pub start: DateTime<Local>,
+    pub last_seen: DateTime<Local>,
+    initiator_fin: bool,
+    responder_fin: bool
+}
+
+/// TCP connections seen on the wire, dropped once idle for longer than the timeout
+pub struct ConnectionTable {
+    pub timeout: Duration,
+    pub max_connections: usize,
+    connections: HashMap<ConnectionKey, Connection>
+}
+
+impl ConnectionTable {
+    pub fn new(timeout: Duration, max_connections: usize) -> Self {
+        ConnectionTable {
+            timeout,
+            max_connections,
+            connections: HashMap::new()
+        }
+    }
+
+    /// Move the connection of a TCP segment to its next state, returning the new state, the
+    /// start of the connection and whether the segment comes from the endpoint that opened it.
+    /// Returns None if the packet is not a TCP segment.
+    pub fn update(&mut self, packet: &Packet) -> Option<(TcpState, DateTime<Local>, bool)> {
+        let flags = packet.tcp_flags?;
+        let source = (packet.src_addr, packet.src_port?);
+        let destination = (packet.dest_addr, packet.dest_port?);
+        let key = ConnectionKey::new(&packet.interface, &packet.vlan, source, destination);
+        let now = packet.timestamp;
+
+        // A new SYN on a finished connection (or any segment after the timeout) starts a new one
+        let restart = match self.connections.get(&key) {
+            Some(connection) => (flags.syn && !flags.ack && connection.state.is_finished()) || now - connection.last_seen > self.timeout,
+            None => true
+        };
+
+        if restart {
+            let (state, initiator) = match (flags.syn, flags.ack, flags.rst, flags.fin) {
+                (true, false, _, _) => (TcpState::SynSent, source),
+                (true, true, _, _) => (TcpState::SynReceived, destination),
+                (_, _, true, _) => (TcpState::Reset, source),
+                (_, _, _, true) => (TcpState::FinWait, source),
+                // Picked up midstream
+                _ => (TcpState::Established, source)
+            };
+            let connection = Connection {
+                state,
+                initiator,
+                start: now,
+                last_seen: now,
+                initiator_fin: flags.fin,
+                responder_fin: false
+            };
+            if !self.connections.contains_key(&key) && self.connections.len() >= self.max_connections {
+                // Make room dropping the connection idle for the longest time
+                let oldest = self.connections.iter()
+                    .min_by_key(|(_, connection)| connection.last_seen)
+                    .map(|(key, _)| key.clone());
+                if let Some(oldest) = oldest {
+                    self.connections.remove(&oldest);
+                }
+            }
+            self.connections.insert(key, connection);
+            return Some((state, now, source == initiator));
+        }
+
+        let connection = self.connections.get_mut(&key)?;
+        let from_initiator = source == connection.initiator;
+        connection.last_seen = now;
+
+        if !connection.state.is_finished() {
+            let handshake = matches!(connection.state, TcpState::SynSent | TcpState::SynReceived);
+            if flags.rst {
+                connection.state = if handshake { TcpState::Refused } else { TcpState::Reset };
+            } else if flags.fin {
+                if from_initiator {
+                    connection.initiator_fin = true;
+                } else {
+                    connection.responder_fin = true;
+                }
+                connection.state = if connection.initiator_fin && connection.responder_fin { TcpState::Closed } else { TcpState::FinWait };
+            } else if flags.syn && flags.ack && !from_initiator && connection.state == TcpState::SynSent {
+                connection.state = TcpState::SynReceived;
+            } else if flags.ack && !flags.syn && handshake {
+                connection.state = TcpState::Established;
+            }
+        }
+
+        Some((connection.state, connection.start, from_initiator))
+    }
+
+    /// Drop the connections idle for longer than the timeout, returning the ones that were still
+    /// open, now timed out
+    pub fn expire(&mut self, now: DateTime<Local>) -> Vec<(ConnectionKey, Connection)> {
+        let timeout = self.timeout;
+        let expired: Vec<ConnectionKey> = self.connections.iter()
+            .filter(|(_, connection)| now - connection.last_seen > timeout)
+            .map(|(key, _)| key.clone())
+            .collect();
+
+        let mut timed_out = Vec::new();
+        for key in expired {
+            if let Some(mut connection) = self.connections.remove(&key) {
+                if !connection.state.is_finished() {
+                    connection.state = TcpState::TimedOut;
+                    timed_out.push((key, connection));
+                }
+            }
+        }
+        timed_out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pcap::Linktype;
+
+    use crate::parser::{Endpoints, Frame};
+    use crate::tcp::TcpFlags;
+
+    const CLIENT: (&str, u16) = ("192.0.2.1", 40000);
+    const SERVER: (&str, u16) = ("192.0.2.2", 443);
+
+    fn endpoint((addr, port): (&str, u16)) -> (IpAddr, u16) {
+        (addr.parse().unwrap(), port)
+    }
+
+    fn segment(source: (&str, u16), destination: (&str, u16), flags: TcpFlags, timestamp: DateTime<Local>) -> Packet {
+        let frame = Frame { timestamp, ..Frame::fixture(Linktype::ETHERNET, Vec::new()) };
+        let (src_addr, src_port) = endpoint(source);
+        let (dest_addr, dest_port) = endpoint(destination);
+        let mut packet = Packet::new(&frame, Endpoints::with_ports(src_addr, src_port, dest_addr, dest_port), 0, "TCP".to_string());
+        packet.tcp_flags = Some(flags);
+        packet
+    }
+
+    const FLAGS: TcpFlags = TcpFlags { urg: false, ack: false, psh: false, rst: false, syn: false, fin: false };
+    const SYN: TcpFlags = TcpFlags { syn: true, ..FLAGS };
+    const SYN_ACK: TcpFlags = TcpFlags { syn: true, ack: true, ..FLAGS };
+    const ACK: TcpFlags = TcpFlags { ack: true, ..FLAGS };
+    const FIN_ACK: TcpFlags = TcpFlags { fin: true, ack: true, ..FLAGS };
+    const RST: TcpFlags = TcpFlags { rst: true, ..FLAGS };
+
+    fn table() -> ConnectionTable {
+        ConnectionTable::new(Duration::seconds(120), 16)
+    }
+
+    #[test]
+    fn handshake_then_close() {
+        let mut table = table();
+        let now = Local::now();
+        let mut state = |source, destination, flags| table.update(&segment(source, destination, flags, now)).unwrap();
+
+        assert_eq!(state(CLIENT, SERVER, SYN), (TcpState::SynSent, now, true));
+        assert_eq!(state(SERVER, CLIENT, SYN_ACK), (TcpState::SynReceived, now, false));
+        assert_eq!(state(CLIENT, SERVER, ACK).0, TcpState::Established);
+        assert_eq!(state(CLIENT, SERVER, FIN_ACK).0, TcpState::FinWait);
+        assert_eq!(state(SERVER, CLIENT, FIN_ACK).0, TcpState::Closed);
+        // The last ACK doesn't reopen it
+        assert_eq!(state(CLIENT, SERVER, ACK).0, TcpState::Closed);
+    }
+
+    #[test]
+    fn reset_during_the_handshake_is_a_refusal() {
+        let mut table = table();
+        let now = Local::now();
+
+        table.update(&segment(CLIENT, SERVER, SYN, now));
+        let (state, _, from_initiator) = table.update(&segment(SERVER, CLIENT, TcpFlags { ack: true, ..RST }, now)).unwrap();
+
+        assert_eq!(state, TcpState::Refused);
+        assert!(!from_initiator);
+        // A new SYN starts another attempt
+        assert_eq!(table.update(&segment(CLIENT, SERVER, SYN, now)).unwrap().0, TcpState::SynSent);
+    }
+
+    #[test]
+    fn reset_of_an_established_connection() {
+        let mut table = table();
+        let now = Local::now();
+
+        // Picked up midstream, the first sender is taken as the initiator
+        assert_eq!(table.update(&segment(SERVER, CLIENT, ACK, now)).unwrap(), (TcpState::Established, now, true));
+        let (state, _, from_initiator) = table.update(&segment(CLIENT, SERVER, RST, now)).unwrap();
+
+        assert_eq!(state, TcpState::Reset);
+        assert!(!from_initiator);
+    }
+
+    #[test]
+    fn idle_open_connections_time_out() {
+        let mut table = table();
+        let now = Local::now();
+
+        table.update(&segment(CLIENT, SERVER, SYN, now));
+        table.update(&segment(SERVER, CLIENT, SYN_ACK, now));
+        table.update(&segment(CLIENT, SERVER, ACK, now));
+        table.update(&segment(("192.0.2.3", 40001), SERVER, SYN, now));
+        table.update(&segment(SERVER, ("192.0.2.3", 40001), RST, now));
+
+        assert!(table.expire(now + Duration::seconds(60)).is_empty());
+        let timed_out = table.expire(now + Duration::seconds(121));
+
+        // The refused one is over, it is dropped without being reported
+        assert_eq!(timed_out.len(), 1);
+        let (key, connection) = &timed_out[0];
+        assert_eq!(connection.state, TcpState::TimedOut);
+        assert_eq!(connection.initiator, endpoint(CLIENT));
+        assert_eq!(key.peer(connection.initiator), endpoint(SERVER));
+    }
+
+    #[test]
+    fn segment_after_the_timeout_starts_a_new_connection() {
+        let mut table = table();
+        let now = Local::now();
+        let later = now + Duration::seconds(121);
+
+        table.update(&segment(CLIENT, SERVER, SYN, now));
+
+        assert_eq!(table.update(&segment(SERVER, CLIENT, ACK, later)).unwrap(), (TcpState::Established, later, true));
+    }
+
+    #[test]
+    fn full_table_drops_the_connection_idle_for_the_longest_time() {
+        let mut table = ConnectionTable::new(Duration::seconds(120), 2);
+        let now = Local::now();
+
+        table.update(&segment(("192.0.2.1", 40000), SERVER, SYN, now));
+        table.update(&segment(("192.0.2.1", 40001), SERVER, SYN, now + Duration::seconds(1)));
+        table.update(&segment(("192.0.2.1", 40000), SERVER, ACK, now + Duration::seconds(2)));
+        table.update(&segment(("192.0.2.1", 40002), SERVER, SYN, now + Duration::seconds(3)));
+
+        let mut open: Vec<u16> = table.expire(now + Duration::hours(1)).iter().map(|(_, connection)| connection.initiator.1).collect();
+        open.sort();
+        assert_eq!(open, [40000, 40002]);
+    }
+}
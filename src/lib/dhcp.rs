@@ -0,0 +1,330 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::net::Ipv4Addr;
+
+use chrono::{DateTime, Local};
+use pktparse::ethernet::MacAddress;
+use serde::Serialize;
+
+use crate::utils::mac_to_str;
+
+const MAGIC_COOKIE: [u8; 4] = [0x63, 0x82, 0x53, 0x63];
+
+const OPTION_PAD: u8 = 0;
+const OPTION_HOSTNAME: u8 = 12;
+const OPTION_REQUESTED_ADDRESS: u8 = 50;
+const OPTION_LEASE_TIME: u8 = 51;
+const OPTION_MESSAGE_TYPE: u8 = 53;
+const OPTION_SERVER_IDENTIFIER: u8 = 54;
+const OPTION_VENDOR_CLASS: u8 = 60;
+const OPTION_END: u8 = 255;
+
+/// Client and server ports, relays talk server to server
+pub fn is_dhcp_port(port: u16) -> bool {
+    matches!(port, 67 | 68)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DhcpMessageType {
+    Discover,
+    Offer,
+    Request,
+    Decline,
+    Ack,
+    Nak,
+    Release,
+    Inform,
+    Other(u8)
+}
+
+impl From<u8> for DhcpMessageType {
+    fn from(raw: u8) -> Self {
+        match raw {
+            1 => DhcpMessageType::Discover,
+            2 => DhcpMessageType::Offer,
+            3 => DhcpMessageType::Request,
+            4 => DhcpMessageType::Decline,
+            5 => DhcpMessageType::Ack,
+            6 => DhcpMessageType::Nak,
+            7 => DhcpMessageType::Release,
+            8 => DhcpMessageType::Inform,
+            other => DhcpMessageType::Other(other)
+        }
+    }
+}
+
+impl fmt::Display for DhcpMessageType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DhcpMessageType::Discover => write!(f, "DISCOVER"),
+            DhcpMessageType::Offer => write!(f, "OFFER"),
+            DhcpMessageType::Request => write!(f, "REQUEST"),
+            DhcpMessageType::Decline => write!(f, "DECLINE"),
+            DhcpMessageType::Ack => write!(f, "ACK"),
+            DhcpMessageType::Nak => write!(f, "NAK"),
+            DhcpMessageType::Release => write!(f, "RELEASE"),
+            DhcpMessageType::Inform => write!(f, "INFORM"),
+            DhcpMessageType::Other(raw) => write!(f, "TYPE{}", raw)
+        }
+    }
+}
+
+/// Content of a DHCP message, plain BOOTP messages have no message type
+#[derive(Debug, Clone)]
+pub struct DhcpMessage {
+    /// Sent by a server (BOOTREPLY)
+    pub reply: bool,
+    pub message_type: Option<DhcpMessageType>,
+    pub transaction_id: u32,
+    pub client_mac: MacAddress,
+    /// Address the client already has (ciaddr)
+    pub client_addr: Ipv4Addr,
+    /// Address assigned by the server (yiaddr)
+    pub your_addr: Ipv4Addr,
+    pub requested_addr: Option<Ipv4Addr>,
+    pub server_id: Option<Ipv4Addr>,
+    pub lease_time: Option<u32>,
+    pub hostname: Option<String>,
+    pub vendor_class: Option<String>
+}
+
+fn ipv4_at(payload: &[u8], offset: usize) -> Ipv4Addr {
+    Ipv4Addr::new(payload[offset], payload[offset + 1], payload[offset + 2], payload[offset + 3])
+}
+
+/// Parse a DHCP message, it must carry the magic cookie
+pub fn parse_dhcp(payload: &[u8]) -> Option<DhcpMessage> {
+    if payload.len() < 240 || payload[236..240] != MAGIC_COOKIE {
+        return None;
+    }
+    // Only Ethernet addresses are expected in chaddr
+    let reply = match payload[0] {
+        1 => false,
+        2 => true,
+        _ => return None
+    };
+    if payload[1] != 1 || payload[2] != 6 {
+        return None;
+    }
+
+    let mut client_mac = [0u8; 6];
+    client_mac.copy_from_slice(&payload[28..34]);
+    let mut message = DhcpMessage {
+        reply,
+        message_type: None,
+        transaction_id: u32::from_be_bytes([payload[4], payload[5], payload[6], payload[7]]),
+        client_mac: MacAddress(client_mac),
+        client_addr: ipv4_at(payload, 12),
+        your_addr: ipv4_at(payload, 16),
+        requested_addr: None,
+        server_id: None,
+        lease_time: None,
+        hostname: None,
+        vendor_class: None
+    };
+
+    let mut options = &payload[240..];
+    while let Some((&code, rest)) = options.split_first() {
+        match code {
+            OPTION_PAD => {
+                options = rest;
+                continue;
+            },
+            OPTION_END => break,
+            _ => ()
+        }
+        let (&len, rest) = rest.split_first()?;
+        let data = rest.get(..len as usize)?;
+        options = &rest[len as usize..];
+
+        match (code, data.len()) {
+            (OPTION_MESSAGE_TYPE, 1) => message.message_type = Some(DhcpMessageType::from(data[0])),
+            (OPTION_REQUESTED_ADDRESS, 4) => message.requested_addr = Some(ipv4_at(data, 0)),
+            (OPTION_SERVER_IDENTIFIER, 4) => message.server_id = Some(ipv4_at(data, 0)),
+            (OPTION_LEASE_TIME, 4) => message.lease_time = Some(u32::from_be_bytes([data[0], data[1], data[2], data[3]])),
+            (OPTION_HOSTNAME, _) => message.hostname = Some(String::from_utf8_lossy(data).trim_end_matches('\0').to_string()),
+            (OPTION_VENDOR_CLASS, _) => message.vendor_class = Some(String::from_utf8_lossy(data).trim_end_matches('\0').to_string()),
+            _ => ()
+        }
+    }
+    Some(message)
+}
+
+/// What the DHCP exchanges of a client showed
+#[derive(Debug, Clone, Serialize)]
+pub struct DhcpLease {
+    pub client_mac: String,
+    /// Address assigned by the last ACK (or requested, until then)
+    pub address: Option<Ipv4Addr>,
+    pub hostname: String,
+    pub vendor_class: String,
+    pub server: Option<Ipv4Addr>,
+    /// Seconds
+    pub lease_time: Option<u32>,
+    /// Type of the last message of the client or for it
+    pub state: String,
+    pub first_seen: DateTime<Local>,
+    pub last_seen: DateTime<Local>
+}
+
+impl fmt::Display for DhcpLease {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let address = self.address.map(|address| address.to_string()).unwrap_or("-".to_string());
+        let server = self.server.map(|server| server.to_string()).unwrap_or("-".to_string());
+        let lease_time = self.lease_time.map(|seconds| seconds.to_string()).unwrap_or("-".to_string());
+        write!(f, "| {0: <17}\t| {1: <15}\t| {2: <25}\t| {3: <25}\t| {4: <15}\t| {5: <8}\t| {6: <8}\t| {7: <15}\t| {8: <15}", self.client_mac, address, self.hostname, self.vendor_class, server, lease_time, self.state, self.first_seen, self.last_seen)
+    }
+}
+
+/// DHCP clients seen during the session, by MAC address
+pub struct DhcpLeaseTable {
+    pub max_entries: usize,
+    leases: HashMap<[u8; 6], DhcpLease>
+}
+
+impl DhcpLeaseTable {
+    pub fn new(max_entries: usize) -> Self {
+        DhcpLeaseTable {
+            max_entries,
+            leases: HashMap::new()
+        }
+    }
+
+    /// Record what a DHCP message tells about its client
+    pub fn update(&mut self, message: &DhcpMessage, timestamp: DateTime<Local>) {
+        let key = message.client_mac.0;
+        if !self.leases.contains_key(&key) && self.leases.len() >= self.max_entries {
+            // Make room dropping the client seen the longest time ago
+            let oldest = self.leases.iter()
+                .min_by_key(|(_, lease)| lease.last_seen)
+                .map(|(key, _)| *key);
+            if let Some(oldest) = oldest {
+                self.leases.remove(&oldest);
+            }
+        }
+
+        let lease = self.leases.entry(key).or_insert_with(|| DhcpLease {
+            client_mac: mac_to_str(message.client_mac),
+            address: None,
+            hostname: "-".to_string(),
+            vendor_class: "-".to_string(),
+            server: None,
+            lease_time: None,
+            state: "-".to_string(),
+            first_seen: timestamp,
+            last_seen: timestamp
+        });
+        lease.last_seen = timestamp;
+        if let Some(hostname) = &message.hostname {
+            lease.hostname = hostname.clone();
+        }
+        if let Some(vendor_class) = &message.vendor_class {
+            lease.vendor_class = vendor_class.clone();
+        }
+        if let Some(message_type) = message.message_type {
+            lease.state = message_type.to_string();
+        }
+
+        match message.message_type {
+            Some(DhcpMessageType::Request) if lease.address.is_none() => {
+                lease.address = message.requested_addr.or(Some(message.client_addr).filter(|address| !address.is_unspecified()));
+            },
+            // Plain BOOTP replies assign the address as well
+            Some(DhcpMessageType::Ack) | None if message.reply => {
+                if !message.your_addr.is_unspecified() {
+                    lease.address = Some(message.your_addr);
+                }
+                lease.server = message.server_id.or(lease.server);
+                lease.lease_time = message.lease_time.or(lease.lease_time);
+            },
+            _ => ()
+        }
+    }
+
+    /// Every client of the table, ordered by MAC address
+    pub fn entries(&self) -> Vec<DhcpLease> {
+        let mut entries: Vec<(&[u8; 6], &DhcpLease)> = self.leases.iter().collect();
+        entries.sort_by_key(|(key, _)| **key);
+        entries.into_iter().map(|(_, lease)| lease.clone()).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CLIENT_MAC: [u8; 6] = [0x00, 0x11, 0x22, 0x33, 0x44, 0x55];
+
+    /// BOOTP header with the magic cookie, followed by `options`
+    fn message(op: u8, your_addr: [u8; 4], options: &[u8]) -> Vec<u8> {
+        let mut payload = vec![0; 240];
+        payload[0] = op;
+        payload[1] = 1;
+        payload[2] = 6;
+        payload[4..8].copy_from_slice(&[0xde, 0xad, 0xbe, 0xef]);
+        payload[16..20].copy_from_slice(&your_addr);
+        payload[28..34].copy_from_slice(&CLIENT_MAC);
+        payload[236..240].copy_from_slice(&MAGIC_COOKIE);
+        payload.extend_from_slice(options);
+        payload
+    }
+
+    #[test]
+    fn discover_with_hostname_and_vendor_class() {
+        let payload = message(1, [0; 4], &[53, 1, 1, 0, 12, 6, b'l', b'a', b'p', b't', b'o', b'p', 60, 8, b'M', b'S', b'F', b'T', b' ', b'5', b'.', b'0', 255]);
+        let message = parse_dhcp(&payload).unwrap();
+
+        assert!(!message.reply);
+        assert_eq!(message.message_type, Some(DhcpMessageType::Discover));
+        assert_eq!(message.transaction_id, 0xdeadbeef);
+        assert_eq!(message.client_mac.0, CLIENT_MAC);
+        assert_eq!(message.hostname.as_deref(), Some("laptop"));
+        assert_eq!(message.vendor_class.as_deref(), Some("MSFT 5.0"));
+    }
+
+    #[test]
+    fn ack_with_server_and_lease_time() {
+        let payload = message(2, [192, 168, 1, 20], &[53, 1, 5, 54, 4, 192, 168, 1, 1, 51, 4, 0, 0, 0x0e, 0x10, 255]);
+        let message = parse_dhcp(&payload).unwrap();
+
+        assert!(message.reply);
+        assert_eq!(message.message_type, Some(DhcpMessageType::Ack));
+        assert_eq!(message.your_addr, Ipv4Addr::new(192, 168, 1, 20));
+        assert_eq!(message.server_id, Some(Ipv4Addr::new(192, 168, 1, 1)));
+        assert_eq!(message.lease_time, Some(3600));
+    }
+
+    #[test]
+    fn not_dhcp() {
+        let mut payload = message(1, [0; 4], &[255]);
+        payload[236] = 0;
+        assert!(parse_dhcp(&payload).is_none());
+        // Not Ethernet
+        let mut payload = message(1, [0; 4], &[255]);
+        payload[1] = 6;
+        assert!(parse_dhcp(&payload).is_none());
+        assert!(parse_dhcp(&message(1, [0; 4], &[])[..239]).is_none());
+        // Option longer than the message
+        assert!(parse_dhcp(&message(1, [0; 4], &[12, 10, b'a'])).is_none());
+    }
+
+    #[test]
+    fn lease_follows_the_request_and_the_ack() {
+        let mut table = DhcpLeaseTable::new(16);
+        let now = Local::now();
+
+        let request = parse_dhcp(&message(1, [0; 4], &[53, 1, 3, 50, 4, 192, 168, 1, 20, 255])).unwrap();
+        table.update(&request, now);
+        assert_eq!(table.entries()[0].address, Some(Ipv4Addr::new(192, 168, 1, 20)));
+        assert_eq!(table.entries()[0].state, "REQUEST");
+
+        let ack = parse_dhcp(&message(2, [192, 168, 1, 21], &[53, 1, 5, 54, 4, 192, 168, 1, 1, 51, 4, 0, 0, 0x0e, 0x10, 255])).unwrap();
+        table.update(&ack, now);
+        let lease = &table.entries()[0];
+        assert_eq!(lease.client_mac, "00:11:22:33:44:55");
+        assert_eq!(lease.address, Some(Ipv4Addr::new(192, 168, 1, 21)));
+        assert_eq!(lease.server, Some(Ipv4Addr::new(192, 168, 1, 1)));
+        assert_eq!(lease.lease_time, Some(3600));
+        assert_eq!(lease.state, "ACK");
+    }
+}
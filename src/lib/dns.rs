@@ -0,0 +1,492 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+
+use chrono::{DateTime, Duration, Local};
+use dns_parser::{Opcode, QueryType, RData, ResourceRecord, ResponseCode};
+use serde::Serialize;
+
+use crate::utils::join_values;
+
+/// DNS, multicast DNS and LLMNR
+pub fn is_dns_port(port: u16) -> bool {
+    matches!(port, 53 | 5353 | 5355)
+}
+
+fn rcode_name(rcode: ResponseCode) -> String {
+    match rcode {
+        ResponseCode::NoError => "NOERROR".to_string(),
+        ResponseCode::FormatError => "FORMERR".to_string(),
+        ResponseCode::ServerFailure => "SERVFAIL".to_string(),
+        ResponseCode::NameError => "NXDOMAIN".to_string(),
+        ResponseCode::NotImplemented => "NOTIMP".to_string(),
+        ResponseCode::Refused => "REFUSED".to_string(),
+        ResponseCode::Reserved(code) => format!("RCODE{}", code)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecordType {
+    A,
+    AAAA,
+    CNAME,
+    NS,
+    PTR,
+    MX,
+    SRV,
+    SOA,
+    TXT,
+    Unknown
+}
+
+impl fmt::Display for RecordType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RecordType::A => write!(f, "A"),
+            RecordType::AAAA => write!(f, "AAAA"),
+            RecordType::CNAME => write!(f, "CNAME"),
+            RecordType::NS => write!(f, "NS"),
+            RecordType::PTR => write!(f, "PTR"),
+            RecordType::MX => write!(f, "MX"),
+            RecordType::SRV => write!(f, "SRV"),
+            RecordType::SOA => write!(f, "SOA"),
+            RecordType::TXT => write!(f, "TXT"),
+            RecordType::Unknown => write!(f, "unknown")
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct DnsRecord {
+    pub name: String,
+    pub record_type: RecordType,
+    pub ttl: u32,
+    /// Address of A and AAAA records
+    pub address: Option<IpAddr>,
+    pub data: String
+}
+
+impl DnsRecord {
+    fn from_resource(record: &ResourceRecord) -> Self {
+        let (record_type, data) = match &record.data {
+            RData::A(a) => (RecordType::A, a.0.to_string()),
+            RData::AAAA(aaaa) => (RecordType::AAAA, aaaa.0.to_string()),
+            RData::CNAME(cname) => (RecordType::CNAME, cname.0.to_string()),
+            RData::NS(ns) => (RecordType::NS, ns.0.to_string()),
+            RData::PTR(ptr) => (RecordType::PTR, ptr.0.to_string()),
+            RData::MX(mx) => (RecordType::MX, format!("{} {}", mx.preference, mx.exchange)),
+            RData::SRV(srv) => (RecordType::SRV, format!("{} {} {} {}", srv.priority, srv.weight, srv.port, srv.target)),
+            RData::SOA(soa) => (RecordType::SOA, format!("{} {}", soa.primary_ns, soa.mailbox)),
+            RData::TXT(txt) => (RecordType::TXT, txt.iter().map(|chunk| format!("\"{}\"", String::from_utf8_lossy(chunk))).collect::<Vec<String>>().join(" ")),
+            RData::Unknown(_) => (RecordType::Unknown, String::new())
+        };
+        let address = match &record.data {
+            RData::A(a) => Some(IpAddr::V4(a.0)),
+            RData::AAAA(aaaa) => Some(IpAddr::V6(aaaa.0)),
+            _ => None
+        };
+
+        DnsRecord {
+            name: record.name.to_string(),
+            record_type,
+            ttl: record.ttl,
+            address,
+            data
+        }
+    }
+}
+
+impl fmt::Display for DnsRecord {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {} {} (ttl {})", self.name, self.record_type, self.data, self.ttl)
+    }
+}
+
+/// Content of a DNS message
+#[derive(Debug, Clone)]
+pub struct DnsMessage {
+    pub id: u16,
+    pub query: bool,
+    pub rcode: ResponseCode,
+    /// Name and type of every question
+    pub questions: Vec<(String, QueryType)>,
+    pub answers: Vec<DnsRecord>,
+    pub authorities: Vec<DnsRecord>
+}
+
+impl DnsMessage {
+    pub fn names(&self) -> Vec<String> {
+        self.questions.iter().map(|(name, _)| name.clone()).collect()
+    }
+}
+
+/// Parse a DNS message. Off the DNS ports it has to look like a DNS message too: a
+/// standard query with a single question (and no answer, if it is a query).
+pub fn parse_dns(payload: &[u8], dns_port: bool) -> Option<DnsMessage> {
+    let packet = dns_parser::Packet::parse(payload).ok()?;
+    let header = &packet.header;
+    if !dns_port {
+        let plausible = header.opcode == Opcode::StandardQuery
+            && header.questions == 1
+            && (!header.query || header.answers == 0);
+        if !plausible {
+            return None;
+        }
+    }
+
+    Some(DnsMessage {
+        id: header.id,
+        query: header.query,
+        rcode: header.response_code,
+        questions: packet.questions.iter().map(|q| (q.qname.to_string(), q.qtype)).collect(),
+        answers: packet.answers.iter().map(DnsRecord::from_resource).collect(),
+        authorities: packet.nameservers.iter().map(DnsRecord::from_resource).collect()
+    })
+}
+
+/// A query matched with its response, or left without one
+#[derive(Debug, Clone, Serialize)]
+pub struct DnsTransaction {
+    pub timestamp: DateTime<Local>,
+    pub client: SocketAddr,
+    pub server: SocketAddr,
+    pub id: u16,
+    pub name: String,
+    pub query_type: String,
+    /// Response code, "TIMEOUT" if no response arrived
+    pub rcode: String,
+    pub answers: String,
+    /// Milliseconds between query and response, if both were seen
+    pub latency: Option<f64>
+}
+
+impl fmt::Display for DnsTransaction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let latency = self.latency.map(|ms| format!("{:.3}", ms)).unwrap_or("-".to_string());
+        write!(f, "| {0: <15}\t| {1: <25}\t| {2: <25}\t| {3: <5}\t| {4: <30}\t| {5: <5}\t| {6: <8}\t| {7}\t| {8}", self.timestamp, self.client, self.server, self.id, self.name, self.query_type, self.rcode, self.answers, latency)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct DnsKey {
+    client: SocketAddr,
+    server: SocketAddr,
+    id: u16
+}
+
+struct PendingQuery {
+    timestamp: DateTime<Local>,
+    name: String,
+    query_type: String
+}
+
+/// Queries waiting for their response, matched by client, server and transaction id
+pub struct DnsTable {
+    pub timeout: Duration,
+    pub max_pending: usize,
+    pending: HashMap<DnsKey, PendingQuery>,
+    transactions: Vec<DnsTransaction>
+}
+
+impl DnsTable {
+    pub fn new(timeout: Duration, max_pending: usize) -> Self {
+        DnsTable {
+            timeout,
+            max_pending,
+            pending: HashMap::new(),
+            transactions: Vec::new()
+        }
+    }
+
+    /// Record a DNS message sent from `source` to `destination`
+    pub fn update(&mut self, message: &DnsMessage, source: SocketAddr, destination: SocketAddr, timestamp: DateTime<Local>) {
+        let (name, query_type) = match message.questions.first() {
+            Some((name, query_type)) => (name.clone(), format!("{:?}", query_type)),
+            None => ("none".to_string(), "-".to_string())
+        };
+
+        if message.query {
+            if self.pending.len() >= self.max_pending {
+                self.expire(timestamp);
+            }
+            // Still full, the query will go unmatched
+            if self.pending.len() < self.max_pending {
+                let key = DnsKey { client: source, server: destination, id: message.id };
+                self.pending.insert(key, PendingQuery { timestamp, name, query_type });
+            }
+            return;
+        }
+
+        let key = DnsKey { client: destination, server: source, id: message.id };
+        let answers = join_values(&message.answers, ", ");
+        let transaction = match self.pending.remove(&key) {
+            Some(query) => DnsTransaction {
+                timestamp: query.timestamp,
+                client: destination,
+                server: source,
+                id: message.id,
+                name: query.name,
+                query_type: query.query_type,
+                rcode: rcode_name(message.rcode),
+                answers,
+                latency: Some((timestamp - query.timestamp).num_microseconds().unwrap_or(0) as f64 / 1000.0)
+            },
+            // The query was not captured (or it is an unsolicited multicast answer)
+            None => DnsTransaction {
+                timestamp,
+                client: destination,
+                server: source,
+                id: message.id,
+                name,
+                query_type,
+                rcode: rcode_name(message.rcode),
+                answers,
+                latency: None
+            }
+        };
+        self.transactions.push(transaction);
+    }
+
+    /// Give up on the queries without a response after the timeout
+    pub fn expire(&mut self, now: DateTime<Local>) {
+        let timeout = self.timeout;
+        let transactions = &mut self.transactions;
+        self.pending.retain(|key, query| {
+            let alive = now - query.timestamp <= timeout;
+            if !alive {
+                transactions.push(DnsTransaction {
+                    timestamp: query.timestamp,
+                    client: key.client,
+                    server: key.server,
+                    id: key.id,
+                    name: query.name.clone(),
+                    query_type: query.query_type.clone(),
+                    rcode: "TIMEOUT".to_string(),
+                    answers: String::new(),
+                    latency: None
+                });
+            }
+            alive
+        });
+    }
+
+    /// Return the transactions completed (or timed out) since the last call
+    pub fn take_transactions(&mut self) -> Vec<DnsTransaction> {
+        std::mem::take(&mut self.transactions)
+    }
+}
+
+/// Address resolved by an observed A/AAAA answer
+#[derive(Debug, Clone, Serialize)]
+pub struct DnsCacheEntry {
+    pub address: IpAddr,
+    /// Name asked by the client (the first of the CNAME chain)
+    pub name: String,
+    pub ttl: u32,
+    pub first_seen: DateTime<Local>,
+    pub last_seen: DateTime<Local>
+}
+
+impl fmt::Display for DnsCacheEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "| {0: <25}\t| {1: <40}\t| {2: <6}\t| {3: <15}\t| {4: <15}", self.address, self.name, self.ttl, self.first_seen, self.last_seen)
+    }
+}
+
+/// Passive DNS cache, built from the answers seen on the wire
+pub struct DnsCache {
+    /// Entries are used at least this long, whatever their TTL: connections outlive it
+    pub retention: Duration,
+    pub max_entries: usize,
+    entries: HashMap<IpAddr, DnsCacheEntry>
+}
+
+impl DnsCache {
+    pub fn new(retention: Duration, max_entries: usize) -> Self {
+        DnsCache {
+            retention,
+            max_entries,
+            entries: HashMap::new()
+        }
+    }
+
+    /// Add the addresses found in the answers of a successful response
+    pub fn insert(&mut self, message: &DnsMessage, timestamp: DateTime<Local>) {
+        if message.query || message.rcode != ResponseCode::NoError {
+            return;
+        }
+
+        for record in &message.answers {
+            // Only A and AAAA records carry an address
+            let address = match record.address {
+                Some(address) => address,
+                None => continue
+            };
+            // The record name is the end of the CNAME chain, the question its start
+            let name = message.questions.first().map(|(name, _)| name.clone()).unwrap_or(record.name.clone());
+
+            if !self.entries.contains_key(&address) && self.entries.len() >= self.max_entries {
+                // Make room dropping the entry refreshed the longest time ago
+                let oldest = self.entries.values()
+                    .min_by_key(|entry| entry.last_seen)
+                    .map(|entry| entry.address);
+                if let Some(oldest) = oldest {
+                    self.entries.remove(&oldest);
+                }
+            }
+
+            let entry = self.entries.entry(address).or_insert_with(|| DnsCacheEntry {
+                address,
+                name: name.clone(),
+                ttl: record.ttl,
+                first_seen: timestamp,
+                last_seen: timestamp
+            });
+            entry.name = name;
+            entry.ttl = record.ttl;
+            entry.last_seen = timestamp;
+        }
+    }
+
+    /// Name recently resolved to the address, if any
+    pub fn lookup(&self, address: &IpAddr, now: DateTime<Local>) -> Option<&str> {
+        let entry = self.entries.get(address)?;
+        let validity = self.retention.max(Duration::seconds(entry.ttl.into()));
+        match now - entry.last_seen <= validity {
+            true => Some(&entry.name),
+            false => None
+        }
+    }
+
+    /// Every entry of the cache, ordered by name
+    pub fn entries(&self) -> Vec<DnsCacheEntry> {
+        let mut entries: Vec<DnsCacheEntry> = self.entries.values().cloned().collect();
+        entries.sort_by(|a, b| a.name.cmp(&b.name).then(a.address.cmp(&b.address)));
+        entries
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const QUERY_TYPE_A: u16 = 1;
+    const QUERY_TYPE_CNAME: u16 = 5;
+    const QUERY_TYPE_AAAA: u16 = 28;
+
+    fn name(name: &str) -> Vec<u8> {
+        let mut encoded = Vec::new();
+        for label in name.split('.') {
+            encoded.push(label.len() as u8);
+            encoded.extend_from_slice(label.as_bytes());
+        }
+        encoded.push(0);
+        encoded
+    }
+
+    /// Message with a single question for `qname`, followed by `answers` (type and data, named after the question)
+    fn message(id: u16, flags: u16, qname: &str, qtype: u16, answers: &[(u16, Vec<u8>)]) -> Vec<u8> {
+        let mut packet = id.to_be_bytes().to_vec();
+        packet.extend_from_slice(&flags.to_be_bytes());
+        packet.extend_from_slice(&[0, 1]);
+        packet.extend_from_slice(&(answers.len() as u16).to_be_bytes());
+        packet.extend_from_slice(&[0, 0, 0, 0]);
+        packet.extend_from_slice(&name(qname));
+        packet.extend_from_slice(&qtype.to_be_bytes());
+        packet.extend_from_slice(&[0, 1]);
+        for (rtype, data) in answers {
+            // Pointer to the question name
+            packet.extend_from_slice(&[0xc0, 12]);
+            packet.extend_from_slice(&rtype.to_be_bytes());
+            packet.extend_from_slice(&[0, 1, 0, 0, 0x0e, 0x10]);
+            packet.extend_from_slice(&(data.len() as u16).to_be_bytes());
+            packet.extend_from_slice(data);
+        }
+        packet
+    }
+
+    fn addr(addr: &str) -> SocketAddr {
+        addr.parse().unwrap()
+    }
+
+    #[test]
+    fn query() {
+        let query = parse_dns(&message(0x1234, 0x0100, "example.com", QUERY_TYPE_A, &[]), true).unwrap();
+
+        assert!(query.query);
+        assert_eq!(query.id, 0x1234);
+        assert_eq!(query.questions, [("example.com".to_string(), QueryType::A)]);
+        assert_eq!(query.names(), ["example.com"]);
+    }
+
+    #[test]
+    fn response_with_cname_and_addresses() {
+        let packet = message(0x1234, 0x8180, "www.example.com", QUERY_TYPE_A, &[
+            (QUERY_TYPE_CNAME, name("example.com")),
+            (QUERY_TYPE_A, vec![93, 184, 216, 34])
+        ]);
+        let response = parse_dns(&packet, true).unwrap();
+
+        assert!(!response.query);
+        assert_eq!(response.rcode, ResponseCode::NoError);
+        assert_eq!(response.answers[0].record_type, RecordType::CNAME);
+        assert_eq!(response.answers[0].data, "example.com");
+        assert_eq!(response.answers[0].address, None);
+        assert_eq!(response.answers[1].record_type, RecordType::A);
+        assert_eq!(response.answers[1].address, Some("93.184.216.34".parse().unwrap()));
+        assert_eq!(response.answers[1].to_string(), "www.example.com A 93.184.216.34 (ttl 3600)");
+    }
+
+    #[test]
+    fn off_the_dns_ports_it_has_to_look_like_a_query() {
+        let query = message(1, 0x0100, "example.com", QUERY_TYPE_A, &[]);
+        assert!(parse_dns(&query, false).is_some());
+        // A query carrying an answer
+        let query = message(1, 0x0100, "example.com", QUERY_TYPE_A, &[(QUERY_TYPE_A, vec![10, 0, 0, 1])]);
+        assert!(parse_dns(&query, false).is_none());
+        assert!(parse_dns(&query, true).is_some());
+        assert!(parse_dns(b"GET / HTTP/1.1\r\n\r\n", false).is_none());
+    }
+
+    #[test]
+    fn transactions_match_queries_and_responses() {
+        let mut table = DnsTable::new(Duration::seconds(10), 16);
+        let now = Local::now();
+        let query = parse_dns(&message(7, 0x0100, "example.com", QUERY_TYPE_AAAA, &[]), true).unwrap();
+        let response = parse_dns(&message(7, 0x8183, "example.com", QUERY_TYPE_AAAA, &[]), true).unwrap();
+        let lost = parse_dns(&message(8, 0x0100, "example.org", QUERY_TYPE_A, &[]), true).unwrap();
+
+        table.update(&query, addr("192.0.2.1:5000"), addr("192.0.2.53:53"), now);
+        table.update(&lost, addr("192.0.2.1:5001"), addr("192.0.2.53:53"), now);
+        table.update(&response, addr("192.0.2.53:53"), addr("192.0.2.1:5000"), now + Duration::milliseconds(20));
+        table.expire(now + Duration::seconds(11));
+        let transactions = table.take_transactions();
+
+        assert_eq!(transactions.len(), 2);
+        assert_eq!(transactions[0].name, "example.com");
+        assert_eq!(transactions[0].query_type, "AAAA");
+        assert_eq!(transactions[0].rcode, "NXDOMAIN");
+        assert_eq!(transactions[0].latency, Some(20.0));
+        assert_eq!(transactions[1].name, "example.org");
+        assert_eq!(transactions[1].rcode, "TIMEOUT");
+    }
+
+    #[test]
+    fn cache_keeps_the_addresses_of_successful_responses() {
+        let mut cache = DnsCache::new(Duration::hours(1), 16);
+        let now = Local::now();
+        let answers = [
+            (QUERY_TYPE_CNAME, name("example.com")),
+            (QUERY_TYPE_AAAA, vec![0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1])
+        ];
+        cache.insert(&parse_dns(&message(1, 0x8180, "www.example.com", QUERY_TYPE_AAAA, &answers), true).unwrap(), now);
+        // Refused, nothing to learn
+        cache.insert(&parse_dns(&message(2, 0x8185, "example.org", QUERY_TYPE_A, &[(QUERY_TYPE_A, vec![10, 0, 0, 1])]), true).unwrap(), now);
+
+        assert_eq!(cache.lookup(&"2001:db8::1".parse().unwrap(), now), Some("www.example.com"));
+        assert_eq!(cache.lookup(&"10.0.0.1".parse().unwrap(), now), None);
+        assert_eq!(cache.entries().len(), 1);
+        // Past the retention, the TTL (an hour) being shorter
+        assert_eq!(cache.lookup(&"2001:db8::1".parse().unwrap(), now + Duration::hours(2)), None);
+    }
+}
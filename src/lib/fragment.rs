@@ -0,0 +1,349 @@
+use std::collections::{BTreeMap, HashMap};
+use std::net::IpAddr;
+
+use chrono::{DateTime, Duration, Local};
+
+use crate::anomaly::{Anomaly, AnomalyKind};
+use crate::parser::Frame;
+
+// Largest datagram that can be rebuilt (IPv4 total length, IPv6 payload length)
+const MAX_DATAGRAM_LEN: usize = 65535;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct FragmentKey {
+    pub src_addr: IpAddr,
+    pub dest_addr: IpAddr,
+    pub protocol: u8,
+    pub id: u32
+}
+
+impl FragmentKey {
+    fn describe(&self) -> String {
+        format!("{} -> {} (protocol {}, id {})", self.src_addr, self.dest_addr, self.protocol, self.id)
+    }
+}
+
+struct FragmentBuffer {
+    fragments: BTreeMap<usize, Vec<u8>>,
+    /// Offsets of `fragments` in the order they were received
+    arrival: Vec<usize>,
+    /// Bytes stored in `fragments`
+    bytes: usize,
+    total_len: Option<usize>,
+    first_seen: DateTime<Local>,
+    wire_bytes: u32,
+    overlapping: bool
+}
+
+impl FragmentBuffer {
+    fn is_complete(&self) -> bool {
+        let total_len = match self.total_len {
+            Some(len) => len,
+            None => return false
+        };
+        let mut covered = 0;
+        for (offset, data) in &self.fragments {
+            if *offset > covered {
+                return false;
+            }
+            covered = covered.max(offset + data.len());
+        }
+        covered >= total_len
+    }
+
+    fn assemble(&self) -> Vec<u8> {
+        let total_len = self.total_len.unwrap_or(0);
+        let mut payload = vec![0; total_len];
+        // Latest first, so that the bytes received first are written last and win the overlaps
+        for offset in self.arrival.iter().rev() {
+            let data = &self.fragments[offset];
+            let end = (offset + data.len()).min(total_len);
+            if *offset < end {
+                payload[*offset..end].copy_from_slice(&data[..end - offset]);
+            }
+        }
+        payload
+    }
+}
+
+/// Datagram rebuilt from its fragments
+pub struct Reassembled {
+    pub payload: Vec<u8>,
+    /// Sum of the on-wire lengths of every fragment
+    pub wire_bytes: u32
+}
+
+/// Bounded buffer of the IPv4/IPv6 datagrams waiting for their fragments
+pub struct FragmentTable {
+    pub timeout: Duration,
+    pub max_datagrams: usize,
+    /// Fragment bytes kept at the same time, for all the datagrams
+    pub max_bytes: usize,
+    buffered_bytes: usize,
+    buffers: HashMap<FragmentKey, FragmentBuffer>
+}
+
+impl FragmentTable {
+    pub fn new(timeout: Duration, max_datagrams: usize, max_bytes: usize) -> Self {
+        FragmentTable {
+            timeout,
+            max_datagrams,
+            max_bytes,
+            buffered_bytes: 0,
+            buffers: HashMap::new()
+        }
+    }
+
+    fn remove(&mut self, key: &FragmentKey) -> Option<FragmentBuffer> {
+        let buffer = self.buffers.remove(key)?;
+        self.buffered_bytes -= buffer.bytes;
+        Some(buffer)
+    }
+
+    /// Drop the oldest datagram other than `key`, returning false if there is none
+    fn drop_oldest(&mut self, key: &FragmentKey, timestamp: DateTime<Local>, anomalies: &mut Vec<Anomaly>) -> bool {
+        let oldest = self.buffers.iter()
+            .filter(|(other, _)| *other != key)
+            .min_by_key(|(_, buffer)| buffer.first_seen)
+            .map(|(key, _)| key.clone());
+        match oldest {
+            Some(oldest) => {
+                self.remove(&oldest);
+                anomalies.push(Anomaly::new(timestamp, AnomalyKind::IncompleteFragments, format!("{}: dropped, reassembly buffer full", oldest.describe())));
+                true
+            },
+            None => false
+        }
+    }
+
+    /// Store a fragment (`offset` in bytes from the start of the datagram payload) and
+    /// return the whole payload once every fragment has been received
+    pub fn insert(
+        &mut self,
+        key: FragmentKey,
+        offset: usize,
+        more_fragments: bool,
+        data: &[u8],
+        frame: &Frame,
+        anomalies: &mut Vec<Anomaly>
+    ) -> Option<Reassembled> {
+        let timestamp = frame.timestamp;
+        self.expire(timestamp, anomalies);
+
+        if offset + data.len() > MAX_DATAGRAM_LEN {
+            self.remove(&key);
+            anomalies.push(Anomaly::new(timestamp, AnomalyKind::OversizedDatagram, key.describe()));
+            return None;
+        }
+
+        if !self.buffers.contains_key(&key) && self.buffers.len() >= self.max_datagrams {
+            // Make room dropping the oldest datagram
+            self.drop_oldest(&key, timestamp, anomalies);
+        }
+        // Same for the bytes, a datagram that can't fit alone (overlapping over and over) is dropped
+        while self.buffered_bytes + data.len() > self.max_bytes {
+            if !self.drop_oldest(&key, timestamp, anomalies) {
+                self.remove(&key);
+                anomalies.push(Anomaly::new(timestamp, AnomalyKind::OversizedDatagram, format!("{}: overlapping fragments over the reassembly buffer", key.describe())));
+                return None;
+            }
+        }
+
+        let buffer = self.buffers.entry(key.clone()).or_insert_with(|| FragmentBuffer {
+            fragments: BTreeMap::new(),
+            arrival: Vec::new(),
+            bytes: 0,
+            total_len: None,
+            first_seen: timestamp,
+            wire_bytes: 0,
+            overlapping: false
+        });
+        buffer.wire_bytes += frame.wire_len;
+
+        // Exact retransmissions are ignored, any other overlap is reported once per datagram
+        let end = offset + data.len();
+        let stored = buffer.fragments.get(&offset).map(|d| d.len());
+        let duplicate = stored == Some(data.len());
+        let overlaps = !duplicate && buffer.fragments.iter()
+            .any(|(o, d)| *o < end && offset < o + d.len());
+        if overlaps && !buffer.overlapping {
+            buffer.overlapping = true;
+            let description = match stored {
+                Some(stored) => format!("{}: {} bytes at offset {} conflicting with {} bytes received before", key.describe(), data.len(), offset, stored),
+                None => key.describe()
+            };
+            anomalies.push(Anomaly::new(timestamp, AnomalyKind::OverlappingFragments, description));
+        }
+        // The first copy of the bytes is kept: a longer fragment at the same offset only adds its tail
+        let (offset, data) = match stored {
+            Some(stored) if stored < data.len() => (offset + stored, &data[stored..]),
+            _ => (offset, data)
+        };
+        if !duplicate && !buffer.fragments.contains_key(&offset) {
+            buffer.fragments.insert(offset, data.to_vec());
+            buffer.arrival.push(offset);
+            buffer.bytes += data.len();
+            self.buffered_bytes += data.len();
+        }
+        if !more_fragments {
+            buffer.total_len = Some(end);
+        }
+
+        if buffer.is_complete() {
+            let buffer = self.remove(&key).unwrap();
+            return Some(Reassembled {
+                payload: buffer.assemble(),
+                wire_bytes: buffer.wire_bytes
+            });
+        }
+        None
+    }
+
+    /// Drop the datagrams still incomplete after the timeout
+    pub fn expire(&mut self, now: DateTime<Local>, anomalies: &mut Vec<Anomaly>) {
+        let timeout = self.timeout;
+        let mut buffered_bytes = self.buffered_bytes;
+        self.buffers.retain(|key, buffer| {
+            let alive = now - buffer.first_seen <= timeout;
+            if !alive {
+                buffered_bytes -= buffer.bytes;
+                anomalies.push(Anomaly::new(now, AnomalyKind::IncompleteFragments, key.describe()));
+            }
+            alive
+        });
+        self.buffered_bytes = buffered_bytes;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pcap::Linktype;
+
+    fn frame(timestamp: DateTime<Local>, wire_len: u32) -> Frame {
+        Frame { timestamp, caplen: wire_len, wire_len, ..Frame::fixture(Linktype::ETHERNET, Vec::new()) }
+    }
+
+    fn key(id: u32) -> FragmentKey {
+        FragmentKey {
+            src_addr: "192.0.2.1".parse().unwrap(),
+            dest_addr: "192.0.2.2".parse().unwrap(),
+            protocol: 17,
+            id
+        }
+    }
+
+    #[test]
+    fn reassembles_out_of_order_fragments() {
+        let mut table = FragmentTable::new(Duration::seconds(30), 16, 1024 * 1024);
+        let mut anomalies = Vec::new();
+        let now = Local::now();
+
+        assert!(table.insert(key(1), 8, false, &[2; 4], &frame(now, 46), &mut anomalies).is_none());
+        let reassembled = table.insert(key(1), 0, true, &[1; 8], &frame(now, 50), &mut anomalies).unwrap();
+
+        assert_eq!(reassembled.payload, [1, 1, 1, 1, 1, 1, 1, 1, 2, 2, 2, 2]);
+        assert_eq!(reassembled.wire_bytes, 96);
+        assert!(anomalies.is_empty());
+    }
+
+    #[test]
+    fn retransmitted_fragment_is_not_an_overlap() {
+        let mut table = FragmentTable::new(Duration::seconds(30), 16, 1024 * 1024);
+        let mut anomalies = Vec::new();
+        let now = Local::now();
+
+        table.insert(key(1), 0, true, &[1; 8], &frame(now, 50), &mut anomalies);
+        table.insert(key(1), 0, true, &[1; 8], &frame(now, 50), &mut anomalies);
+        let reassembled = table.insert(key(1), 8, false, &[2; 8], &frame(now, 50), &mut anomalies).unwrap();
+
+        assert_eq!(reassembled.payload.len(), 16);
+        assert!(anomalies.is_empty());
+    }
+
+    #[test]
+    fn overlapping_fragments_keep_the_first_copy() {
+        let mut table = FragmentTable::new(Duration::seconds(30), 16, 1024 * 1024);
+        let mut anomalies = Vec::new();
+        let now = Local::now();
+
+        table.insert(key(1), 0, true, &[1; 16], &frame(now, 50), &mut anomalies);
+        table.insert(key(1), 8, true, &[2; 16], &frame(now, 50), &mut anomalies);
+        let reassembled = table.insert(key(1), 24, false, &[3; 8], &frame(now, 50), &mut anomalies).unwrap();
+
+        assert_eq!(&reassembled.payload[..16], &[1; 16]);
+        assert_eq!(&reassembled.payload[16..24], &[2; 8]);
+        assert_eq!(anomalies.len(), 1);
+        assert_eq!(anomalies[0].kind, AnomalyKind::OverlappingFragments);
+    }
+
+    #[test]
+    fn conflicting_fragment_at_the_same_offset() {
+        let mut table = FragmentTable::new(Duration::seconds(30), 16, 1024 * 1024);
+        let mut anomalies = Vec::new();
+        let now = Local::now();
+
+        table.insert(key(1), 0, true, &[1; 8], &frame(now, 50), &mut anomalies);
+        // Longer than the first copy: only its tail is kept
+        table.insert(key(1), 0, true, &[2; 16], &frame(now, 50), &mut anomalies);
+        let reassembled = table.insert(key(1), 16, false, &[3; 8], &frame(now, 50), &mut anomalies).unwrap();
+
+        assert_eq!(&reassembled.payload[..8], &[1; 8]);
+        assert_eq!(&reassembled.payload[8..16], &[2; 8]);
+        assert_eq!(anomalies.len(), 1);
+        assert_eq!(anomalies[0].kind, AnomalyKind::OverlappingFragments);
+        assert!(anomalies[0].description.contains("16 bytes at offset 0 conflicting with 8 bytes"));
+    }
+
+    #[test]
+    fn oversized_datagram_is_dropped() {
+        let mut table = FragmentTable::new(Duration::seconds(30), 16, 1024 * 1024);
+        let mut anomalies = Vec::new();
+        let now = Local::now();
+
+        assert!(table.insert(key(1), 65528, false, &[0; 16], &frame(now, 50), &mut anomalies).is_none());
+        assert_eq!(anomalies[0].kind, AnomalyKind::OversizedDatagram);
+    }
+
+    #[test]
+    fn byte_budget_drops_the_oldest_datagram() {
+        let mut table = FragmentTable::new(Duration::seconds(30), 16, 32);
+        let mut anomalies = Vec::new();
+        let now = Local::now();
+
+        table.insert(key(1), 0, true, &[1; 24], &frame(now, 50), &mut anomalies);
+        table.insert(key(2), 0, true, &[2; 24], &frame(now + Duration::seconds(1), 50), &mut anomalies);
+
+        assert_eq!(anomalies.len(), 1);
+        assert_eq!(anomalies[0].kind, AnomalyKind::IncompleteFragments);
+        assert!(anomalies[0].description.contains("id 1"));
+        assert_eq!(table.buffered_bytes, 24);
+    }
+
+    #[test]
+    fn datagram_over_the_byte_budget_alone_is_dropped() {
+        let mut table = FragmentTable::new(Duration::seconds(30), 16, 32);
+        let mut anomalies = Vec::new();
+        let now = Local::now();
+
+        table.insert(key(1), 0, true, &[1; 24], &frame(now, 50), &mut anomalies);
+        table.insert(key(1), 8, true, &[1; 24], &frame(now, 50), &mut anomalies);
+
+        assert_eq!(anomalies.last().unwrap().kind, AnomalyKind::OversizedDatagram);
+        assert_eq!(table.buffered_bytes, 0);
+    }
+
+    #[test]
+    fn incomplete_datagram_expires() {
+        let mut table = FragmentTable::new(Duration::seconds(30), 16, 1024 * 1024);
+        let mut anomalies = Vec::new();
+        let now = Local::now();
+
+        table.insert(key(1), 0, true, &[1; 8], &frame(now, 50), &mut anomalies);
+        table.expire(now + Duration::seconds(31), &mut anomalies);
+
+        assert_eq!(anomalies.len(), 1);
+        assert_eq!(anomalies[0].kind, AnomalyKind::IncompleteFragments);
+        assert_eq!(table.buffered_bytes, 0);
+    }
+}
@@ -0,0 +1,426 @@
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+
+use chrono::{DateTime, Duration, Local};
+use serde::Serialize;
+
+use crate::connection::ConnectionKey;
+use crate::stream::StreamData;
+
+// Headers longer than this are not HTTP (or not worth waiting for)
+const MAX_HEAD_LEN: usize = 16 * 1024;
+// Rows of every category in the HTTP section of the report
+const TOP_ROWS: usize = 10;
+// Pipelined requests remembered while waiting for their response
+const MAX_PENDING_REQUESTS: usize = 64;
+
+const METHODS: [&str; 9] = ["GET", "POST", "PUT", "DELETE", "HEAD", "OPTIONS", "PATCH", "CONNECT", "TRACE"];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HttpRequest {
+    pub method: String,
+    pub host: Option<String>,
+    pub uri: String,
+    pub user_agent: Option<String>,
+    pub content_type: Option<String>
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HttpResponse {
+    pub status: u16,
+    pub reason: String,
+    pub content_type: Option<String>
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HttpMessage {
+    Request(HttpRequest),
+    Response(HttpResponse)
+}
+
+impl fmt::Display for HttpMessage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HttpMessage::Request(request) => write!(f, "{} {}{}", request.method, request.host.as_deref().unwrap_or(""), request.uri),
+            HttpMessage::Response(response) => write!(f, "{} {}", response.status, response.reason)
+        }
+    }
+}
+
+/// Check if the data starts with an HTTP/1.x request or status line
+pub fn looks_like_http(data: &[u8]) -> bool {
+    data.starts_with(b"HTTP/1.")
+        || METHODS.iter().any(|method| data.starts_with(method.as_bytes()) && data.get(method.len()) == Some(&b' '))
+}
+
+/// Length of the body that follows a message head, None if it lasts until the
+/// connection is closed or it is chunked
+enum BodyLength {
+    Known(usize),
+    Unknown
+}
+
+/// Parse the start line and the headers of an HTTP/1.x message (everything before the empty line)
+fn parse_head(head: &str) -> Option<(HttpMessage, BodyLength)> {
+    let mut lines = head.split("\r\n");
+    let start_line = lines.next()?;
+    let mut headers = HashMap::new();
+    for line in lines {
+        if let Some((name, value)) = line.split_once(':') {
+            headers.insert(name.trim().to_ascii_lowercase(), value.trim().to_string());
+        }
+    }
+
+    let chunked = headers.get("transfer-encoding").map(|value| value.to_ascii_lowercase().contains("chunked")).unwrap_or(false);
+    let content_length = headers.get("content-length").and_then(|value| value.parse::<usize>().ok());
+    let mut parts = start_line.splitn(3, ' ');
+
+    if start_line.starts_with("HTTP/1.") {
+        let _version = parts.next()?;
+        let status = parts.next()?.parse::<u16>().ok()?;
+        let body = match (chunked, content_length) {
+            // No body, whatever the headers say
+            _ if (100..200).contains(&status) || status == 204 || status == 304 => BodyLength::Known(0),
+            (false, Some(length)) => BodyLength::Known(length),
+            _ => BodyLength::Unknown
+        };
+        let response = HttpResponse {
+            status,
+            reason: parts.next().unwrap_or("").to_string(),
+            content_type: headers.get("content-type").cloned()
+        };
+        return Some((HttpMessage::Response(response), body));
+    }
+
+    let method = parts.next()?;
+    let uri = parts.next()?;
+    if !METHODS.contains(&method) || !parts.next()?.starts_with("HTTP/1.") {
+        return None;
+    }
+    // Requests without a length have no body
+    let body = match (chunked, content_length) {
+        (true, _) => BodyLength::Unknown,
+        (false, length) => BodyLength::Known(length.unwrap_or(0))
+    };
+    let request = HttpRequest {
+        method: method.to_string(),
+        host: headers.get("host").cloned(),
+        uri: uri.to_string(),
+        user_agent: headers.get("user-agent").cloned(),
+        content_type: headers.get("content-type").cloned()
+    };
+    Some((HttpMessage::Request(request), body))
+}
+
+enum HttpStreamState {
+    /// Waiting for (or reading) the head of the next message
+    Head(Vec<u8>),
+    /// Skipping the given number of body bytes
+    Body(usize),
+    /// Body of unknown length: the next message is expected at the start of a segment
+    Resync,
+    /// Not HTTP, ignored until the connection is over
+    Ignored
+}
+
+struct HttpConnection {
+    /// Parsing state of the direction from the initiator and of the one back, once they sent data
+    initiator: Option<HttpStreamState>,
+    responder: Option<HttpStreamState>,
+    /// Methods of the requests waiting for their response, oldest first
+    methods: VecDeque<String>,
+    last_seen: DateTime<Local>
+}
+
+/// Row of the HTTP section of the report
+#[derive(Debug, Clone, Serialize)]
+pub struct HttpSummary {
+    pub category: String,
+    pub value: String,
+    pub count: u32
+}
+
+impl fmt::Display for HttpSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "| {0: <8}\t| {1: <60}\t| {2}", self.category, self.value, self.count)
+    }
+}
+
+/// HTTP/1.x analyzer fed with the reassembled TCP streams, on any port
+pub struct HttpTable {
+    pub timeout: Duration,
+    pub max_connections: usize,
+    connections: HashMap<ConnectionKey, HttpConnection>,
+    hosts: HashMap<String, u32>,
+    uris: HashMap<String, u32>,
+    statuses: HashMap<u16, u32>
+}
+
+impl HttpTable {
+    pub fn new(timeout: Duration, max_connections: usize) -> Self {
+        HttpTable {
+            timeout,
+            max_connections,
+            connections: HashMap::new(),
+            hosts: HashMap::new(),
+            uris: HashMap::new(),
+            statuses: HashMap::new()
+        }
+    }
+
+    /// Go on parsing a direction of a connection, returning the messages whose head has been completed
+    pub fn process(&mut self, stream: &StreamData) -> Vec<HttpMessage> {
+        if !self.connections.contains_key(&stream.connection) && self.connections.len() >= self.max_connections {
+            // Make room dropping the connection idle for the longest time
+            let oldest = self.connections.iter()
+                .min_by_key(|(_, connection)| connection.last_seen)
+                .map(|(key, _)| key.clone());
+            if let Some(oldest) = oldest {
+                self.connections.remove(&oldest);
+            }
+        }
+        let connection = self.connections.entry(stream.connection.clone()).or_insert_with(|| HttpConnection {
+            initiator: None,
+            responder: None,
+            methods: VecDeque::new(),
+            last_seen: stream.timestamp
+        });
+        connection.last_seen = stream.timestamp;
+
+        let HttpConnection { initiator, responder, methods, .. } = connection;
+        let state = match stream.from_initiator {
+            true => initiator,
+            false => responder
+        };
+        // A stream is HTTP only if it starts like HTTP
+        let state = state.get_or_insert_with(|| match looks_like_http(&stream.data) {
+            true => HttpStreamState::Head(Vec::new()),
+            false => HttpStreamState::Ignored
+        });
+
+        // After missing bytes, wait for something that looks like the start of a message
+        if stream.gap && !matches!(state, HttpStreamState::Ignored) {
+            *state = HttpStreamState::Resync;
+        }
+
+        let mut messages = Vec::new();
+        let mut data = &stream.data[..];
+        while !data.is_empty() {
+            match state {
+                HttpStreamState::Ignored => break,
+                HttpStreamState::Resync => {
+                    if !looks_like_http(data) {
+                        break;
+                    }
+                    *state = HttpStreamState::Head(Vec::new());
+                },
+                HttpStreamState::Body(remaining) => {
+                    let skip = (*remaining).min(data.len());
+                    *remaining -= skip;
+                    data = &data[skip..];
+                    if *remaining == 0 {
+                        *state = HttpStreamState::Head(Vec::new());
+                    }
+                },
+                HttpStreamState::Head(buffer) => {
+                    let before = buffer.len();
+                    buffer.extend_from_slice(data);
+                    let end = match buffer.windows(4).position(|window| window == b"\r\n\r\n") {
+                        Some(end) => end,
+                        None => {
+                            if buffer.len() > MAX_HEAD_LEN {
+                                *state = HttpStreamState::Ignored;
+                            }
+                            break;
+                        }
+                    };
+                    // What follows the empty line belongs to the body (or to the next message)
+                    data = &data[end + 4 - before..];
+                    let head = String::from_utf8_lossy(&buffer[..end]).to_string();
+                    *state = match parse_head(&head) {
+                        Some((message, body)) => {
+                            let body = match &message {
+                                HttpMessage::Request(request) => {
+                                    if methods.len() < MAX_PENDING_REQUESTS {
+                                        methods.push_back(request.method.clone());
+                                    }
+                                    body
+                                },
+                                // Interim responses come before the one answering the request
+                                HttpMessage::Response(response) if (100..200).contains(&response.status) => body,
+                                // The response to a HEAD has the headers of a GET, without its body
+                                HttpMessage::Response(_) => match methods.pop_front().as_deref() {
+                                    Some("HEAD") => BodyLength::Known(0),
+                                    _ => body
+                                }
+                            };
+                            messages.push(message);
+                            match body {
+                                BodyLength::Known(0) => HttpStreamState::Head(Vec::new()),
+                                BodyLength::Known(length) => HttpStreamState::Body(length),
+                                BodyLength::Unknown => HttpStreamState::Resync
+                            }
+                        },
+                        None => HttpStreamState::Ignored
+                    };
+                }
+            }
+        }
+
+        for message in &messages {
+            match message {
+                HttpMessage::Request(request) => {
+                    let host = request.host.clone().unwrap_or("-".to_string());
+                    *self.uris.entry(format!("{}{}", host, request.uri)).or_insert(0) += 1;
+                    *self.hosts.entry(host).or_insert(0) += 1;
+                },
+                HttpMessage::Response(response) => {
+                    *self.statuses.entry(response.status).or_insert(0) += 1;
+                }
+            }
+        }
+        messages
+    }
+
+    /// Forget both directions of a connection that is over
+    pub fn close(&mut self, connection: &ConnectionKey) {
+        self.connections.remove(connection);
+    }
+
+    /// Drop the connections idle for longer than the timeout
+    pub fn expire(&mut self, now: DateTime<Local>) {
+        let timeout = self.timeout;
+        self.connections.retain(|_, connection| now - connection.last_seen <= timeout);
+    }
+
+    /// Return the top hosts, URIs and status codes seen since the last call
+    pub fn take_summary(&mut self) -> Vec<HttpSummary> {
+        let mut summary = Vec::new();
+        let categories = [
+            ("host", std::mem::take(&mut self.hosts)),
+            ("uri", std::mem::take(&mut self.uris)),
+            ("status", std::mem::take(&mut self.statuses).into_iter().map(|(status, count)| (status.to_string(), count)).collect())
+        ];
+
+        for (category, counts) in categories {
+            let mut counts: Vec<(String, u32)> = counts.into_iter().collect();
+            counts.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+            summary.extend(counts.into_iter().take(TOP_ROWS).map(|(value, count)| HttpSummary {
+                category: category.to_string(),
+                value,
+                count
+            }));
+        }
+        summary
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stream(from_initiator: bool, data: &[u8]) -> StreamData {
+        let client = ("192.0.2.1".parse().unwrap(), 40000);
+        let server = ("192.0.2.2".parse().unwrap(), 80);
+        StreamData {
+            connection: ConnectionKey::new("eth0", &[], client, server),
+            source: if from_initiator { client } else { server },
+            from_initiator,
+            timestamp: Local::now(),
+            gap: false,
+            data: data.to_vec()
+        }
+    }
+
+    #[test]
+    fn request_head() {
+        let (message, body) = parse_head("GET /index.html HTTP/1.1\r\nHost: example.com\r\nuser-agent: curl/8.0\r\nContent-Length: 5").unwrap();
+
+        assert_eq!(message, HttpMessage::Request(HttpRequest {
+            method: "GET".to_string(),
+            host: Some("example.com".to_string()),
+            uri: "/index.html".to_string(),
+            user_agent: Some("curl/8.0".to_string()),
+            content_type: None
+        }));
+        assert!(matches!(body, BodyLength::Known(5)));
+    }
+
+    #[test]
+    fn response_head() {
+        let (message, body) = parse_head("HTTP/1.1 404 Not Found\r\nContent-Type: text/html\r\nTransfer-Encoding: chunked").unwrap();
+
+        assert_eq!(message, HttpMessage::Response(HttpResponse { status: 404, reason: "Not Found".to_string(), content_type: Some("text/html".to_string()) }));
+        assert!(matches!(body, BodyLength::Unknown));
+        // No body, whatever the headers say
+        assert!(matches!(parse_head("HTTP/1.1 304 Not Modified\r\nContent-Length: 10").unwrap().1, BodyLength::Known(0)));
+    }
+
+    #[test]
+    fn not_http_heads() {
+        assert!(parse_head("FETCH / HTTP/1.1").is_none());
+        assert!(parse_head("GET / SPDY/3").is_none());
+        assert!(parse_head("HTTP/1.1 abc OK").is_none());
+    }
+
+    #[test]
+    fn pipelined_requests_split_across_segments() {
+        let mut table = HttpTable::new(Duration::seconds(120), 16);
+
+        assert_eq!(table.process(&stream(true, b"POST /a HTTP/1.1\r\nHost: example.com\r\nContent-Length: 4\r\n\r\nab")).len(), 1);
+        // End of the body and the next head, in two segments
+        assert!(table.process(&stream(true, b"cdGET /b HTTP/1.1\r\nHo")).is_empty());
+        let messages = table.process(&stream(true, b"st: example.com\r\n\r\n"));
+
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].to_string(), "GET example.com/b");
+    }
+
+    #[test]
+    fn streams_that_do_not_start_like_http_are_ignored() {
+        let mut table = HttpTable::new(Duration::seconds(120), 16);
+
+        assert!(table.process(&stream(true, b"\x16\x03\x01\x00")).is_empty());
+        assert!(table.process(&stream(true, b"GET / HTTP/1.1\r\n\r\n")).is_empty());
+    }
+
+    #[test]
+    fn responses_to_head_requests_have_no_body() {
+        let mut table = HttpTable::new(Duration::seconds(120), 16);
+
+        table.process(&stream(true, b"HEAD /big HTTP/1.1\r\nHost: example.com\r\n\r\nGET /small HTTP/1.1\r\nHost: example.com\r\n\r\n"));
+        let messages = table.process(&stream(false, b"HTTP/1.1 100 Continue\r\n\r\nHTTP/1.1 200 OK\r\nContent-Length: 1000\r\n\r\nHTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok"));
+        assert_eq!(messages.len(), 3);
+        // Still in sync with the responses that follow
+        assert_eq!(table.process(&stream(false, b"HTTP/1.1 404 Not Found\r\n\r\n")).len(), 1);
+    }
+
+    #[test]
+    fn full_table_drops_the_connection_idle_for_the_longest_time() {
+        let mut table = HttpTable::new(Duration::seconds(120), 1);
+        let mut other = stream(true, b"GET / HTTP/1.1\r\n");
+        other.connection.low.1 = 40001;
+
+        table.process(&stream(true, b"GET / HTTP/1.1\r\n"));
+        table.process(&other);
+
+        assert_eq!(table.connections.len(), 1);
+        assert!(table.connections.contains_key(&other.connection));
+    }
+
+    #[test]
+    fn summary_counts_hosts_uris_and_statuses() {
+        let mut table = HttpTable::new(Duration::seconds(120), 16);
+
+        table.process(&stream(true, b"GET / HTTP/1.1\r\nHost: example.com\r\n\r\nGET / HTTP/1.1\r\nHost: example.com\r\n\r\n"));
+        table.process(&stream(false, b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n"));
+        let summary: Vec<(String, String, u32)> = table.take_summary().into_iter().map(|row| (row.category, row.value, row.count)).collect();
+
+        assert_eq!(summary, [
+            ("host".to_string(), "example.com".to_string(), 2),
+            ("uri".to_string(), "example.com/".to_string(), 2),
+            ("status".to_string(), "200".to_string(), 1)
+        ]);
+        assert!(table.take_summary().is_empty());
+    }
+}
@@ -0,0 +1,208 @@
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr};
+
+use serde::Serializer;
+
+fn icmp_type_name(icmp_type: u8) -> String {
+    let name = match icmp_type {
+        0 => "echo reply",
+        3 => "destination unreachable",
+        4 => "source quench",
+        5 => "redirect",
+        8 => "echo request",
+        9 => "router advertisement",
+        10 => "router solicitation",
+        11 => "time exceeded",
+        12 => "parameter problem",
+        13 => "timestamp",
+        14 => "timestamp reply",
+        other => return format!("type {}", other)
+    };
+    name.to_string()
+}
+
+/// IP and transport header of the datagram that triggered an ICMP error
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QuotedHeader {
+    pub protocol: u8,
+    pub src_addr: IpAddr,
+    pub dest_addr: IpAddr,
+    pub src_port: Option<u16>,
+    pub dest_port: Option<u16>
+}
+
+impl fmt::Display for QuotedHeader {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let protocol = match self.protocol {
+            1 => "ICMP".to_string(),
+            6 => "TCP".to_string(),
+            17 => "UDP".to_string(),
+            other => format!("protocol {}", other)
+        };
+        match (self.src_port, self.dest_port) {
+            (Some(sport), Some(dport)) => write!(f, "{} {}:{} -> {}:{}", protocol, self.src_addr, sport, self.dest_addr, dport),
+            _ => write!(f, "{} {} -> {}", protocol, self.src_addr, self.dest_addr)
+        }
+    }
+}
+
+/// Serialize the header quoted by an ICMP error as a single field, `-` for the other packets
+pub fn serialize_quoted<S: Serializer>(quoted: &Option<QuotedHeader>, serializer: S) -> Result<S::Ok, S::Error> {
+    match quoted {
+        Some(quoted) => serializer.serialize_str(&quoted.to_string()),
+        None => serializer.serialize_str("-")
+    }
+}
+
+/// Content of an ICMP message
+#[derive(Debug)]
+pub struct IcmpMessage {
+    pub icmp_type: u8,
+    pub code: u8,
+    /// Identifier and sequence number of echo and timestamp messages
+    pub identifier: Option<u16>,
+    pub sequence: Option<u16>,
+    /// Header quoted by Destination Unreachable, Source Quench, Redirect, Time Exceeded and Parameter Problem
+    pub quoted: Option<QuotedHeader>
+}
+
+impl IcmpMessage {
+    /// Name of the message, with the code when it is meaningful
+    pub fn describe(&self) -> String {
+        match self.code {
+            0 => format!("ICMP {}", icmp_type_name(self.icmp_type)),
+            code => format!("ICMP {} (code {})", icmp_type_name(self.icmp_type), code)
+        }
+    }
+}
+
+/// Parse the IPv4 header (and the first 8 bytes of its payload) quoted by an ICMP error
+fn parse_quoted_header(packet: &[u8]) -> Option<QuotedHeader> {
+    let header = packet.get(..20)?;
+    if header[0] >> 4 != 4 {
+        return None;
+    }
+    let header_len = (header[0] & 0x0f) as usize * 4;
+    let protocol = header[9];
+    let src_addr = Ipv4Addr::new(header[12], header[13], header[14], header[15]);
+    let dest_addr = Ipv4Addr::new(header[16], header[17], header[18], header[19]);
+
+    // Only TCP and UDP start with the ports, the first fragment quotes them
+    let ports = match protocol {
+        6 | 17 => packet.get(header_len..header_len + 4),
+        _ => None
+    };
+
+    Some(QuotedHeader {
+        protocol,
+        src_addr: IpAddr::V4(src_addr),
+        dest_addr: IpAddr::V4(dest_addr),
+        src_port: ports.map(|ports| u16::from_be_bytes([ports[0], ports[1]])),
+        dest_port: ports.map(|ports| u16::from_be_bytes([ports[2], ports[3]]))
+    })
+}
+
+/// Parse an ICMP message. Returns None if it is shorter than the 8 bytes of its header.
+pub fn parse_icmp(packet: &[u8]) -> Option<IcmpMessage> {
+    let header = packet.get(..8)?;
+    let mut message = IcmpMessage {
+        icmp_type: header[0],
+        code: header[1],
+        identifier: None,
+        sequence: None,
+        quoted: None
+    };
+
+    match message.icmp_type {
+        0 | 8 | 13 | 14 => {
+            message.identifier = Some(u16::from_be_bytes([header[4], header[5]]));
+            message.sequence = Some(u16::from_be_bytes([header[6], header[7]]));
+        },
+        3 | 4 | 5 | 11 | 12 => {
+            message.quoted = parse_quoted_header(&packet[8..]);
+        },
+        _ => {}
+    }
+
+    Some(message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// IPv4 header from 192.0.2.1 to 198.51.100.1 followed by `payload`, as quoted by an error
+    fn quoted_datagram(protocol: u8, payload: &[u8]) -> Vec<u8> {
+        let mut packet = vec![0x45, 0, 0, 60, 0x12, 0x34, 0, 0, 1, protocol, 0, 0, 192, 0, 2, 1, 198, 51, 100, 1];
+        packet.extend_from_slice(payload);
+        packet
+    }
+
+    fn error(icmp_type: u8, code: u8, quoted: &[u8]) -> Vec<u8> {
+        let mut packet = vec![icmp_type, code, 0, 0, 0, 0, 0, 0];
+        packet.extend_from_slice(quoted);
+        packet
+    }
+
+    #[test]
+    fn echo_identifier_and_sequence() {
+        let message = parse_icmp(&[8, 0, 0xf7, 0xfd, 0x12, 0x34, 0x00, 0x2a, b'p', b'i', b'n', b'g']).unwrap();
+
+        assert_eq!(message.identifier, Some(0x1234));
+        assert_eq!(message.sequence, Some(42));
+        assert_eq!(message.quoted, None);
+        assert_eq!(message.describe(), "ICMP echo request");
+    }
+
+    #[test]
+    fn port_unreachable_quotes_the_udp_datagram() {
+        // Source port 53000, destination port 53, length and checksum
+        let quoted = quoted_datagram(17, &[0xcf, 0x08, 0, 53, 0, 40, 0, 0]);
+        let message = parse_icmp(&error(3, 3, &quoted)).unwrap();
+
+        assert_eq!(message.describe(), "ICMP destination unreachable (code 3)");
+        assert_eq!(message.identifier, None);
+        let quoted = message.quoted.unwrap();
+        assert_eq!(quoted.src_port, Some(53000));
+        assert_eq!(quoted.dest_port, Some(53));
+        assert_eq!(quoted.to_string(), "UDP 192.0.2.1:53000 -> 198.51.100.1:53");
+    }
+
+    #[test]
+    fn time_exceeded_quotes_the_header_without_ports() {
+        // Echo request that ran out of hops: ICMP has no ports
+        let message = parse_icmp(&error(11, 0, &quoted_datagram(1, &[8, 0, 0, 0, 0, 1, 0, 1]))).unwrap();
+
+        assert_eq!(message.describe(), "ICMP time exceeded");
+        assert_eq!(message.quoted.unwrap().to_string(), "ICMP 192.0.2.1 -> 198.51.100.1");
+    }
+
+    #[test]
+    fn truncated_quote() {
+        // The IP header only, the ports are missing
+        let message = parse_icmp(&error(3, 1, &quoted_datagram(6, &[0xcf, 0x08]))).unwrap();
+        assert_eq!(message.quoted.unwrap().to_string(), "TCP 192.0.2.1 -> 198.51.100.1");
+
+        // Not even the whole IP header
+        assert_eq!(parse_icmp(&error(3, 1, &quoted_datagram(6, &[])[..12])).unwrap().quoted, None);
+        // Not an IPv4 header
+        assert_eq!(parse_icmp(&error(3, 1, &[0x60; 40])).unwrap().quoted, None);
+        assert!(parse_icmp(&[3, 1, 0, 0]).is_none());
+    }
+
+    #[test]
+    fn quoted_serialized_as_a_single_field() {
+        #[derive(serde::Serialize)]
+        struct Row {
+            #[serde(serialize_with = "serialize_quoted")]
+            quoted: Option<QuotedHeader>
+        }
+        let mut writer = csv::Writer::from_writer(Vec::new());
+
+        writer.serialize(Row { quoted: None }).unwrap();
+        writer.serialize(Row { quoted: parse_icmp(&error(11, 0, &quoted_datagram(1, &[]))).unwrap().quoted }).unwrap();
+
+        let csv = String::from_utf8(writer.into_inner().unwrap()).unwrap();
+        assert_eq!(csv, "quoted\n-\nICMP 192.0.2.1 -> 198.51.100.1\n");
+    }
+}
@@ -0,0 +1,276 @@
+use std::fmt;
+use std::net::Ipv6Addr;
+
+use pktparse::ethernet::MacAddress;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Icmpv6Type {
+    DestinationUnreachable,
+    PacketTooBig,
+    TimeExceeded,
+    ParameterProblem,
+    EchoRequest,
+    EchoReply,
+    MulticastListenerQuery,
+    MulticastListenerReport,
+    MulticastListenerDone,
+    RouterSolicitation,
+    RouterAdvertisement,
+    NeighborSolicitation,
+    NeighborAdvertisement,
+    Redirect,
+    MulticastListenerReportV2,
+    Other(u8)
+}
+
+impl From<u8> for Icmpv6Type {
+    fn from(raw: u8) -> Self {
+        match raw {
+            1 => Icmpv6Type::DestinationUnreachable,
+            2 => Icmpv6Type::PacketTooBig,
+            3 => Icmpv6Type::TimeExceeded,
+            4 => Icmpv6Type::ParameterProblem,
+            128 => Icmpv6Type::EchoRequest,
+            129 => Icmpv6Type::EchoReply,
+            130 => Icmpv6Type::MulticastListenerQuery,
+            131 => Icmpv6Type::MulticastListenerReport,
+            132 => Icmpv6Type::MulticastListenerDone,
+            133 => Icmpv6Type::RouterSolicitation,
+            134 => Icmpv6Type::RouterAdvertisement,
+            135 => Icmpv6Type::NeighborSolicitation,
+            136 => Icmpv6Type::NeighborAdvertisement,
+            137 => Icmpv6Type::Redirect,
+            143 => Icmpv6Type::MulticastListenerReportV2,
+            other => Icmpv6Type::Other(other)
+        }
+    }
+}
+
+impl From<Icmpv6Type> for u8 {
+    fn from(icmp_type: Icmpv6Type) -> Self {
+        match icmp_type {
+            Icmpv6Type::DestinationUnreachable => 1,
+            Icmpv6Type::PacketTooBig => 2,
+            Icmpv6Type::TimeExceeded => 3,
+            Icmpv6Type::ParameterProblem => 4,
+            Icmpv6Type::EchoRequest => 128,
+            Icmpv6Type::EchoReply => 129,
+            Icmpv6Type::MulticastListenerQuery => 130,
+            Icmpv6Type::MulticastListenerReport => 131,
+            Icmpv6Type::MulticastListenerDone => 132,
+            Icmpv6Type::RouterSolicitation => 133,
+            Icmpv6Type::RouterAdvertisement => 134,
+            Icmpv6Type::NeighborSolicitation => 135,
+            Icmpv6Type::NeighborAdvertisement => 136,
+            Icmpv6Type::Redirect => 137,
+            Icmpv6Type::MulticastListenerReportV2 => 143,
+            Icmpv6Type::Other(raw) => raw
+        }
+    }
+}
+
+impl fmt::Display for Icmpv6Type {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Icmpv6Type::DestinationUnreachable => write!(f, "destination unreachable"),
+            Icmpv6Type::PacketTooBig => write!(f, "packet too big"),
+            Icmpv6Type::TimeExceeded => write!(f, "time exceeded"),
+            Icmpv6Type::ParameterProblem => write!(f, "parameter problem"),
+            Icmpv6Type::EchoRequest => write!(f, "echo request"),
+            Icmpv6Type::EchoReply => write!(f, "echo reply"),
+            Icmpv6Type::MulticastListenerQuery => write!(f, "multicast listener query"),
+            Icmpv6Type::MulticastListenerReport => write!(f, "multicast listener report"),
+            Icmpv6Type::MulticastListenerDone => write!(f, "multicast listener done"),
+            Icmpv6Type::RouterSolicitation => write!(f, "router solicitation"),
+            Icmpv6Type::RouterAdvertisement => write!(f, "router advertisement"),
+            Icmpv6Type::NeighborSolicitation => write!(f, "neighbor solicitation"),
+            Icmpv6Type::NeighborAdvertisement => write!(f, "neighbor advertisement"),
+            Icmpv6Type::Redirect => write!(f, "redirect"),
+            Icmpv6Type::MulticastListenerReportV2 => write!(f, "multicast listener report v2"),
+            Icmpv6Type::Other(raw) => write!(f, "type {}", raw)
+        }
+    }
+}
+
+/// Content of an ICMPv6 message, with the Neighbor Discovery fields when present
+#[derive(Debug)]
+pub struct Icmpv6Message {
+    pub icmp_type: Icmpv6Type,
+    pub code: u8,
+    /// Target address of Neighbor Solicitation/Advertisement and Redirect
+    pub target: Option<Ipv6Addr>,
+    pub source_link_addr: Option<MacAddress>,
+    pub target_link_addr: Option<MacAddress>,
+    /// Router lifetime (in seconds) of a Router Advertisement, 0 if it is not a default router
+    pub router_lifetime: Option<u16>,
+    /// Prefixes (address and length) announced by a Router Advertisement
+    pub prefixes: Vec<(Ipv6Addr, u8)>
+}
+
+fn read_ipv6_addr(bytes: &[u8]) -> Option<Ipv6Addr> {
+    let octets: [u8; 16] = bytes.get(..16)?.try_into().ok()?;
+    Some(Ipv6Addr::from(octets))
+}
+
+/// Parse an ICMPv6 message. Returns None if it is shorter than its fixed part.
+pub fn parse_icmpv6(packet: &[u8]) -> Option<Icmpv6Message> {
+    let header = packet.get(..4)?;
+    let mut message = Icmpv6Message {
+        icmp_type: Icmpv6Type::from(header[0]),
+        code: header[1],
+        target: None,
+        source_link_addr: None,
+        target_link_addr: None,
+        router_lifetime: None,
+        prefixes: Vec::new()
+    };
+
+    // Offset of the NDP options, after the fixed part of every message
+    let options_offset = match message.icmp_type {
+        Icmpv6Type::RouterSolicitation => 8,
+        Icmpv6Type::RouterAdvertisement => {
+            let lifetime = packet.get(6..8)?;
+            message.router_lifetime = Some(u16::from_be_bytes([lifetime[0], lifetime[1]]));
+            16
+        },
+        Icmpv6Type::NeighborSolicitation | Icmpv6Type::NeighborAdvertisement => {
+            message.target = Some(read_ipv6_addr(packet.get(8..)?)?);
+            24
+        },
+        Icmpv6Type::Redirect => {
+            message.target = Some(read_ipv6_addr(packet.get(8..)?)?);
+            40
+        },
+        _ => return Some(message)
+    };
+
+    // Options are type, length (in 8-octet units) and value, a zero length is invalid
+    let mut offset = options_offset;
+    while let Some(option) = packet.get(offset..offset + 2) {
+        let length = option[1] as usize * 8;
+        let value = match packet.get(offset..offset + length) {
+            Some(value) if length > 0 => value,
+            _ => break
+        };
+        match option[0] {
+            1 | 2 if length >= 8 => {
+                let mac = MacAddress(value[2..8].try_into().unwrap());
+                if option[0] == 1 {
+                    message.source_link_addr = Some(mac);
+                } else {
+                    message.target_link_addr = Some(mac);
+                }
+            },
+            // Prefix Information
+            3 if length >= 32 => {
+                if let Some(prefix) = read_ipv6_addr(&value[16..]) {
+                    message.prefixes.push((prefix, value[2]));
+                }
+            },
+            _ => {}
+        }
+        offset += length;
+    }
+
+    Some(message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAC: MacAddress = MacAddress([0x02, 0, 0, 0, 0, 0x01]);
+
+    fn addr(addr: &str) -> Ipv6Addr {
+        addr.parse().unwrap()
+    }
+
+    /// Link-layer address option (1 for the source, 2 for the target)
+    fn link_addr_option(option: u8) -> Vec<u8> {
+        let mut data = vec![option, 1];
+        data.extend_from_slice(&MAC.0);
+        data
+    }
+
+    fn prefix_option(prefix: &str, length: u8) -> Vec<u8> {
+        // Flags, valid and preferred lifetimes, reserved
+        let mut data = vec![3, 4, length, 0xc0, 0, 0, 0x0e, 0x10, 0, 0, 0x0e, 0x10, 0, 0, 0, 0];
+        data.extend_from_slice(&addr(prefix).octets());
+        data
+    }
+
+    fn neighbor_message(icmp_type: u8, target: &str, options: &[u8]) -> Vec<u8> {
+        let mut packet = vec![icmp_type, 0, 0, 0, 0x60, 0, 0, 0];
+        packet.extend_from_slice(&addr(target).octets());
+        packet.extend_from_slice(options);
+        packet
+    }
+
+    fn router_advertisement(lifetime: u16, options: &[u8]) -> Vec<u8> {
+        let mut packet = vec![134, 0, 0, 0, 64, 0];
+        packet.extend_from_slice(&lifetime.to_be_bytes());
+        packet.extend_from_slice(&[0; 8]);
+        packet.extend_from_slice(options);
+        packet
+    }
+
+    #[test]
+    fn neighbor_solicitation() {
+        let message = parse_icmpv6(&neighbor_message(135, "fe80::1", &link_addr_option(1))).unwrap();
+
+        assert_eq!(message.icmp_type, Icmpv6Type::NeighborSolicitation);
+        assert_eq!(message.target, Some(addr("fe80::1")));
+        assert_eq!(message.source_link_addr, Some(MAC));
+        assert_eq!(message.target_link_addr, None);
+    }
+
+    #[test]
+    fn neighbor_advertisement() {
+        let message = parse_icmpv6(&neighbor_message(136, "2001:db8::1", &link_addr_option(2))).unwrap();
+
+        assert_eq!(message.icmp_type, Icmpv6Type::NeighborAdvertisement);
+        assert_eq!(message.target, Some(addr("2001:db8::1")));
+        assert_eq!(message.target_link_addr, Some(MAC));
+    }
+
+    #[test]
+    fn router_advertisement_with_prefixes() {
+        let mut options = link_addr_option(1);
+        options.extend_from_slice(&prefix_option("2001:db8:1::", 64));
+        // MTU option, ignored
+        options.extend_from_slice(&[5, 1, 0, 0, 0, 0, 0x05, 0xdc]);
+        options.extend_from_slice(&prefix_option("2001:db8:2::", 48));
+        let message = parse_icmpv6(&router_advertisement(1800, &options)).unwrap();
+
+        assert_eq!(message.router_lifetime, Some(1800));
+        assert_eq!(message.source_link_addr, Some(MAC));
+        assert_eq!(message.prefixes, [(addr("2001:db8:1::"), 64), (addr("2001:db8:2::"), 48)]);
+    }
+
+    #[test]
+    fn truncated_options_are_left_out() {
+        let mut options = prefix_option("2001:db8:1::", 64);
+        let truncated = prefix_option("2001:db8:2::", 64);
+        options.extend_from_slice(&truncated[..24]);
+        let message = parse_icmpv6(&router_advertisement(0, &options)).unwrap();
+        assert_eq!(message.prefixes, [(addr("2001:db8:1::"), 64)]);
+
+        // A zero length stops the walk
+        let mut options = vec![1, 0, 0, 0, 0, 0, 0, 0];
+        options.extend_from_slice(&link_addr_option(1));
+        assert_eq!(parse_icmpv6(&neighbor_message(135, "fe80::1", &options)).unwrap().source_link_addr, None);
+
+        // Too short for the fixed part of the message
+        assert!(parse_icmpv6(&neighbor_message(135, "fe80::1", &[])[..20]).is_none());
+        assert!(parse_icmpv6(&[134, 0, 0, 0, 64, 0, 7]).is_none());
+        assert!(parse_icmpv6(&[128, 0, 0]).is_none());
+    }
+
+    #[test]
+    fn types_round_trip() {
+        for raw in [1, 4, 128, 134, 137, 143, 200] {
+            assert_eq!(u8::from(Icmpv6Type::from(raw)), raw);
+        }
+        assert_eq!(Icmpv6Type::from(200).to_string(), "type 200");
+    }
+}
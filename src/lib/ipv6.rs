@@ -0,0 +1,165 @@
+use std::fmt;
+
+use serde::Serialize;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum Ipv6Extension {
+    HopByHop,
+    Routing,
+    Fragment,
+    DestinationOptions,
+    Authentication,
+    Mobility,
+    HostIdentity,
+    Shim6
+}
+
+impl fmt::Display for Ipv6Extension {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Ipv6Extension::HopByHop => write!(f, "HopByHop"),
+            Ipv6Extension::Routing => write!(f, "Routing"),
+            Ipv6Extension::Fragment => write!(f, "Fragment"),
+            Ipv6Extension::DestinationOptions => write!(f, "DestOpts"),
+            Ipv6Extension::Authentication => write!(f, "AH"),
+            Ipv6Extension::Mobility => write!(f, "Mobility"),
+            Ipv6Extension::HostIdentity => write!(f, "HIP"),
+            Ipv6Extension::Shim6 => write!(f, "Shim6")
+        }
+    }
+}
+
+/// Content of the IPv6 fragment header
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct FragmentHeader {
+    pub id: u32,
+    /// Offset of the fragment in bytes
+    pub offset: u16,
+    pub more_fragments: bool
+}
+
+/// Result of walking the extension header chain of an IPv6 packet
+#[derive(Debug)]
+pub struct ExtensionChain {
+    /// Upper-layer protocol number, found after the last extension header
+    pub protocol: u8,
+    /// Offset of the upper-layer header from the start of the IPv6 payload
+    pub offset: usize,
+    pub extensions: Vec<Ipv6Extension>,
+    pub fragment: Option<FragmentHeader>
+}
+
+/// Walk the extension headers starting from the Next Header of the fixed IPv6 header.
+/// The walk stops after a fragment header, since what follows is only meaningful once
+/// the datagram is reassembled. Returns None if the chain is truncated.
+pub fn walk_extension_headers(next_header: u8, payload: &[u8]) -> Option<ExtensionChain> {
+    let mut chain = ExtensionChain {
+        protocol: next_header,
+        offset: 0,
+        extensions: Vec::new(),
+        fragment: None
+    };
+
+    loop {
+        let extension = match chain.protocol {
+            0 => Ipv6Extension::HopByHop,
+            43 => Ipv6Extension::Routing,
+            44 => Ipv6Extension::Fragment,
+            60 => Ipv6Extension::DestinationOptions,
+            51 => Ipv6Extension::Authentication,
+            135 => Ipv6Extension::Mobility,
+            139 => Ipv6Extension::HostIdentity,
+            140 => Ipv6Extension::Shim6,
+            // Upper-layer protocol (or No Next Header / ESP, which can't be walked through)
+            _ => return Some(chain)
+        };
+
+        let header = payload.get(chain.offset..chain.offset + 8)?;
+        let length = match extension {
+            Ipv6Extension::Fragment => {
+                let offset_flags = u16::from_be_bytes([header[2], header[3]]);
+                chain.fragment = Some(FragmentHeader {
+                    id: u32::from_be_bytes([header[4], header[5], header[6], header[7]]),
+                    offset: offset_flags & 0xfff8,
+                    more_fragments: offset_flags & 0x0001 == 1
+                });
+                8
+            },
+            // AH length is in 4-octet units, not counting the first 2
+            Ipv6Extension::Authentication => (header[1] as usize + 2) * 4,
+            // Everything else is in 8-octet units, not counting the first 8
+            _ => (header[1] as usize + 1) * 8
+        };
+
+        chain.extensions.push(extension);
+        chain.protocol = header[0];
+        chain.offset += length;
+
+        if chain.offset > payload.len() {
+            return None;
+        }
+        if extension == Ipv6Extension::Fragment {
+            return Some(chain);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn no_extension_headers() {
+        let chain = walk_extension_headers(6, &[0; 20]).unwrap();
+
+        assert_eq!(chain.protocol, 6);
+        assert_eq!(chain.offset, 0);
+        assert!(chain.extensions.is_empty());
+        assert!(chain.fragment.is_none());
+    }
+
+    #[test]
+    fn hop_by_hop_then_destination_options() {
+        let mut payload = vec![60, 0, 5, 2, 0, 0, 1, 0];
+        // Two 8-octet units
+        payload.extend_from_slice(&[17, 1, 1, 12, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        payload.extend_from_slice(&[0; 8]);
+        let chain = walk_extension_headers(0, &payload).unwrap();
+
+        assert_eq!(chain.protocol, 17);
+        assert_eq!(chain.offset, 24);
+        assert_eq!(chain.extensions, [Ipv6Extension::HopByHop, Ipv6Extension::DestinationOptions]);
+    }
+
+    #[test]
+    fn authentication_header_length_is_in_4_octet_units() {
+        let mut payload = vec![6, 4, 0, 0];
+        payload.extend_from_slice(&[0; 20]);
+        payload.extend_from_slice(&[0; 20]);
+        let chain = walk_extension_headers(51, &payload).unwrap();
+
+        assert_eq!(chain.protocol, 6);
+        assert_eq!(chain.offset, 24);
+        assert_eq!(chain.extensions, [Ipv6Extension::Authentication]);
+    }
+
+    #[test]
+    fn walk_stops_after_the_fragment_header() {
+        // Offset 181 (1448 bytes) with the M flag, then what looks like another extension header
+        let payload = [0, 0, 0x05, 0xa9, 0x12, 0x34, 0x56, 0x78, 0, 0, 0, 0, 0, 0, 0, 0];
+        let chain = walk_extension_headers(44, &payload).unwrap();
+
+        assert_eq!(chain.protocol, 0);
+        assert_eq!(chain.offset, 8);
+        assert_eq!(chain.extensions, [Ipv6Extension::Fragment]);
+        assert_eq!(chain.fragment, Some(FragmentHeader { id: 0x12345678, offset: 1448, more_fragments: true }));
+    }
+
+    #[test]
+    fn truncated_chain() {
+        // Shorter than the fixed 8 bytes
+        assert!(walk_extension_headers(0, &[17, 0, 0, 0]).is_none());
+        // Longer than what is left of the payload
+        assert!(walk_extension_headers(60, &[17, 2, 0, 0, 0, 0, 0, 0, 0, 0]).is_none());
+    }
+}
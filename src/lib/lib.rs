@@ -3,3 +3,21 @@ pub mod parser;
 pub mod menu;
 pub mod args;
 pub mod report;
+pub mod savefile;
+pub mod ipv6;
+pub mod anomaly;
+pub mod fragment;
+pub mod icmp;
+pub mod icmpv6;
+pub mod tcp;
+pub mod connection;
+pub mod stream;
+pub mod dns;
+pub mod http;
+pub mod tls;
+pub mod certificate;
+pub mod classifier;
+pub mod dhcp;
+pub mod arp;
+pub mod oui;
+pub mod tunnel;
@@ -0,0 +1,162 @@
+use std::collections::HashMap;
+use std::fs::read_to_string;
+use std::io;
+
+use pktparse::ethernet::MacAddress;
+
+/// Vendors of the most common network cards, in the same format accepted by `load`
+const BUNDLED: &str = include_str!("oui.txt");
+
+/// Vendor names by Organizationally Unique Identifier (the first three octets of a MAC address)
+pub struct OuiTable {
+    vendors: HashMap<[u8; 3], String>
+}
+
+impl OuiTable {
+    /// The table of the vendors bundled with the program
+    pub fn builtin() -> Self {
+        let mut table = OuiTable { vendors: HashMap::new() };
+        table.parse(BUNDLED);
+        table
+    }
+
+    /// Add the vendors of a file, replacing the known ones with the same prefix. Both the IEEE
+    /// `oui.txt` (`00-03-93   (hex)   Apple, Inc.`) and the Wireshark `manuf` (`00:03:93  Apple  Apple, Inc.`)
+    /// formats are accepted, the lines without a 24-bit prefix are skipped
+    pub fn load(&mut self, path: &str) -> io::Result<()> {
+        let content = read_to_string(path)?;
+        match self.parse(&content) {
+            0 => Err(io::Error::new(io::ErrorKind::InvalidData, "no vendor prefix found")),
+            _ => Ok(())
+        }
+    }
+
+    fn parse(&mut self, content: &str) -> usize {
+        let mut count = 0;
+        for line in content.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let (prefix, name) = match line.split_once(|c: char| c.is_whitespace()) {
+                Some(fields) => fields,
+                None => continue
+            };
+            let digits: String = prefix.chars().filter(|c| !matches!(c, ':' | '-' | '.')).collect();
+            if digits.len() != 6 || !digits.chars().all(|c| c.is_ascii_hexdigit()) {
+                // Longer (MA-M, MA-S) assignments and the address lines of the IEEE file
+                continue;
+            }
+            let value = u32::from_str_radix(&digits, 16).unwrap();
+            let oui = [(value >> 16) as u8, (value >> 8) as u8, value as u8];
+
+            // The long name is the last field of manuf, oui.txt marks the prefix with "(hex)" or "(base 16)"
+            let name = name.trim().trim_start_matches("(hex)").trim_start_matches("(base 16)");
+            let name = name.rsplit('\t').next().unwrap_or(name);
+            // Older manuf files give the long name as a comment
+            let name = name.split_once('#').map(|(_, long)| long).unwrap_or(name).trim();
+            if name.is_empty() {
+                continue;
+            }
+            self.vendors.insert(oui, name.to_string());
+            count += 1;
+        }
+        count
+    }
+
+    /// Vendor of the network card, or what kind of address it is when there is no vendor
+    pub fn vendor(&self, addr: MacAddress) -> &str {
+        let octets = addr.0;
+        if octets == [0xff; 6] {
+            return "Broadcast";
+        }
+        if octets[0] & 0x01 != 0 {
+            return match octets {
+                [0x01, 0x00, 0x5e, ..] => "IPv4 multicast",
+                [0x33, 0x33, ..] => "IPv6 multicast",
+                _ => "Multicast"
+            };
+        }
+        if let Some(name) = self.vendors.get(&[octets[0], octets[1], octets[2]]) {
+            return name;
+        }
+        // Random addresses (e.g. of phones) and containers
+        match octets[0] & 0x02 != 0 {
+            true => "Locally administered",
+            false => "unknown"
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn table(content: &str) -> (OuiTable, usize) {
+        let mut table = OuiTable { vendors: HashMap::new() };
+        let count = table.parse(content);
+        (table, count)
+    }
+
+    #[test]
+    fn ieee_oui_txt() {
+        let (table, count) = table(concat!(
+            "OUI/MA-L                                                    Organization                                 \n",
+            "company_id                                                  Organization                                 \n",
+            "                                                            Address                                      \n",
+            "\n",
+            "00-03-93   (hex)\t\tApple, Inc.\n",
+            "000393     (base 16)\t\tApple, Inc.\n",
+            "\t\t\t\t1 Infinite Loop\n",
+            "\t\t\t\tCupertino  CA  95014\n",
+            "\t\t\t\tUS\n",
+            "\n",
+            "B8-27-EB   (hex)\t\tRaspberry Pi Foundation\n",
+            "B827EB     (base 16)\t\tRaspberry Pi Foundation\n",
+            "\t\t\t\tMitchell Wood House\n"
+        ));
+
+        assert_eq!(count, 4);
+        assert_eq!(table.vendor(MacAddress([0x00, 0x03, 0x93, 0x12, 0x34, 0x56])), "Apple, Inc.");
+        assert_eq!(table.vendor(MacAddress([0xb8, 0x27, 0xeb, 0x00, 0x00, 0x01])), "Raspberry Pi Foundation");
+    }
+
+    #[test]
+    fn wireshark_manuf() {
+        let (table, count) = table(concat!(
+            "# This file was generated by running ./tools/make-manuf.py.\n",
+            "00:00:0C\tCisco\tCisco Systems, Inc\n",
+            "00:03:93\tApple\t# Apple, Inc.\n",
+            "08:00:27\tPcsCompu\n",
+            "70:B3:D5:00:00:00/36\tTelinete\tTelinete\n",
+            "8C:1F:64:00:00:00/36\tSuzhouXi\tSuzhou Xingxiangyi Precision Manufacturing Co.,Ltd.\n"
+        ));
+
+        assert_eq!(count, 3);
+        assert_eq!(table.vendor(MacAddress([0x00, 0x00, 0x0c, 0x07, 0xac, 0x01])), "Cisco Systems, Inc");
+        assert_eq!(table.vendor(MacAddress([0x00, 0x03, 0x93, 0x12, 0x34, 0x56])), "Apple, Inc.");
+        // Without a long name the short one is kept
+        assert_eq!(table.vendor(MacAddress([0x08, 0x00, 0x27, 0xaa, 0xbb, 0xcc])), "PcsCompu");
+        // MA-M and MA-S blocks are not vendors of the whole prefix
+        assert_eq!(table.vendor(MacAddress([0x70, 0xb3, 0xd5, 0x00, 0x00, 0x01])), "unknown");
+        assert_eq!(table.vendor(MacAddress([0x8c, 0x1f, 0x64, 0x00, 0x00, 0x01])), "unknown");
+    }
+
+    #[test]
+    fn addresses_without_vendor() {
+        let (table, _) = table("00:03:93\tApple\tApple, Inc.\n");
+
+        assert_eq!(table.vendor(MacAddress([0xff; 6])), "Broadcast");
+        assert_eq!(table.vendor(MacAddress([0x01, 0x00, 0x5e, 0x00, 0x00, 0xfb])), "IPv4 multicast");
+        assert_eq!(table.vendor(MacAddress([0x33, 0x33, 0x00, 0x00, 0x00, 0x01])), "IPv6 multicast");
+        assert_eq!(table.vendor(MacAddress([0x01, 0x80, 0xc2, 0x00, 0x00, 0x0e])), "Multicast");
+        assert_eq!(table.vendor(MacAddress([0xda, 0xa1, 0x19, 0x12, 0x34, 0x56])), "Locally administered");
+        assert_eq!(table.vendor(MacAddress([0x00, 0x11, 0x22, 0x33, 0x44, 0x55])), "unknown");
+    }
+
+    #[test]
+    fn builtin_table() {
+        assert!(!OuiTable::builtin().vendors.is_empty());
+    }
+}
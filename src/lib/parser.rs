@@ -1,34 +1,375 @@
+use std::collections::HashMap;
 use std::fmt;
-use std::net::IpAddr;
-use pcap::Device;
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+use chrono::{DateTime, Duration, Local, TimeZone};
+use pcap::{Linktype, PacketHeader, Precision};
 
 use pktparse::arp::parse_arp_pkt;
-use pktparse::ethernet::{EtherType, parse_ethernet_frame};
-use pktparse::icmp::{IcmpCode, parse_icmp_header};
+use pktparse::ethernet::{EtherType, MacAddress, parse_ethernet_frame};
 use pktparse::ip::IPProtocol;
 use pktparse::ipv4::parse_ipv4_header;
 use pktparse::ipv6::parse_ipv6_header;
 use pktparse::tcp::parse_tcp_header;
 use pktparse::udp::parse_udp_header;
 
-use crate::utils::{tcp_l7, udp_l7};
+use crate::anomaly::{Anomaly, AnomalyKind};
+use crate::arp::{ArpMessage, ArpTable};
+use crate::classifier::{Classifier, ServiceTable};
+use crate::connection::{ConnectionKey, ConnectionTable, TcpState};
+use crate::dhcp::{is_dhcp_port, parse_dhcp, DhcpLeaseTable, DhcpMessage, DhcpMessageType};
+use crate::dns::{is_dns_port, parse_dns, DnsCache, DnsMessage, DnsTable, DnsTransaction};
+use crate::fragment::{FragmentKey, FragmentTable};
+use crate::http::{HttpMessage, HttpSummary, HttpTable};
+use crate::icmp::{parse_icmp, serialize_quoted, QuotedHeader};
+use crate::icmpv6::{parse_icmpv6, Icmpv6Message, Icmpv6Type};
+use crate::oui::OuiTable;
+use crate::ipv6::{walk_extension_headers, ExtensionChain, Ipv6Extension};
+use crate::stream::{StreamDumper, StreamTable};
+use crate::tcp::{parse_tcp_options, TcpFlags, TcpOptions};
+use crate::tls::{ClientHello, TlsHandshake, TlsTable};
+use crate::tunnel::{decapsulate, serialize_tunnels, udp_tunnel, InnerProtocol, Tunnel, TunnelKind, IPPROTO_GRE};
+use crate::utils::{join_values, mac_to_str, serialize_joined, serialize_mac};
 
 use serde::Serialize;
 
+/// Frame received from a capture, together with the metadata pcap attached to it
+#[derive(Debug, Clone)]
+pub struct Frame {
+    pub interface: String,
+    pub linktype: Linktype,
+    pub timestamp: DateTime<Local>,
+    pub caplen: u32,
+    pub wire_len: u32,
+    pub data: Vec<u8>,
+    /// Report (and savefile) of the interval the frame was captured in
+    pub report_index: i32
+}
+
+impl Frame {
+    pub fn new(interface: &str, linktype: Linktype, header: &PacketHeader, precision: Precision, data: &[u8]) -> Self {
+        // With nanosecond precision pcap stores nanoseconds in the tv_usec field
+        let nanos = match precision {
+            Precision::Micro => (header.ts.tv_usec as u32).saturating_mul(1_000),
+            Precision::Nano => header.ts.tv_usec as u32
+        };
+        // Corrupt or crafted savefiles carry out of range times, they get the time they are read at
+        let timestamp = Local.timestamp_opt(header.ts.tv_sec, nanos).earliest().unwrap_or_else(Local::now);
+
+        Frame {
+            interface: interface.to_string(),
+            linktype,
+            timestamp,
+            caplen: header.caplen,
+            wire_len: header.len,
+            data: data.to_vec(),
+            report_index: 0
+        }
+    }
+}
+
+#[cfg(test)]
+impl Frame {
+    /// Frame captured now on eth0, whole on the wire: tests change the fields they care about
+    pub(crate) fn fixture(linktype: Linktype, data: Vec<u8>) -> Self {
+        Frame {
+            interface: "eth0".to_string(),
+            linktype,
+            timestamp: Local::now(),
+            caplen: data.len() as u32,
+            wire_len: data.len() as u32,
+            data,
+            report_index: 0
+        }
+    }
+}
+
+/// State kept by the parsing thread across frames
+pub struct ParserState {
+    pub fragments: FragmentTable,
+    pub connections: ConnectionTable,
+    pub streams: StreamTable,
+    pub dns: DnsTable,
+    pub dns_cache: DnsCache,
+    pub http: HttpTable,
+    pub tls: TlsTable,
+    pub classifier: Classifier,
+    pub dhcp_leases: DhcpLeaseTable,
+    pub arp: ArpTable,
+    /// Vendors of the MAC addresses
+    pub oui: OuiTable,
+    /// Where the reassembled TCP streams are written, if requested
+    pub dump: Option<StreamDumper>,
+    pub anomalies: Vec<Anomaly>,
+    pub last_seen: Option<DateTime<Local>>,
+    /// Routers allowed to send Router Advertisements, if empty the first one seen on each interface
+    pub routers: Vec<IpAddr>,
+    known_routers: HashMap<String, IpAddr>,
+    /// DHCP servers allowed to answer the clients, if empty the first one seen on each interface
+    pub dhcp_servers: Vec<IpAddr>,
+    known_dhcp_servers: HashMap<String, IpAddr>,
+    /// Encapsulations (VLAN tags, MPLS stacks, PPPoE sessions, tunnels) entered by the frame being parsed
+    depth: usize
+}
+
+impl ParserState {
+    pub fn new() -> Self {
+        ParserState {
+            // Same defaults as the Linux IP stack: 30 seconds to receive every fragment, 4 MB of fragments
+            fragments: FragmentTable::new(Duration::seconds(30), 1024, 4 * 1024 * 1024),
+            // Idle connections are forgotten (and reported as timed out) after 2 minutes, at most
+            // 65536 connections are tracked between two reports
+            connections: ConnectionTable::new(Duration::seconds(120), 65536),
+            // 1 MB waiting in each direction at most, 64 MB for all the streams
+            streams: StreamTable::new(Duration::seconds(120), 4096, 64 * 1024 * 1024),
+            // Resolvers give up well before 10 seconds
+            dns: DnsTable::new(Duration::seconds(10), 4096),
+            // Names stay for an hour after the last answer, unless the TTL is longer
+            dns_cache: DnsCache::new(Duration::hours(1), 65536),
+            // Same as the streams it is fed with
+            http: HttpTable::new(Duration::seconds(120), 4096),
+            // At most 4096 handshakes read at the same time and 65536 servers in the TLS inventory
+            tls: TlsTable::new(Duration::seconds(120), 4096, 65536),
+            classifier: Classifier::new(ServiceTable::builtin(), Duration::seconds(120), 65536),
+            dhcp_leases: DhcpLeaseTable::new(65536),
+            // A MAC claiming back its address within a minute is a conflict, more than 50 unsolicited
+            // replies in 10 seconds a flood
+            arp: ArpTable::new(65536, Duration::seconds(60), 50, Duration::seconds(10)),
+            oui: OuiTable::builtin(),
+            dump: None,
+            anomalies: Vec::new(),
+            last_seen: None,
+            routers: Vec::new(),
+            known_routers: HashMap::new(),
+            dhcp_servers: Vec::new(),
+            known_dhcp_servers: HashMap::new(),
+            depth: 0
+        }
+    }
+
+    /// Enter one more encapsulation of the current frame, failing past `MAX_ENCAPSULATION_DEPTH` so
+    /// that crafted frames stacking thousands of headers can not exhaust the stack
+    fn nest(&mut self) -> Result<(), Error> {
+        if self.depth >= MAX_ENCAPSULATION_DEPTH {
+            return Err(Error::EncapsulationTooDeep);
+        }
+        self.depth += 1;
+        Ok(())
+    }
+
+    /// Return the anomalies noticed since the last call, expiring what timed out in the meanwhile
+    pub fn take_anomalies(&mut self) -> Vec<Anomaly> {
+        if let Some(now) = self.last_seen {
+            self.fragments.expire(now, &mut self.anomalies);
+            // Connections still open are reported as timed out, even those without packets in the report
+            for (key, connection) in self.connections.expire(now) {
+                let segment = match key.vlan.is_empty() {
+                    true => key.interface.clone(),
+                    false => format!("{} (VLAN {})", key.interface, join_values(&key.vlan, "."))
+                };
+                self.anomalies.push(Anomaly::new(now, AnomalyKind::ConnectionTimedOut, format!(
+                    "{} -> {} on {}, idle since {}",
+                    SocketAddr::from(connection.initiator),
+                    SocketAddr::from(key.peer(connection.initiator)),
+                    segment,
+                    connection.last_seen
+                )));
+            }
+            self.streams.expire(now);
+            if let Some(dump) = &mut self.dump {
+                dump.expire(now);
+            }
+            self.http.expire(now);
+            self.tls.expire(now);
+            self.classifier.expire(now);
+            self.arp.expire(now);
+        }
+        std::mem::take(&mut self.anomalies)
+    }
+
+    /// Return the DNS transactions completed since the last call, including the queries left without a response
+    pub fn take_dns_transactions(&mut self) -> Vec<DnsTransaction> {
+        if let Some(now) = self.last_seen {
+            self.dns.expire(now);
+        }
+        self.dns.take_transactions()
+    }
+
+    /// Return the top HTTP hosts, URIs and status codes seen since the last call
+    pub fn take_http_summary(&mut self) -> Vec<HttpSummary> {
+        self.http.take_summary()
+    }
+
+    /// Reassemble the payload of a TCP segment, passing what became contiguous to the analyzers
+    fn track_stream(&mut self, packet: &mut Packet, from_initiator: bool, payload: &[u8]) {
+        let (src_port, dest_port) = match (packet.src_port, packet.dest_port) {
+            (Some(src_port), Some(dest_port)) => (src_port, dest_port),
+            _ => return
+        };
+        let connection = ConnectionKey::new(&packet.interface, &packet.vlan, (packet.src_addr, src_port), (packet.dest_addr, dest_port));
+
+        if let Some(stream) = self.streams.process(packet, &connection, from_initiator, payload) {
+            if let Some(dump) = &mut self.dump {
+                dump.write(&stream);
+            }
+            packet.http = self.http.process(&stream);
+            for handshake in self.tls.process(&stream) {
+                match handshake {
+                    TlsHandshake::ClientHello(hello) => {
+                        // The name asked by the client is better than the one guessed from DNS
+                        if let Some(server_name) = &hello.server_name {
+                            packet.res_name = server_name.clone();
+                        }
+                        packet.tls_client_hello = Some(hello);
+                    },
+                    // Kept in the TLS inventory
+                    TlsHandshake::ServerHello(_) | TlsHandshake::Certificate(_) => ()
+                }
+            }
+        }
+        if packet.tcp_state.map(|state| state.is_finished()).unwrap_or(false) {
+            self.streams.close(&connection);
+            if let Some(dump) = &mut self.dump {
+                dump.close(&connection);
+            }
+            self.http.close(&connection);
+            self.tls.close(&connection);
+        }
+    }
+
+    /// Report a Router Advertisement coming from an unexpected source
+    fn check_router_advertisement(&mut self, frame: &Frame, source: IpAddr, message: &Icmpv6Message) {
+        let expected = match self.routers.is_empty() {
+            true => *self.known_routers.entry(frame.interface.clone()).or_insert(source) == source,
+            false => self.routers.contains(&source)
+        };
+        // RFC 4861: a router advertises itself from its link-local address
+        let link_local = match source {
+            IpAddr::V6(addr) => addr.segments()[0] & 0xffc0 == 0xfe80,
+            IpAddr::V4(_) => false
+        };
+
+        if !expected || !link_local {
+            let router_mac = message.source_link_addr.map(mac_to_str).unwrap_or("unknown".to_string());
+            // The prefixes tell which addresses the hosts configure after this router
+            let prefixes = match message.prefixes.is_empty() {
+                true => "none".to_string(),
+                false => message.prefixes.iter().map(|(prefix, length)| format!("{}/{}", prefix, length)).collect::<Vec<String>>().join(", ")
+            };
+            self.anomalies.push(Anomaly::new(
+                frame.timestamp,
+                AnomalyKind::RogueRouterAdvertisement,
+                format!("{} on {} (MAC {}, lifetime {}s, prefixes {})", source, frame.interface, router_mac, message.router_lifetime.unwrap_or(0), prefixes)
+            ));
+        }
+    }
+
+    /// Report an offer (or an acknowledgement) coming from an unexpected DHCP server
+    fn check_dhcp_server(&mut self, frame: &Frame, source: IpAddr, message: &DhcpMessage) {
+        if !matches!(message.message_type, Some(DhcpMessageType::Offer) | Some(DhcpMessageType::Ack)) {
+            return;
+        }
+        // Relays forward the answers from their own address, the server identifier is the server
+        let server = message.server_id.map(IpAddr::V4).unwrap_or(source);
+        let expected = match self.dhcp_servers.is_empty() {
+            true => *self.known_dhcp_servers.entry(frame.interface.clone()).or_insert(server) == server,
+            false => self.dhcp_servers.contains(&server)
+        };
+
+        if !expected {
+            self.anomalies.push(Anomaly::new(
+                frame.timestamp,
+                AnomalyKind::RogueDhcpServer,
+                format!("{} from {} on {} (address {} for {})", message.message_type.unwrap(), server, frame.interface, message.your_addr, mac_to_str(message.client_mac))
+            ));
+        }
+    }
+}
+
+impl Default for ParserState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 #[derive(Debug, Serialize)]
 pub struct Packet {
     pub interface: String,
+    #[serde(serialize_with = "serialize_joined")]
+    pub vlan: Vec<u16>,
+    #[serde(serialize_with = "serialize_tunnels")]
+    pub tunnels: Vec<Tunnel>,
+    #[serde(serialize_with = "serialize_joined")]
+    pub mpls_labels: Vec<u32>,
+    pub pppoe_session: Option<u16>,
+    #[serde(serialize_with = "serialize_mac")]
+    pub src_mac: Option<MacAddress>,
+    pub src_vendor: String,
+    #[serde(serialize_with = "serialize_mac")]
+    pub dest_mac: Option<MacAddress>,
+    pub dest_vendor: String,
     pub src_addr: IpAddr,
     pub dest_addr: IpAddr,
     pub res_name: String,
     pub src_port: Option<u16>,
     pub dest_port: Option<u16>,
-    #[serde(skip_serializing)]
-    pub length: u16,
     pub transport: String,
     pub application: String,
+    #[serde(serialize_with = "serialize_quoted")]
+    pub quoted: Option<QuotedHeader>,
+    #[serde(skip_serializing)]
+    pub timestamp: DateTime<Local>,
+    #[serde(skip_serializing)]
+    pub report_index: i32,
+    #[serde(skip_serializing)]
+    pub caplen: u32,
+    #[serde(skip_serializing)]
+    pub wire_len: u32,
+    #[serde(skip_serializing)]
+    pub ip_bytes: u32,
+    #[serde(skip_serializing)]
+    pub payload_bytes: u32,
+    #[serde(skip_serializing)]
+    pub ipv6_extensions: Vec<Ipv6Extension>,
+    #[serde(skip_serializing)]
+    pub fragment_id: Option<u32>,
+    #[serde(skip_serializing)]
+    pub tcp_flags: Option<TcpFlags>,
+    #[serde(skip_serializing)]
+    pub tcp_seq: Option<u32>,
+    #[serde(skip_serializing)]
+    pub tcp_ack: Option<u32>,
     #[serde(skip_serializing)]
-    pub timestamp: String
+    pub tcp_window: Option<u16>,
+    #[serde(skip_serializing)]
+    pub tcp_options: Option<TcpOptions>,
+    #[serde(skip_serializing)]
+    pub payload: Vec<u8>,
+    #[serde(skip_serializing)]
+    pub tcp_state: Option<TcpState>,
+    #[serde(skip_serializing)]
+    pub connection_start: Option<DateTime<Local>>,
+    #[serde(skip_serializing)]
+    pub dns: Option<DnsMessage>,
+    #[serde(skip_serializing)]
+    pub dhcp: Option<DhcpMessage>,
+    #[serde(skip_serializing)]
+    pub arp: Option<ArpMessage>,
+    #[serde(skip_serializing)]
+    pub http: Vec<HttpMessage>,
+    #[serde(skip_serializing)]
+    pub tls_client_hello: Option<ClientHello>,
+    #[serde(skip_serializing)]
+    pub icmp_type: Option<u8>,
+    #[serde(skip_serializing)]
+    pub icmp_code: Option<u8>,
+    #[serde(skip_serializing)]
+    pub icmp_id: Option<u16>,
+    #[serde(skip_serializing)]
+    pub icmp_seq: Option<u16>,
+    #[serde(skip_serializing)]
+    pub ndp_target: Option<Ipv6Addr>,
+    #[serde(skip_serializing)]
+    pub link_layer_addr: Option<MacAddress>
 }
 
 #[derive(Debug)]
@@ -39,9 +380,19 @@ pub enum Error {
     IPv6ParsingError,
     IPv4ParsingError,
     ICMPParsingError,
+    ICMPv6ParsingError,
     TCPParsingError,
     UDPParsingError,
-    EthernetParsingError
+    EthernetParsingError,
+    SLLParsingError,
+    LoopbackParsingError,
+    VLANParsingError,
+    UnknownLinktype,
+    TunnelParsingError,
+    MPLSParsingError,
+    PPPoEParsingError,
+    EncapsulationTooDeep,
+    FragmentBuffered
 }
 
 impl std::error::Error for Error {}
@@ -55,48 +406,115 @@ impl fmt::Display for Error {
             Error::IPv6ParsingError => write!(f, "Error while parsing IPv6 Packet!"),
             Error::IPv4ParsingError => write!(f, "Error while parsing IPv4 Packet!"),
             Error::ICMPParsingError => write!(f, "Error while parsing ICMP Packet!"),
+            Error::ICMPv6ParsingError => write!(f, "Error while parsing ICMPv6 Packet!"),
             Error::TCPParsingError => write!(f, "Error while parsing TCP Packet!"),
             Error::UDPParsingError => write!(f, "Error while parsing UDP Packet!"),
-            Error::EthernetParsingError => write!(f, "Error while parsing Ethernet Packet!")
+            Error::EthernetParsingError => write!(f, "Error while parsing Ethernet Packet!"),
+            Error::SLLParsingError => write!(f, "Error while parsing Linux cooked Packet!"),
+            Error::LoopbackParsingError => write!(f, "Error while parsing Loopback Packet!"),
+            Error::VLANParsingError => write!(f, "Error while parsing VLAN tag!"),
+            Error::UnknownLinktype => write!(f, "Unknown link-layer header type!"),
+            Error::TunnelParsingError => write!(f, "Error while parsing tunnel!"),
+            Error::MPLSParsingError => write!(f, "Error while parsing MPLS label stack!"),
+            Error::PPPoEParsingError => write!(f, "Error while parsing PPPoE Packet!"),
+            Error::EncapsulationTooDeep => write!(f, "Too many nested encapsulations!"),
+            Error::FragmentBuffered => write!(f, "Fragment buffered for reassembly")
         }
     }
 }
 
+/// Addresses of a packet, with the ports for TCP and UDP
+pub struct Endpoints {
+    pub src_addr: IpAddr,
+    pub dest_addr: IpAddr,
+    pub src_port: Option<u16>,
+    pub dest_port: Option<u16>
+}
+
+impl Endpoints {
+    pub fn new(src_addr: IpAddr, dest_addr: IpAddr) -> Self {
+        Endpoints { src_addr, dest_addr, src_port: None, dest_port: None }
+    }
+
+    pub fn with_ports(src_addr: IpAddr, src_port: u16, dest_addr: IpAddr, dest_port: u16) -> Self {
+        Endpoints { src_addr, dest_addr, src_port: Some(src_port), dest_port: Some(dest_port) }
+    }
+}
+
 impl Packet {
-    pub fn new(
-        interface: String,
-        src_addr: IpAddr,
-        dest_addr: IpAddr,
-        res_name: String,
-        src_port: Option<u16>,
-        dest_port: Option<u16>,
-        length: u16,
-        transport: String,
-        application: String,
-        timestamp: String,
-    ) -> Self {
+    pub fn new(frame: &Frame, endpoints: Endpoints, payload_bytes: u32, transport: String) -> Self {
+        let Endpoints { src_addr, dest_addr, src_port, dest_port } = endpoints;
         Packet {
-            interface,
+            interface: frame.interface.clone(),
+            // Filled by the link layer handlers, outermost tag first
+            vlan: Vec::new(),
+            // Filled by the tunnel handler, outermost tunnel first
+            tunnels: Vec::new(),
+            // Filled by the MPLS and PPPoE handlers, outermost label first
+            mpls_labels: Vec::new(),
+            pppoe_session: None,
+            src_mac: None,
+            src_vendor: "-".to_string(),
+            dest_mac: None,
+            dest_vendor: "-".to_string(),
             src_addr,
             src_port,
             dest_addr,
-            res_name,
+            // Filled by the handlers that find a name (DNS, Neighbor Discovery, ...) and by handle_frame
+            res_name: "none".to_string(),
             dest_port,
-            length,
             transport,
-            application,
-            timestamp
+            // Classified once the whole packet is parsed, see handle_frame
+            application: "unknown".to_string(),
+            // Filled by the ICMP handler for errors
+            quoted: None,
+            timestamp: frame.timestamp,
+            report_index: frame.report_index,
+            caplen: frame.caplen,
+            wire_len: frame.wire_len,
+            // Filled by the network layer handler, if any
+            ip_bytes: 0,
+            payload_bytes,
+            ipv6_extensions: Vec::new(),
+            fragment_id: None,
+            // Filled by the TCP handler
+            tcp_flags: None,
+            tcp_seq: None,
+            tcp_ack: None,
+            tcp_window: None,
+            tcp_options: None,
+            // Filled by the TCP and UDP handlers, emptied by handle_frame
+            payload: Vec::new(),
+            // Filled once the whole packet is parsed, see handle_frame
+            tcp_state: None,
+            connection_start: None,
+            // Filled by the UDP handler
+            dns: None,
+            dhcp: None,
+            arp: None,
+            // Filled by the HTTP analyzer, with the heads completed by this segment
+            http: Vec::new(),
+            // Filled by the TLS analyzer
+            tls_client_hello: None,
+            // Filled by the ICMP handler
+            icmp_type: None,
+            icmp_code: None,
+            icmp_id: None,
+            icmp_seq: None,
+            // Filled by the ICMPv6 handler for Neighbor Discovery messages
+            ndp_target: None,
+            link_layer_addr: None
         }
     }
 }
 impl fmt::Display for Packet {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        //        "Interface\t| Source IP address\t| Source Port\t| Dest IP address \t| Dest Port\t| Timestamp\t|  Bytes\t| Transport \t| Application \n"
-        write!(f, "| {0: <2}\t| {1: <30}\t| {2: <25}\t| {3: <25} ({4}) \t| {5: <5}\t| {6: <3}\t| {7: <3} \t| {8: <7}\t| {9}", self.interface, self.src_addr, self.src_port.unwrap_or(0), self.dest_addr, self.res_name, self.dest_port.unwrap_or(0), self.length, self.transport, self.application, chrono::offset::Local::now())
+        //        "Interface\t| VLAN\t| Source IP address\t| Source Port\t| Dest IP address \t| Dest Port\t| Timestamp\t|  Bytes\t| Transport \t| Application \n"
+        write!(f, "| {0: <2}\t| {1: <4}\t| {2: <30}\t| {3: <25}\t| {4: <25} ({5}) \t| {6: <5}\t| {7: <3}\t| {8: <3} \t| {9: <7}\t| {10}", self.interface, join_values(&self.vlan, "."), self.src_addr, self.src_port.unwrap_or(0), self.dest_addr, self.res_name, self.dest_port.unwrap_or(0), self.wire_len, self.transport, self.application, self.timestamp)
     }
 }
 
-fn handle_udp_packet(interface_name: &str, source: IpAddr, destination: IpAddr, packet: &[u8]) -> Result<Packet, Error> {
+fn handle_udp_packet(frame: &Frame, state: &mut ParserState, source: IpAddr, destination: IpAddr, packet: &[u8]) -> Result<Packet, Error> {
     let parsed_udp = parse_udp_header(packet);
 
     match parsed_udp {
@@ -105,151 +523,194 @@ fn handle_udp_packet(interface_name: &str, source: IpAddr, destination: IpAddr,
             let header = tuple.1;
 
             // DONE: use dns_parser and extract the useful info about the packet (hostname, resolved ip, ...)
-            match dns_parser::Packet::parse(payload) {
-                Ok(dns_packet) => {
-                    let app_layer = udp_l7(header.dest_port);
-                    Ok(Packet::new(
-                        interface_name.to_string(),
-                        source,
-                        destination,
-                        dns_packet.questions.iter().map(|q| { q.qname.to_string() }).collect::<Vec<String>>().join(", "),
-                        Some(header.source_port),
-                        Some(header.dest_port),
-                        header.length,
-                        "UDP".to_string(),
-                        app_layer,
-                        chrono::offset::Local::now().to_string()
-                    ))
-                }
-                Err(_) => {
-                    Ok(Packet::new(
-                        interface_name.to_string(),
-                        source,
-                        destination,
-                        "none".to_string(),
-                        Some(header.source_port),
-                        Some(header.dest_port),
-                        header.length,
-                        "UDP".to_string(),
-                        "unknown".to_string(),
-                        chrono::offset::Local::now().to_string()
-                    ))
-                }
-            }
+            // Off the DNS ports a payload has to look like DNS to be decoded as such
+            let dns_port = is_dns_port(header.source_port) || is_dns_port(header.dest_port);
+            let dns = parse_dns(payload, dns_port);
 
+            let res_name = match &dns {
+                Some(message) => message.names().join(", "),
+                None => "none".to_string()
+            };
 
+            let dhcp = match is_dhcp_port(header.source_port) || is_dhcp_port(header.dest_port) {
+                true => parse_dhcp(payload),
+                false => None
+            };
+            if let Some(message) = &dhcp {
+                state.dhcp_leases.update(message, frame.timestamp);
+                state.check_dhcp_server(frame, source, message);
+            }
 
+            if let Some(message) = &dns {
+                state.dns.update(
+                    message,
+                    SocketAddr::new(source, header.source_port),
+                    SocketAddr::new(destination, header.dest_port),
+                    frame.timestamp
+                );
+                state.dns_cache.insert(message, frame.timestamp);
+            }
 
+            let mut parsed = Packet::new(
+                frame,
+                Endpoints::with_ports(source, header.source_port, destination, header.dest_port),
+                header.length.saturating_sub(8) as u32,
+                "UDP".to_string()
+            );
+            parsed.res_name = res_name;
+            parsed.dns = dns;
+            parsed.dhcp = dhcp;
+            parsed.payload = payload.to_vec();
+            Ok(parsed)
         },
         Err(_) => Err(Error::UDPParsingError)
     }
 }
 
-fn handle_icmp_packet(interface_name: &str, source: IpAddr, destination: IpAddr, packet: &[u8]) -> Result<Packet, Error> {
-    let parsed_icmp = parse_icmp_header(packet);
+fn handle_icmp_packet(frame: &Frame, source: IpAddr, destination: IpAddr, packet: &[u8]) -> Result<Packet, Error> {
+    let message = match parse_icmp(packet) {
+        Some(message) => message,
+        None => return Err(Error::ICMPParsingError)
+    };
 
-    match parsed_icmp {
-        Ok(tuple) => {
-            let header = tuple.1;
-            match header.code {
-                IcmpCode::EchoReply => {
-                    // DONE: parse echo reply packet for seq and id
-                    Ok(Packet::new(
-                        interface_name.to_string(),
-                        source,
-                        destination,
-                        "none".to_string(),
-                        None,
-                        None,
-                        64,
-                        "ICMP echo reply".to_string(),
-                        "unknown".to_string(),
-                        chrono::offset::Local::now().to_string()
-                    ))
-                },
-                IcmpCode::EchoRequest => {
-                    // DONE: parse echo request packet for seq and id
-                    Ok(Packet::new(
-                        interface_name.to_string(),
-                        source,
-                        destination,
-                        "none".to_string(),
-                        None,
-                        None,
-                        64,
-                        "ICMP echo request".to_string(),
-                        "unknown".to_string(),
-                        chrono::offset::Local::now().to_string()
-                    ))
+    let mut parsed = Packet::new(
+        frame,
+        Endpoints::new(source, destination),
+        // Everything after the 8 bytes of type, code, checksum and rest of header
+        packet.len().saturating_sub(8) as u32,
+        message.describe()
+    );
+    parsed.icmp_type = Some(message.icmp_type);
+    parsed.icmp_code = Some(message.code);
+    parsed.icmp_id = message.identifier;
+    parsed.icmp_seq = message.sequence;
+    // Errors are tied back to the flow that caused them through the quoted header
+    parsed.quoted = message.quoted;
+    Ok(parsed)
+}
 
-                },
-                _ => {
-                    // DONE: parse echo reply packet for seq and id
-                    Ok(Packet::new(
-                        interface_name.to_string(),
-                        source,
-                        destination,
-                        "none".to_string(),
-                        None,
-                        None,
-                        64,
-                        "ICMP packet".to_string(),
-                        "unknown".to_string(),
-                        chrono::offset::Local::now().to_string()
-                    ))
+fn handle_icmpv6_packet(frame: &Frame, state: &mut ParserState, source: IpAddr, destination: IpAddr, packet: &[u8]) -> Result<Packet, Error> {
+    let message = match parse_icmpv6(packet) {
+        Some(message) => message,
+        None => return Err(Error::ICMPv6ParsingError)
+    };
 
-                }
-            }
-        },
-        Err(_) => Err(Error::ICMPParsingError)
+    if message.icmp_type == Icmpv6Type::RouterAdvertisement {
+        state.check_router_advertisement(frame, source, &message);
     }
+
+    // The address being resolved (or the better first hop of a Redirect) is shown as the name
+    let res_name = message.target.map(|target| target.to_string()).unwrap_or("none".to_string());
+    let mut parsed = Packet::new(
+        frame,
+        Endpoints::new(source, destination),
+        packet.len().saturating_sub(8) as u32,
+        format!("ICMPv6 {}", message.icmp_type)
+    );
+    parsed.res_name = res_name;
+    parsed.icmp_type = Some(u8::from(message.icmp_type));
+    parsed.icmp_code = Some(message.code);
+    parsed.ndp_target = message.target;
+    parsed.link_layer_addr = message.target_link_addr.or(message.source_link_addr);
+    Ok(parsed)
 }
 
-fn handle_tcp_packet(interface_name: &str, source: IpAddr, destination: IpAddr, packet: &[u8]) -> Result<Packet, Error> {
+fn handle_tcp_packet(frame: &Frame, source: IpAddr, destination: IpAddr, packet: &[u8]) -> Result<Packet, Error> {
     let parsed_tcp = parse_tcp_header(packet);
 
     match parsed_tcp {
         Ok(tuple) => {
-            let _payload = tuple.0;
+            let payload = tuple.0;
             let header = tuple.1;
-            // DONE: L7 recognised from TCP Header
-            let app_layer = tcp_l7(header.dest_port);
+            // pktparse gives up on timestamps and SACK, the options are parsed here
+            let header_len = (header.data_offset as usize * 4).clamp(20, packet.len().max(20));
+            let options = parse_tcp_options(packet.get(20..header_len).unwrap_or(&[]));
+
+            let mut parsed = Packet::new(
+                frame,
+                Endpoints::with_ports(source, header.source_port, destination, header.dest_port),
+                payload.len() as u32,
+                "TCP".to_string()
+            );
+            parsed.tcp_flags = Some(TcpFlags::from_header(&header));
+            parsed.tcp_seq = Some(header.sequence_no);
+            parsed.tcp_ack = Some(header.ack_no);
+            parsed.tcp_window = Some(header.window);
+            parsed.tcp_options = Some(options);
+            parsed.payload = payload.to_vec();
+            Ok(parsed)
 
-            Ok(Packet::new(
-                interface_name.to_string(),
-                source,
-                destination,
-                "none".to_string(),
-                Some(header.source_port),
-                Some(header.dest_port),
-                packet.len() as u16,
-                "TCP".to_string(),
-                app_layer,
-                chrono::offset::Local::now().to_string()
-            ))
-            
         },
         Err(_) => Err(Error::TCPParsingError)
     }
 }
 
+/// Parse the packet carried by a tunnel, recording the tunnel on it. Flows are tracked on the inner headers
+fn handle_tunnel(
+    frame: &Frame,
+    state: &mut ParserState,
+    source: IpAddr,
+    destination: IpAddr,
+    kind: TunnelKind,
+    packet: &[u8],
+) -> Result<Packet, Error> {
+    let decapsulated = match decapsulate(kind, packet) {
+        Some(decapsulated) => decapsulated,
+        None => return Err(Error::TunnelParsingError)
+    };
+    state.nest()?;
+
+    // Tunnels inside the tunnel are decapsulated by the recursion
+    let parsed = match decapsulated.inner {
+        InnerProtocol::Ethernet => handle_ethernet_frame(frame, state, decapsulated.payload),
+        InnerProtocol::IPv4 => handle_ipv4_packet(frame, state, decapsulated.payload),
+        InnerProtocol::IPv6 => handle_ipv6_packet(frame, state, decapsulated.payload)
+    };
+    parsed.map(|mut packet| {
+        packet.tunnels.insert(0, Tunnel { kind, outer_src: source, outer_dest: destination, id: decapsulated.id });
+        packet
+    })
+}
+
 fn handle_transport_protocol(
-    interface_name: &str,
+    frame: &Frame,
+    state: &mut ParserState,
     source: IpAddr,
     destination: IpAddr,
     protocol: IPProtocol,
     packet: &[u8],
 ) -> Result<Packet, Error> {
 
+    let tunnel = match protocol {
+        IPProtocol::UDP => udp_tunnel(packet).map(|kind| (kind, &packet[8..])),
+        IPProtocol::IPINIP => Some((TunnelKind::IpInIp, packet)),
+        IPProtocol::IPV6 if source.is_ipv4() => Some((TunnelKind::SixInFour, packet)),
+        IPProtocol::IPV6 => Some((TunnelKind::IpInIp, packet)),
+        IPProtocol::Other(IPPROTO_GRE) => Some((TunnelKind::Gre, packet)),
+        _ => None
+    };
+    if let Some((kind, inner)) = tunnel {
+        match handle_tunnel(frame, state, source, destination, kind, inner) {
+            Ok(packet) => return Ok(packet),
+            Err(Error::FragmentBuffered) => return Err(Error::FragmentBuffered),
+            // What can't be decapsulated, or nests too many encapsulations, is reported as the tunnel packet itself
+            Err(_) if kind.over_udp() => (),
+            Err(_) => return Ok(Packet::new(frame, Endpoints::new(source, destination), packet.len() as u32, kind.to_string()))
+        }
+    }
+
     match protocol {
         IPProtocol::UDP => {
-            handle_udp_packet(interface_name, source, destination, packet)
+            handle_udp_packet(frame, state, source, destination, packet)
         }
         IPProtocol::TCP => {
-            handle_tcp_packet(interface_name, source, destination, packet)
+            handle_tcp_packet(frame, source, destination, packet)
         }
         IPProtocol::ICMP => {
-            handle_icmp_packet(interface_name, source, destination, packet)
+            handle_icmp_packet(frame, source, destination, packet)
+        }
+        IPProtocol::ICMP6 => {
+            handle_icmpv6_packet(frame, state, source, destination, packet)
         }
         _ => Err(
             Error::UnknownPacket
@@ -257,86 +718,616 @@ fn handle_transport_protocol(
     }
 }
 
-fn handle_ipv4_packet(interface_name: &str, packet: &[u8]) -> Result<Packet, Error> {
+fn handle_ipv4_packet(frame: &Frame, state: &mut ParserState, packet: &[u8]) -> Result<Packet, Error> {
     let parsed_ipv4 = parse_ipv4_header(packet);
 
     match parsed_ipv4 {
         Ok(tuple) => {
-            let payload = tuple.0;
             let header = tuple.1;
+            // pktparse stops after the fixed header: skip the options (IHL) and
+            // drop the link layer padding (total length)
+            let header_len = (header.ihl as usize * 4).min(packet.len());
+            let total_len = (header.length as usize).clamp(header_len, packet.len());
+            let payload = &packet[header_len..total_len];
+            let source = IpAddr::V4(header.source_addr);
+            let destination = IpAddr::V4(header.dest_addr);
+
+            // Neither More Fragments nor an offset: the datagram is all here
+            let more_fragments = header.flags & 0b001 == 1;
+            if !more_fragments && header.fragment_offset == 0 {
+                return handle_transport_protocol(
+                    frame,
+                    state,
+                    source,
+                    destination,
+                    header.protocol,
+                    payload,
+                ).map(|mut packet| {
+                    packet.ip_bytes = header.length as u32;
+                    packet
+                });
+            }
+
+            // The raw protocol number is byte 9 of the header, the offset is in 8-octet units
+            let key = FragmentKey { src_addr: source, dest_addr: destination, protocol: packet[9], id: header.id as u32 };
+            let reassembled = match state.fragments.insert(key, header.fragment_offset as usize * 8, more_fragments, payload, frame, &mut state.anomalies) {
+                Some(reassembled) => reassembled,
+                None => return Err(Error::FragmentBuffered)
+            };
+
             handle_transport_protocol(
-                interface_name,
-                IpAddr::V4(header.source_addr),
-                IpAddr::V4(header.dest_addr),
+                frame,
+                state,
+                source,
+                destination,
                 header.protocol,
-                payload,
-            )
+                &reassembled.payload,
+            ).map(|mut packet| {
+                // Bytes of the rebuilt datagram, on the wire every fragment counts
+                packet.ip_bytes = (header_len + reassembled.payload.len()) as u32;
+                packet.wire_len = reassembled.wire_bytes;
+                packet.fragment_id = Some(header.id as u32);
+                packet
+            })
         },
         Err(_) => Err(Error::IPv4ParsingError)
     }
 }
 
-fn handle_ipv6_packet(interface_name: &str, packet: &[u8]) -> Result<Packet, Error> {
+fn handle_ipv6_upper_layer(
+    frame: &Frame,
+    state: &mut ParserState,
+    source: IpAddr,
+    destination: IpAddr,
+    chain: ExtensionChain,
+    packet: &[u8],
+) -> Result<Packet, Error> {
+    // The walk stopped at the fragment header (if any), go on with the headers after it
+    let rest = match walk_extension_headers(chain.protocol, packet) {
+        Some(rest) => rest,
+        None => return Err(Error::IPv6ParsingError)
+    };
+
+    handle_transport_protocol(
+        frame,
+        state,
+        source,
+        destination,
+        IPProtocol::from(rest.protocol),
+        &packet[rest.offset..],
+    ).map(|mut packet| {
+        packet.ipv6_extensions = chain.extensions;
+        packet.ipv6_extensions.extend(rest.extensions);
+        packet.fragment_id = chain.fragment.map(|fragment| fragment.id);
+        packet
+    })
+}
+
+fn handle_ipv6_packet(frame: &Frame, state: &mut ParserState, packet: &[u8]) -> Result<Packet, Error> {
     let parsed_ipv6 = parse_ipv6_header(packet);
 
     match parsed_ipv6 {
         Ok(tuple) => {
-            let payload = tuple.0;
             let header = tuple.1;
-            handle_transport_protocol(
-                interface_name,
-                IpAddr::V6(header.source_addr),
-                IpAddr::V6(header.dest_addr),
-                header.next_header,
-                payload,
-            )
+            // Drop the link layer padding after the payload length
+            let payload = &tuple.0[..(header.length as usize).min(tuple.0.len())];
+
+            // The raw Next Header (byte 6) is walked through the extension headers
+            let chain = match walk_extension_headers(packet[6], payload) {
+                Some(chain) => chain,
+                None => return Err(Error::IPv6ParsingError)
+            };
+            let source = IpAddr::V6(header.source_addr);
+            let destination = IpAddr::V6(header.dest_addr);
+            let fragmentable = &payload[chain.offset..];
+
+            match chain.fragment {
+                // Atomic fragments (offset 0, no More Fragments) carry the whole datagram
+                Some(fragment) if fragment.offset != 0 || fragment.more_fragments => {
+                    let key = FragmentKey { src_addr: source, dest_addr: destination, protocol: chain.protocol, id: fragment.id };
+                    let reassembled = match state.fragments.insert(key, fragment.offset as usize, fragment.more_fragments, fragmentable, frame, &mut state.anomalies) {
+                        Some(reassembled) => reassembled,
+                        None => return Err(Error::FragmentBuffered)
+                    };
+                    let ip_bytes = (40 + chain.offset + reassembled.payload.len()) as u32;
+
+                    handle_ipv6_upper_layer(frame, state, source, destination, chain, &reassembled.payload).map(|mut packet| {
+                        // Bytes of the rebuilt datagram, on the wire every fragment counts
+                        packet.ip_bytes = ip_bytes;
+                        packet.wire_len = reassembled.wire_bytes;
+                        packet
+                    })
+                },
+                _ => {
+                    handle_ipv6_upper_layer(frame, state, source, destination, chain, fragmentable).map(|mut packet| {
+                        // The fixed header is not part of the payload length
+                        packet.ip_bytes = 40 + header.length as u32;
+                        packet
+                    })
+                }
+            }
         },
         Err(_) => Err(Error::IPv6ParsingError)
     }
 }
 
-fn handle_arp_packet(interface_name: &str, packet: &[u8]) -> Result<Packet, Error> {
+fn handle_arp_packet(frame: &Frame, packet: &[u8]) -> Result<Packet, Error> {
     let parsed_arp = parse_arp_pkt(packet);
 
     match parsed_arp {
         Ok(tuple) => {
             let _payload = tuple.0;
             let header = tuple.1;
+            let message = ArpMessage::from_packet(&header);
 
-            Ok(Packet::new(
-                interface_name.to_string(),
-                IpAddr::from(header.src_addr),
-                IpAddr::from(header.dest_addr),
-                "none".to_string(),
-                None,
-                None,
-                64,
-                "ARP".to_string(),
-                "unknown".to_string(),
-                chrono::offset::Local::now().to_string()
-            ))
+            let mut packet = Packet::new(
+                frame,
+                Endpoints::new(IpAddr::from(header.src_addr), IpAddr::from(header.dest_addr)),
+                0,
+                format!("ARP {}", message.operation)
+            );
+            packet.arp = Some(message);
+            Ok(packet)
         },
         Err(_) => Err(Error::ARPParsingError)
     }
 }
 
-pub fn handle_ethernet_frame(interface: &Device, ethernet: &[u8]) -> Result<Packet, Error> {
-    let interface_name = &interface.name[..];
+fn handle_vlan_tag(frame: &Frame, state: &mut ParserState, packet: &[u8]) -> Result<Packet, Error> {
+    // Tag control information (PCP, DEI, VID) followed by the encapsulated EtherType
+    if packet.len() < 4 {
+        return Err(Error::VLANParsingError);
+    }
+    let vid = u16::from_be_bytes([packet[0], packet[1]]) & 0x0fff;
+    let ethertype = u16::from_be_bytes([packet[2], packet[3]]);
+    state.nest()?;
+
+    // Stacked tags (QinQ) are handled by the recursion, the outer one is recorded last
+    handle_ethertype(frame, state, EtherType::from(ethertype), &packet[4..]).map(|mut packet| {
+        packet.vlan.insert(0, vid);
+        packet
+    })
+}
+
+fn handle_mpls_stack(frame: &Frame, state: &mut ParserState, packet: &[u8]) -> Result<Packet, Error> {
+    // Label (20 bits), traffic class (3), bottom of stack (1) and TTL (8) for every entry
+    let mut labels = Vec::new();
+    let mut offset = 0;
+    loop {
+        let entry = match packet.get(offset..offset + 4) {
+            Some(entry) => u32::from_be_bytes([entry[0], entry[1], entry[2], entry[3]]),
+            None => return Err(Error::MPLSParsingError)
+        };
+        labels.push(entry >> 12);
+        offset += 4;
+        if entry & 0x100 != 0 {
+            break;
+        }
+    }
+    let payload = &packet[offset..];
+    state.nest()?;
+
+    // There is no protocol field: the first nibble tells IPv4 and IPv6 from the control word of an Ethernet pseudowire
+    let parsed = match payload.first().map(|byte| byte >> 4) {
+        Some(4) => handle_ipv4_packet(frame, state, payload),
+        Some(6) => handle_ipv6_packet(frame, state, payload),
+        Some(0) if payload.len() >= 4 => handle_ethernet_frame(frame, state, &payload[4..]),
+        _ => Err(Error::MPLSParsingError)
+    };
+    parsed.map(|mut packet| {
+        packet.mpls_labels.splice(0..0, labels);
+        packet
+    })
+}
+
+fn handle_pppoe_session(frame: &Frame, state: &mut ParserState, packet: &[u8]) -> Result<Packet, Error> {
+    // Version and type (both 1), code (0 for session data), session id and length, followed by the PPP protocol
+    if packet.len() < 8 || packet[0] != 0x11 || packet[1] != 0 {
+        return Err(Error::PPPoEParsingError);
+    }
+    let session = u16::from_be_bytes([packet[2], packet[3]]);
+    let length = u16::from_be_bytes([packet[4], packet[5]]) as usize;
+    let protocol = u16::from_be_bytes([packet[6], packet[7]]);
+    // The length counts the PPP protocol, what follows is link layer padding
+    let payload = &packet[8..(6 + length).clamp(8, packet.len())];
+    state.nest()?;
+
+    let parsed = match protocol {
+        PPP_IPV4 => handle_ipv4_packet(frame, state, payload),
+        PPP_IPV6 => handle_ipv6_packet(frame, state, payload),
+        // LCP, authentication and the network control protocols carry no IP packet
+        _ => Err(Error::UnknownPacket)
+    };
+    parsed.map(|mut packet| {
+        packet.pppoe_session = Some(session);
+        packet
+    })
+}
+
+fn handle_ethertype(frame: &Frame, state: &mut ParserState, ethertype: EtherType, packet: &[u8]) -> Result<Packet, Error> {
+    match ethertype {
+        EtherType::VLAN | EtherType::QinQ | EtherType::VLANdouble => handle_vlan_tag(frame, state, packet),
+        EtherType::IPv4 => handle_ipv4_packet(frame, state, packet),
+        EtherType::IPv6 => handle_ipv6_packet(frame, state, packet),
+        EtherType::ARP => handle_arp_packet(frame, packet),
+        EtherType::MPLSuni | EtherType::MPLSmulti => handle_mpls_stack(frame, state, packet),
+        // Only the session stage carries IP packets, discovery frames are left unknown
+        EtherType::PPPoEsession => handle_pppoe_session(frame, state, packet),
+        _ => Err(
+            Error::UnknownPacket
+        )
+    }
+}
+
+fn handle_raw_ip_packet(frame: &Frame, state: &mut ParserState, packet: &[u8]) -> Result<Packet, Error> {
+    // No link layer header at all, the IP version tells the protocol
+    match packet.first().map(|b| b >> 4) {
+        Some(4) => handle_ipv4_packet(frame, state, packet),
+        Some(6) => handle_ipv6_packet(frame, state, packet),
+        _ => Err(Error::UnknownPacket)
+    }
+}
+
+fn handle_loopback_frame(frame: &Frame, state: &mut ParserState, packet: &[u8]) -> Result<Packet, Error> {
+    if packet.len() < 4 {
+        return Err(Error::LoopbackParsingError);
+    }
+
+    // DLT_NULL stores the address family in the byte order of the capturing host,
+    // DLT_LOOP in network byte order: the right one is the small value
+    let family_bytes = [packet[0], packet[1], packet[2], packet[3]];
+    let family = u32::from_be_bytes(family_bytes).min(u32::from_le_bytes(family_bytes));
+
+    match family {
+        // AF_INET is the same everywhere
+        2 => handle_ipv4_packet(frame, state, &packet[4..]),
+        // AF_INET6 on Linux, BSDs, FreeBSD and macOS
+        10 | 24 | 28 | 30 => handle_ipv6_packet(frame, state, &packet[4..]),
+        _ => Err(Error::UnknownPacket)
+    }
+}
+
+/// Cooked captures keep only the link-layer address of the sender, a MAC if the ARPHRD type is Ethernet
+fn sll_source_mac(arphrd_type: &[u8], addr_len: usize, addr: &[u8]) -> Option<MacAddress> {
+    match (u16::from_be_bytes([arphrd_type[0], arphrd_type[1]]), addr_len) {
+        (ARPHRD_ETHER, 6) => Some(MacAddress(addr[..6].try_into().unwrap())),
+        _ => None
+    }
+}
+
+fn handle_sll_frame(frame: &Frame, state: &mut ParserState, packet: &[u8]) -> Result<Packet, Error> {
+    // Packet type (2), ARPHRD type (2), address length (2), address (8), protocol (2)
+    if packet.len() < 16 {
+        return Err(Error::SLLParsingError);
+    }
+    let protocol = u16::from_be_bytes([packet[14], packet[15]]);
+    let source = sll_source_mac(&packet[2..4], packet[5] as usize, &packet[6..14]);
+
+    handle_ethertype(frame, state, EtherType::from(protocol), &packet[16..]).map(|mut packet| {
+        packet.src_mac = packet.src_mac.or(source);
+        packet
+    })
+}
+
+fn handle_sll2_frame(frame: &Frame, state: &mut ParserState, packet: &[u8]) -> Result<Packet, Error> {
+    // Protocol (2), reserved (2), interface index (4), ARPHRD type (2), packet type (1),
+    // address length (1), address (8)
+    if packet.len() < 20 {
+        return Err(Error::SLLParsingError);
+    }
+    let protocol = u16::from_be_bytes([packet[0], packet[1]]);
+    let source = sll_source_mac(&packet[8..10], packet[11] as usize, &packet[12..20]);
+
+    handle_ethertype(frame, state, EtherType::from(protocol), &packet[20..]).map(|mut packet| {
+        packet.src_mac = packet.src_mac.or(source);
+        packet
+    })
+}
+
+pub fn handle_ethernet_frame(frame: &Frame, state: &mut ParserState, ethernet: &[u8]) -> Result<Packet, Error> {
     let ethernet_frame = parse_ethernet_frame(ethernet);
 
     match ethernet_frame {
         Ok(tuple) => {
             let payload = tuple.0;
             let header = tuple.1;
-            match header.ethertype {
-                EtherType::IPv4 => handle_ipv4_packet(interface_name, payload),
-                EtherType::IPv6 => handle_ipv6_packet(interface_name, payload),
-                EtherType::ARP => handle_arp_packet(interface_name, payload),
-                _ => Err(
-                    Error::UnknownPacket
-                )
-            }
+            handle_ethertype(frame, state, header.ethertype, payload).map(|mut packet| {
+                // Tunnels carrying Ethernet keep the addresses of the inner frame
+                packet.src_mac = packet.src_mac.or(Some(header.source_mac));
+                packet.dest_mac = packet.dest_mac.or(Some(header.dest_mac));
+                packet
+            })
         },
         Err(_) => Err(Error::EthernetParsingError)
     }
+}
+
+const ARPHRD_ETHER: u16 = 1;
+
+const PPP_IPV4: u16 = 0x0021;
+const PPP_IPV6: u16 = 0x0057;
+
+// More than any real network stacks (QinQ inside MPLS inside a tunnel is already 4)
+const MAX_ENCAPSULATION_DEPTH: usize = 8;
+
+// DLT_RAW as returned by libpcap on most platforms, LINKTYPE_RAW (101) is only used in savefiles
+const DLT_RAW: Linktype = Linktype(12);
+
+pub fn handle_frame(frame: &Frame, state: &mut ParserState) -> Result<Packet, Error> {
+    let data = &frame.data[..];
+    state.last_seen = Some(frame.timestamp);
+    state.depth = 0;
+
+    let parsed = match frame.linktype {
+        Linktype::ETHERNET => handle_ethernet_frame(frame, state, data),
+        Linktype::LINUX_SLL => handle_sll_frame(frame, state, data),
+        Linktype::LINUX_SLL2 => handle_sll2_frame(frame, state, data),
+        Linktype::RAW | DLT_RAW => handle_raw_ip_packet(frame, state, data),
+        Linktype::IPV4 => handle_ipv4_packet(frame, state, data),
+        Linktype::IPV6 => handle_ipv6_packet(frame, state, data),
+        Linktype::NULL | Linktype::LOOP => handle_loopback_frame(frame, state, data),
+        _ => Err(Error::UnknownLinktype)
+    };
+
+    // Connections are tracked here, the VLAN tags are known only after the link layer
+    parsed.map(|mut packet| {
+        // Flows without a name of their own get the one recently resolved for their addresses
+        if packet.res_name == "none" {
+            let name = state.dns_cache.lookup(&packet.dest_addr, frame.timestamp)
+                .or_else(|| state.dns_cache.lookup(&packet.src_addr, frame.timestamp));
+            if let Some(name) = name {
+                packet.res_name = name.to_string();
+            }
+        }
+        if let Some(mac) = packet.src_mac {
+            packet.src_vendor = state.oui.vendor(mac).to_string();
+        }
+        if let Some(mac) = packet.dest_mac {
+            packet.dest_vendor = state.oui.vendor(mac).to_string();
+        }
+        // The payload is needed only by the classifier and the reassembly, reports don't keep it
+        let payload = std::mem::take(&mut packet.payload);
+        packet.application = state.classifier.classify(&packet, &payload);
+        if let Some(message) = &packet.arp {
+            state.arp.update(&packet.interface, &packet.vlan, message, frame.timestamp, &mut state.anomalies);
+        }
+        if let Some((tcp_state, start, from_initiator)) = state.connections.update(&packet) {
+            packet.tcp_state = Some(tcp_state);
+            packet.connection_start = Some(start);
+            state.track_stream(&mut packet, from_initiator, &payload);
+        }
+        packet
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(data: Vec<u8>) -> Frame {
+        Frame::fixture(Linktype::ETHERNET, data)
+    }
+
+    /// IPv4 datagram from 192.0.2.1:40000 to 192.0.2.2:9999 carrying `payload` over UDP
+    fn udp_datagram(payload: &[u8]) -> Vec<u8> {
+        let total = (20 + 8 + payload.len()) as u16;
+        let mut packet = vec![0x45, 0];
+        packet.extend_from_slice(&total.to_be_bytes());
+        packet.extend_from_slice(&[0, 1, 0, 0, 64, 17, 0, 0, 192, 0, 2, 1, 192, 0, 2, 2]);
+        packet.extend_from_slice(&40000u16.to_be_bytes());
+        packet.extend_from_slice(&9999u16.to_be_bytes());
+        packet.extend_from_slice(&((8 + payload.len()) as u16).to_be_bytes());
+        packet.extend_from_slice(&[0, 0]);
+        packet.extend_from_slice(payload);
+        packet
+    }
+
+    /// IPv6 datagram from 2001:db8::1:40000 to 2001:db8::2:9999 carrying `payload` over UDP
+    fn udp6_datagram(payload: &[u8]) -> Vec<u8> {
+        let mut packet = vec![0x60, 0, 0, 0];
+        packet.extend_from_slice(&((8 + payload.len()) as u16).to_be_bytes());
+        packet.extend_from_slice(&[17, 64]);
+        packet.extend_from_slice(&"2001:db8::1".parse::<Ipv6Addr>().unwrap().octets());
+        packet.extend_from_slice(&"2001:db8::2".parse::<Ipv6Addr>().unwrap().octets());
+        packet.extend_from_slice(&40000u16.to_be_bytes());
+        packet.extend_from_slice(&9999u16.to_be_bytes());
+        packet.extend_from_slice(&((8 + payload.len()) as u16).to_be_bytes());
+        packet.extend_from_slice(&[0, 0]);
+        packet.extend_from_slice(payload);
+        packet
+    }
+
+    fn ethernet(ethertype: u16, payload: &[u8]) -> Vec<u8> {
+        let mut frame = vec![0x02, 0, 0, 0, 0, 0x02, 0x02, 0, 0, 0, 0, 0x01];
+        frame.extend_from_slice(&ethertype.to_be_bytes());
+        frame.extend_from_slice(payload);
+        frame
+    }
+
+    fn mpls_entry(label: u32, bottom: bool) -> [u8; 4] {
+        (label << 12 | (bottom as u32) << 8 | 64).to_be_bytes()
+    }
+
+    fn parse(linktype: Linktype, data: Vec<u8>) -> Result<Packet, Error> {
+        handle_frame(&Frame::fixture(linktype, data), &mut ParserState::new())
+    }
+
+    #[test]
+    fn linux_cooked_capture() {
+        // Sent by us, ARPHRD_ETHER, 6 bytes of address padded to 8, IPv4
+        let mut frame = vec![0, 4, 0, 1, 0, 6, 0x02, 0, 0, 0, 0, 0x01, 0, 0, 0x08, 0x00];
+        frame.extend_from_slice(&udp_datagram(b"hello"));
+
+        let packet = parse(Linktype::LINUX_SLL, frame).unwrap();
+
+        assert_eq!(packet.src_mac, Some(MacAddress([0x02, 0, 0, 0, 0, 0x01])));
+        assert_eq!(packet.dest_mac, None);
+        assert_eq!(packet.dest_port, Some(9999));
+        assert!(matches!(parse(Linktype::LINUX_SLL, vec![0; 15]), Err(Error::SLLParsingError)));
+    }
+
+    #[test]
+    fn linux_cooked_capture_v2() {
+        // IPv6, interface 2, ARPHRD_ETHER, to us, 6 bytes of address padded to 8
+        let mut frame = vec![0x86, 0xdd, 0, 0, 0, 0, 0, 2, 0, 1, 0, 6, 0x02, 0, 0, 0, 0, 0x01, 0, 0];
+        frame.extend_from_slice(&udp6_datagram(b"hello"));
+
+        let packet = parse(Linktype::LINUX_SLL2, frame).unwrap();
+
+        assert_eq!(packet.src_mac, Some(MacAddress([0x02, 0, 0, 0, 0, 0x01])));
+        assert_eq!(packet.src_addr, "2001:db8::1".parse::<IpAddr>().unwrap());
+        // Not Ethernet (ARPHRD_NONE of a tunnel interface), no MAC
+        let mut frame = vec![0x08, 0x00, 0, 0, 0, 0, 0, 2, 0xff, 0xfe, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        frame.extend_from_slice(&udp_datagram(b"hello"));
+        assert_eq!(parse(Linktype::LINUX_SLL2, frame).unwrap().src_mac, None);
+    }
+
+    #[test]
+    fn raw_ip_by_version() {
+        assert_eq!(parse(Linktype::RAW, udp_datagram(b"hello")).unwrap().dest_addr, "192.0.2.2".parse::<IpAddr>().unwrap());
+        assert_eq!(parse(Linktype::RAW, udp6_datagram(b"hello")).unwrap().dest_addr, "2001:db8::2".parse::<IpAddr>().unwrap());
+        // DLT_RAW, as live captures report it
+        assert!(parse(DLT_RAW, udp_datagram(b"hello")).is_ok());
+        assert!(parse(Linktype::IPV6, udp6_datagram(b"hello")).is_ok());
+        assert!(matches!(parse(Linktype::RAW, vec![0x50; 40]), Err(Error::UnknownPacket)));
+    }
+
+    #[test]
+    fn loopback_address_family_in_either_byte_order() {
+        let loopback = |family: [u8; 4], datagram: Vec<u8>| {
+            let mut frame = family.to_vec();
+            frame.extend_from_slice(&datagram);
+            frame
+        };
+
+        // DLT_NULL written by a little endian host: AF_INET, AF_INET6 of Linux and of macOS
+        assert!(parse(Linktype::NULL, loopback([2, 0, 0, 0], udp_datagram(b"hello"))).is_ok());
+        assert!(parse(Linktype::NULL, loopback([10, 0, 0, 0], udp6_datagram(b"hello"))).is_ok());
+        assert!(parse(Linktype::NULL, loopback([30, 0, 0, 0], udp6_datagram(b"hello"))).is_ok());
+        // DLT_LOOP is in network byte order, AF_INET6 of the BSDs
+        assert!(parse(Linktype::LOOP, loopback([0, 0, 0, 24], udp6_datagram(b"hello"))).is_ok());
+        assert!(matches!(parse(Linktype::NULL, loopback([7, 0, 0, 0], udp_datagram(b"hello"))), Err(Error::UnknownPacket)));
+        assert!(matches!(parse(Linktype::NULL, vec![2, 0, 0]), Err(Error::LoopbackParsingError)));
+    }
+
+    #[test]
+    fn unknown_linktype() {
+        assert!(matches!(parse(Linktype(147), udp_datagram(b"hello")), Err(Error::UnknownLinktype)));
+    }
+
+    /// Router Advertisement from a router with MAC 02:00:00:00:00:fe announcing 2001:db8:1::/64
+    fn router_advertisement() -> Icmpv6Message {
+        let mut packet = vec![134, 0, 0, 0, 64, 0, 0x07, 0x08, 0, 0, 0, 0, 0, 0, 0, 0];
+        packet.extend_from_slice(&[1, 1, 0x02, 0, 0, 0, 0, 0xfe]);
+        packet.extend_from_slice(&[3, 4, 64, 0xc0, 0, 0, 0x0e, 0x10, 0, 0, 0x0e, 0x10, 0, 0, 0, 0]);
+        packet.extend_from_slice(&"2001:db8:1::".parse::<Ipv6Addr>().unwrap().octets());
+        parse_icmpv6(&packet).unwrap()
+    }
+
+    #[test]
+    fn first_router_of_the_interface_is_trusted() {
+        let mut state = ParserState::new();
+        let frame = Frame::fixture(Linktype::ETHERNET, Vec::new());
+
+        state.check_router_advertisement(&frame, "fe80::1".parse().unwrap(), &router_advertisement());
+        state.check_router_advertisement(&frame, "fe80::1".parse().unwrap(), &router_advertisement());
+        // The first router of another interface
+        state.check_router_advertisement(&Frame { interface: "eth1".to_string(), ..frame.clone() }, "fe80::2".parse().unwrap(), &router_advertisement());
+        assert!(state.anomalies.is_empty());
+
+        state.check_router_advertisement(&frame, "fe80::2".parse().unwrap(), &router_advertisement());
+        assert_eq!(state.anomalies.len(), 1);
+        assert_eq!(state.anomalies[0].kind, AnomalyKind::RogueRouterAdvertisement);
+        assert_eq!(state.anomalies[0].description, "fe80::2 on eth0 (MAC 02:00:00:00:00:fe, lifetime 1800s, prefixes 2001:db8:1::/64)");
+    }
+
+    #[test]
+    fn routers_advertise_from_link_local_addresses() {
+        let mut state = ParserState::new();
+        let frame = Frame::fixture(Linktype::ETHERNET, Vec::new());
+
+        state.check_router_advertisement(&frame, "2001:db8::1".parse().unwrap(), &router_advertisement());
+
+        assert_eq!(state.anomalies.len(), 1);
+    }
+
+    #[test]
+    fn only_the_given_routers_are_trusted() {
+        let mut state = ParserState::new();
+        state.routers = vec!["fe80::2".parse().unwrap()];
+        let frame = Frame::fixture(Linktype::ETHERNET, Vec::new());
+        let bare = parse_icmpv6(&[134, 0, 0, 0, 64, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]).unwrap();
+
+        state.check_router_advertisement(&frame, "fe80::2".parse().unwrap(), &bare);
+        state.check_router_advertisement(&frame, "fe80::1".parse().unwrap(), &bare);
+
+        assert_eq!(state.anomalies.len(), 1);
+        assert_eq!(state.anomalies[0].description, "fe80::1 on eth0 (MAC unknown, lifetime 0s, prefixes none)");
+    }
+
+    #[test]
+    fn mpls_label_stack() {
+        let mut stack = Vec::new();
+        stack.extend_from_slice(&mpls_entry(16, false));
+        stack.extend_from_slice(&mpls_entry(1000, true));
+        stack.extend_from_slice(&udp_datagram(b"hello"));
+        let mut state = ParserState::new();
+
+        let packet = handle_frame(&frame(ethernet(0x8847, &stack)), &mut state).unwrap();
+
+        assert_eq!(packet.mpls_labels, [16, 1000]);
+        assert_eq!(packet.src_addr, "192.0.2.1".parse::<IpAddr>().unwrap());
+        assert_eq!(packet.dest_port, Some(9999));
+        assert_eq!(packet.transport, "UDP");
+    }
+
+    #[test]
+    fn mpls_ethernet_pseudowire() {
+        let mut stack = mpls_entry(2000, true).to_vec();
+        // Control word, then the customer frame tagged with VLAN 10
+        stack.extend_from_slice(&[0, 0, 0, 0]);
+        let mut tagged = vec![0, 10, 0x08, 0x00];
+        tagged.extend_from_slice(&udp_datagram(b"hello"));
+        stack.extend_from_slice(&ethernet(0x8100, &tagged));
+        let mut state = ParserState::new();
+
+        let packet = handle_frame(&frame(ethernet(0x8847, &stack)), &mut state).unwrap();
+
+        assert_eq!(packet.mpls_labels, [2000]);
+        assert_eq!(packet.vlan, [10]);
+    }
+
+    #[test]
+    fn mpls_stack_without_bottom() {
+        let mut stack = Vec::new();
+        stack.extend_from_slice(&mpls_entry(16, false));
+        stack.extend_from_slice(&mpls_entry(17, false));
+        let mut state = ParserState::new();
+
+        let parsed = handle_frame(&frame(ethernet(0x8847, &stack)), &mut state);
+
+        assert!(matches!(parsed, Err(Error::MPLSParsingError)));
+    }
+
+    #[test]
+    fn encapsulation_depth_limit() {
+        let tags = |count: usize| {
+            let mut packet = Vec::new();
+            for vid in 1..=count as u16 {
+                let ethertype: u16 = if vid == count as u16 { 0x0800 } else { 0x8100 };
+                packet.extend_from_slice(&vid.to_be_bytes());
+                packet.extend_from_slice(&ethertype.to_be_bytes());
+            }
+            packet.extend_from_slice(&udp_datagram(b"hello"));
+            ethernet(0x8100, &packet)
+        };
+        let mut state = ParserState::new();
+
+        let packet = handle_frame(&frame(tags(MAX_ENCAPSULATION_DEPTH)), &mut state).unwrap();
+        assert_eq!(packet.vlan.len(), MAX_ENCAPSULATION_DEPTH);
+        let parsed = handle_frame(&frame(tags(MAX_ENCAPSULATION_DEPTH + 1)), &mut state);
+        assert!(matches!(parsed, Err(Error::EncapsulationTooDeep)));
+        // The budget is per frame
+        assert!(handle_frame(&frame(tags(2)), &mut state).is_ok());
+    }
 }
\ No newline at end of file
@@ -1,4 +1,5 @@
 use std::collections::HashMap;
+use std::collections::hash_map::Entry;
 use std::fmt;
 use std::fs::{create_dir, File, set_permissions};
 use std::path::Path;
@@ -6,14 +7,19 @@ use std::net::IpAddr;
 use std::io::Write;
 use std::os::unix::fs::PermissionsExt;
 
+use chrono::{DateTime, Duration, Local};
 use csv::{Writer, WriterBuilder};
 use serde::Serialize;
 
 use crate::report::Reporter::{CSV, TXT};
+use crate::connection::TcpState;
 use crate::parser::Packet;
+use crate::utils::{join_values, mac_to_str};
 
 #[derive(PartialEq, Eq, Hash)]
 pub struct ReportHeader {
+    pub interface: String,
+    pub vlan: Vec<u16>,
     pub src_addr: IpAddr,
     pub dest_addr: IpAddr,
     pub src_port: Option<u16>,
@@ -24,14 +30,40 @@ pub struct ReportHeader {
 pub struct Report {
     pub packet: Packet,
     pub total_bytes: u64,
-    pub start_time: String,
-    pub stop_time: String
+    pub ip_bytes: u64,
+    pub payload_bytes: u64,
+    pub syn_count: u32,
+    pub fin_count: u32,
+    pub rst_count: u32,
+    pub state: Option<TcpState>,
+    /// Seconds since the start of the TCP connection (or of the flow in this report)
+    pub duration: f64,
+    /// Fingerprints of the TLS ClientHello, if the flow carries one
+    pub ja3: Option<String>,
+    pub ja4: Option<String>,
+    pub start_time: DateTime<Local>,
+    pub stop_time: DateTime<Local>
+}
+
+impl Report {
+    /// A connection still open with no traffic for longer than `timeout` has timed out
+    pub fn check_timeout(&mut self, now: DateTime<Local>, timeout: Duration) {
+        if let Some(state) = self.state {
+            if !state.is_finished() && now - self.stop_time > timeout {
+                self.state = Some(TcpState::TimedOut);
+            }
+        }
+    }
 }
 
 impl fmt::Display for Report {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         //        "Interface\t| Source IP address\t| Source Port\t| Dest IP address \t| Dest Port\t| Timestamp\t|  Bytes\t| Transport \t| Application \n"
-        write!(f, "| {0: <1}\t| {1: <20}\t| {2: <5}\t| {3: <25} ({4}) \t| {5: <5}\t| {6: <3}\t| {7: <4} \t| {8: <4}\t| {9: <15}\t| {10: <15}", self.packet.interface, self.packet.src_addr, self.packet.src_port.unwrap_or(0), self.packet.dest_addr, self.packet.res_name, self.packet.dest_port.unwrap_or(0), self.total_bytes, self.packet.transport, self.packet.application, self.start_time, self.stop_time )
+        let src_mac = self.packet.src_mac.map(mac_to_str).unwrap_or("-".to_string());
+        let dest_mac = self.packet.dest_mac.map(mac_to_str).unwrap_or("-".to_string());
+        let pppoe_session = self.packet.pppoe_session.map(|session| session.to_string()).unwrap_or("-".to_string());
+        let quoted = self.packet.quoted.map(|quoted| quoted.to_string()).unwrap_or("-".to_string());
+        write!(f, "| {0: <1}\t| {1: <4}\t| {2: <4}\t| {3: <4}\t| {4: <5}\t| {5: <17} ({6})\t| {7: <17} ({8})\t| {9: <20}\t| {10: <5}\t| {11: <25} ({12}) \t| {13: <5}\t| {14: <3}\t| {15: <3}\t| {16: <3}\t| {17: <4} \t| {18: <4}\t| {19: <40}\t| {20: <3}\t| {21: <3}\t| {22: <3}\t| {23: <12}\t| {24: <8.3}\t| {25: <32}\t| {26: <36}\t| {27: <15}\t| {28: <15}", self.packet.interface, join_values(&self.packet.vlan, "."), join_values(&self.packet.tunnels, ", "), join_values(&self.packet.mpls_labels, "."), pppoe_session, src_mac, self.packet.src_vendor, dest_mac, self.packet.dest_vendor, self.packet.src_addr, self.packet.src_port.unwrap_or(0), self.packet.dest_addr, self.packet.res_name, self.packet.dest_port.unwrap_or(0), self.total_bytes, self.ip_bytes, self.payload_bytes, self.packet.transport, self.packet.application, quoted, self.syn_count, self.fin_count, self.rst_count, self.state.map(|state| state.to_string()).unwrap_or("-".to_string()), self.duration, self.ja3.as_deref().unwrap_or("-"), self.ja4.as_deref().unwrap_or("-"), self.start_time, self.stop_time )
     }
 }
 
@@ -42,7 +74,9 @@ pub enum Reporter <'a> {
 
 pub struct ReportWriter {
     pub csv_mode: bool,
+    pub folder: String,
     pub filename: String,
+    pub index: i32,
     csv_writer: Option<Box<Writer<File>>>,
     txt_writer: Option<Box<File>>
 }
@@ -59,7 +93,9 @@ impl ReportWriter {
                 };
                 Self {
                     csv_mode,
+                    folder: folder.to_string(),
                     filename: filename.to_string(),
+                    index,
                     csv_writer: Some(Box::new(file)),
                     txt_writer: None
                 }
@@ -73,7 +109,9 @@ impl ReportWriter {
                 };
                 return Self {
                     csv_mode,
+                    folder: folder.to_string(),
                     filename: filename.to_string(),
+                    index,
                     csv_writer: None,
                     txt_writer: Some(Box::new(file))
                 }
@@ -97,12 +135,12 @@ impl ReportWriter {
         let writer = self.get_csv_ref();
         match writer {
             Reporter::CSV(csv) => csv.write_record(
-                        &["interface", "src_addr", "dest_addr",
-                            "res_name", "src_port", "dest_port", "transport", "application",
-                            "tot_bytes", "start_time", "stop_time"]
+                        &["interface", "vlan", "tunnel", "mpls", "pppoe_session", "src_mac", "src_vendor", "dest_mac", "dest_vendor", "src_addr", "dest_addr",
+                            "res_name", "src_port", "dest_port", "transport", "application", "quoted",
+                            "tot_bytes", "ip_bytes", "payload_bytes", "syn", "fin", "rst", "state", "duration", "ja3", "ja4", "start_time", "stop_time"]
                     ).unwrap(),
             Reporter::TXT(file) => {
-                writeln!(file, "| Interface\t| Source IP address\t| Source Port\t| Dest IP address \t| Dest Port\t| Tot Bytes\t| Transport \t| Application \t| First Timestamp \t| Last Timestamp \n").unwrap();
+                writeln!(file, "| Interface\t| VLAN\t| Tunnel\t| MPLS\t| PPPoE\t| Source MAC (Vendor)\t\t| Dest MAC (Vendor)\t\t| Source IP address\t| Source Port\t| Dest IP address \t| Dest Port\t| Tot Bytes\t| IP Bytes\t| Payload Bytes\t| Transport \t| Application \t| Quoted\t\t\t\t| SYN\t| FIN\t| RST\t| State\t\t| Duration\t| JA3\t\t\t\t\t| JA4\t\t\t\t\t| First Timestamp \t| Last Timestamp \n").unwrap();
             }
         }
     }
@@ -117,6 +155,36 @@ impl ReportWriter {
         }
     }
 
+    /// Write a section of extra rows (e.g. the anomalies) for the same report: appended to
+    /// the TXT report, or in a `{filename}-{index}-{name}.csv` file next to the CSV one
+    pub fn write_section<T: Serialize + fmt::Display>(&mut self, name: &str, header: &[&str], rows: &[T]) {
+        if rows.is_empty() {
+            return;
+        }
+        let writer = self.get_csv_ref();
+        match writer {
+            Reporter::CSV(_) => {
+                let pathname = format!("{}/{}-{}-{}.csv", self.folder, self.filename, self.index, name);
+                let mut csv = match WriterBuilder::new().has_headers(false).from_path(&pathname) {
+                    Err(why) => panic!("couldn't create {}: {}", pathname, why),
+                    Ok(csv) => csv,
+                };
+                csv.write_record(header).unwrap();
+                for row in rows {
+                    csv.serialize(row).unwrap();
+                }
+                csv.flush().unwrap();
+            },
+            Reporter::TXT(file) => {
+                writeln!(file, "\n{}\n", name.to_uppercase()).unwrap();
+                writeln!(file, "| {}\n", header.join("\t| ")).unwrap();
+                for row in rows {
+                    writeln!(file, "{}", row).unwrap();
+                }
+            }
+        }
+    }
+
     pub fn close(&mut self) -> () {
         let writer = self.get_csv_ref();
         match writer {
@@ -126,6 +194,36 @@ impl ReportWriter {
     }
 }
 
+/// Write a table that covers the whole session (e.g. the DNS cache) in `{folder}/{name}.csv`
+/// (or `.txt`), replacing the previous one
+pub fn write_table<T: Serialize + fmt::Display>(csv_mode: bool, folder: &str, name: &str, header: &[&str], rows: &[T]) {
+    match csv_mode {
+        true => {
+            let pathname = format!("{}/{}.csv", folder, name);
+            let mut csv = match WriterBuilder::new().has_headers(false).from_path(&pathname) {
+                Err(why) => panic!("couldn't create {}: {}", pathname, why),
+                Ok(csv) => csv,
+            };
+            csv.write_record(header).unwrap();
+            for row in rows {
+                csv.serialize(row).unwrap();
+            }
+            csv.flush().unwrap();
+        },
+        false => {
+            let pathname = format!("{}/{}.txt", folder, name);
+            let mut file = match File::create(&pathname) {
+                Err(why) => panic!("couldn't create {}: {}", pathname, why),
+                Ok(file) => file,
+            };
+            writeln!(file, "| {}\n", header.join("\t| ")).unwrap();
+            for row in rows {
+                writeln!(file, "{}", row).unwrap();
+            }
+        }
+    }
+}
+
 pub fn setup_directory(filename: &str) -> String {
     let mut folder = format!(
         "{}_{}",
@@ -149,36 +247,89 @@ pub fn setup_directory(filename: &str) -> String {
     folder
 }
 
+fn seconds_between(start: DateTime<Local>, stop: DateTime<Local>) -> f64 {
+    (stop - start).num_milliseconds() as f64 / 1000.0
+}
+
 pub fn produce_hashmap(buffer: Vec<Packet>) -> HashMap<ReportHeader, Report> {
     let mut report = HashMap::new();
 
     for s in buffer {
-        let bytes = s.length;
+        // Frame, network and transport payload bytes are summed separately
+        let bytes = s.wire_len;
+        let ip_bytes = s.ip_bytes;
+        let payload_bytes = s.payload_bytes;
+        // Connection attempts, closes and resets seen in the interval
+        let flags = s.tcp_flags.unwrap_or_default();
+        let (syn, fin, rst) = (flags.syn as u32, flags.fin as u32, flags.rst as u32);
 
         let p_header = ReportHeader {
+            interface: s.interface.clone(),
+            vlan: s.vlan.clone(),
             src_addr: s.src_addr,
             dest_addr: s.dest_addr,
             src_port: s.src_port,
             dest_port: s.dest_port
         };
 
-        if report.contains_key(&p_header) {
-            let mut update: &mut Report = report.get_mut(&p_header).unwrap();
-            update.total_bytes += bytes as u64;
-            update.stop_time = s.timestamp;
-        } else {
-
-            report.insert(p_header, {
-                let time = s.timestamp.clone();
-                let time2 = s.timestamp.clone();
-
-                Report {
-                    packet: s,
-                    total_bytes: bytes as u64,
-                    start_time: time,
-                    stop_time: time2
+        match report.entry(p_header) {
+            Entry::Occupied(mut entry) => {
+                let update: &mut Report = entry.get_mut();
+                update.total_bytes += bytes as u64;
+                update.ip_bytes += ip_bytes as u64;
+                update.payload_bytes += payload_bytes as u64;
+                update.syn_count += syn;
+                update.fin_count += fin;
+                update.rst_count += rst;
+                update.stop_time = s.timestamp;
+                // The name may be learnt (e.g. from the DNS cache) after the first packet
+                if update.packet.res_name == "none" {
+                    update.packet.res_name = s.res_name.clone();
+                }
+                // ICMP errors between the same hosts show the first flow they refer to
+                if update.packet.quoted.is_none() {
+                    update.packet.quoted = s.quoted;
+                }
+                // Same for protocols recognized by their content
+                if update.packet.application == "unknown" {
+                    update.packet.application = s.application.clone();
+                }
+                // The SNI names the flow, whatever the DNS cache said
+                if let Some(hello) = &s.tls_client_hello {
+                    update.ja3 = Some(hello.ja3());
+                    update.ja4 = Some(hello.ja4());
+                    if let Some(server_name) = &hello.server_name {
+                        update.packet.res_name = server_name.clone();
+                    }
                 }
-            });
+                update.state = s.tcp_state;
+                update.duration = seconds_between(s.connection_start.unwrap_or(update.start_time), s.timestamp);
+            },
+            Entry::Vacant(entry) => {
+                entry.insert({
+                    let time = s.timestamp;
+                    let state = s.tcp_state;
+                    let duration = seconds_between(s.connection_start.unwrap_or(time), time);
+                    let ja3 = s.tls_client_hello.as_ref().map(|hello| hello.ja3());
+                    let ja4 = s.tls_client_hello.as_ref().map(|hello| hello.ja4());
+
+                    Report {
+                        packet: s,
+                        total_bytes: bytes as u64,
+                        ip_bytes: ip_bytes as u64,
+                        payload_bytes: payload_bytes as u64,
+                        syn_count: syn,
+                        fin_count: fin,
+                        rst_count: rst,
+                        state,
+                        duration,
+                        ja3,
+                        ja4,
+                        start_time: time,
+                        stop_time: time
+                    }
+                });
+            }
         }
         //writeln!(&mut file, "{}", s).unwrap();
     }
@@ -0,0 +1,80 @@
+use pcap::{Capture, Dead, Linktype, Packet, Precision, Savefile};
+
+// Size of the pcap global header and of every per-record header
+const PCAP_FILE_HEADER_LEN: u64 = 24;
+const PCAP_RECORD_HEADER_LEN: u64 = 16;
+
+pub struct SavefileWriter {
+    pub folder: String,
+    pub filename: String,
+    pub max_bytes: Option<u64>,
+    handle: Capture<Dead>,
+    savefile: Option<Savefile>,
+    index: i32,
+    part: u32,
+    written: u64
+}
+
+impl SavefileWriter {
+    pub fn new(linktype: Linktype, precision: Precision, folder: &str, filename: &str, max_bytes: Option<u64>) -> Self {
+        // The precision must match the capture one, otherwise timestamps are scaled wrongly
+        let handle = match Capture::dead_with_precision(linktype, precision) {
+            Err(why) => panic!("couldn't set up the savefile handle: {}", why),
+            Ok(handle) => handle,
+        };
+
+        Self {
+            folder: folder.to_string(),
+            filename: filename.to_string(),
+            max_bytes,
+            handle,
+            savefile: None,
+            index: -1,
+            part: 0,
+            written: 0
+        }
+    }
+
+    fn open(&mut self) {
+        let pathname = match self.part {
+            0 => format!("{}/{}-{}.pcap", self.folder, self.filename, self.index),
+            part => format!("{}/{}-{}.{}.pcap", self.folder, self.filename, self.index, part)
+        };
+        let savefile = match self.handle.savefile(&pathname) {
+            Err(why) => panic!("couldn't create {}: {}", pathname, why),
+            Ok(savefile) => savefile,
+        };
+        self.savefile = Some(savefile);
+        self.written = PCAP_FILE_HEADER_LEN;
+    }
+
+    /// Write the frame in the savefile of the report `index`, rotating the file when
+    /// the report index changes or when the maximum size would be exceeded
+    pub fn write(&mut self, packet: &Packet, index: i32) {
+        let record_len = PCAP_RECORD_HEADER_LEN + packet.header.caplen as u64;
+
+        if index != self.index {
+            self.close();
+            self.index = index;
+            self.part = 0;
+            self.open();
+        } else if let Some(max) = self.max_bytes {
+            if self.written > PCAP_FILE_HEADER_LEN && self.written + record_len > max {
+                self.close();
+                self.part += 1;
+                self.open();
+            }
+        }
+
+        if let Some(savefile) = &mut self.savefile {
+            savefile.write(packet);
+            self.written += record_len;
+        }
+    }
+
+    pub fn close(&mut self) {
+        if let Some(mut savefile) = self.savefile.take() {
+            savefile.flush().unwrap();
+        }
+    }
+}
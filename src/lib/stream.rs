@@ -0,0 +1,523 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{create_dir_all, File, OpenOptions};
+use std::io::Write;
+use std::net::IpAddr;
+
+use chrono::{DateTime, Duration, Local};
+
+use crate::connection::ConnectionKey;
+use crate::parser::Packet;
+use crate::utils::join_values;
+
+// Out of order bytes kept for each direction before giving up on the missing ones
+const MAX_PENDING_BYTES: usize = 1024 * 1024;
+// Sequence numbers more than 2^31 ahead are actually behind
+const HALF_SEQ_SPACE: u32 = 1 << 31;
+
+/// Whether `a` comes before `b`, sequence numbers (and the offsets from the ISN) wrap around
+fn seq_before(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) < 0
+}
+
+/// Contiguous bytes of one direction of a TCP connection, ready for the analyzers
+#[derive(Debug)]
+pub struct StreamData {
+    pub connection: ConnectionKey,
+    pub source: (IpAddr, u16),
+    /// Sent by the endpoint that opened the connection
+    pub from_initiator: bool,
+    pub timestamp: DateTime<Local>,
+    /// Some bytes before these were never received and have been skipped
+    pub gap: bool,
+    pub data: Vec<u8>
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct StreamKey {
+    connection: ConnectionKey,
+    source: (IpAddr, u16)
+}
+
+struct StreamBuffer {
+    /// Sequence number of the first byte of the stream
+    isn: Option<u32>,
+    /// Offset (from `isn`) of the next byte to deliver
+    next: u32,
+    pending: BTreeMap<u32, Vec<u8>>,
+    pending_bytes: usize,
+    last_seen: DateTime<Local>
+}
+
+impl StreamBuffer {
+    /// Keep a segment that arrived ahead of the next byte, without the bytes other waiting
+    /// segments already hold: the first copy wins
+    fn queue(&mut self, offset: u32, segment: &[u8]) {
+        let end = offset.wrapping_add(segment.len() as u32);
+        let mut held: Vec<(u32, u32)> = self.pending.iter()
+            .map(|(&start, data)| (start, start.wrapping_add(data.len() as u32)))
+            .filter(|&(start, stop)| seq_before(start, end) && seq_before(offset, stop))
+            .collect();
+        // The map is ordered by offset, not by sequence: the segments may sit across the wrap
+        held.sort_by_key(|&(start, _)| start.wrapping_sub(offset) as i32);
+
+        let mut start = offset;
+        for (held_start, held_stop) in held.into_iter().chain(std::iter::once((end, end))) {
+            if seq_before(start, held_start) {
+                let piece = &segment[start.wrapping_sub(offset) as usize..held_start.wrapping_sub(offset) as usize];
+                self.pending.insert(start, piece.to_vec());
+                self.pending_bytes += piece.len();
+            }
+            if seq_before(start, held_stop) {
+                start = held_stop;
+            }
+        }
+    }
+
+    /// Offset of a pending segment starting at or before the next byte, if any
+    fn due(&self) -> Option<u32> {
+        // The half of the offsets behind the next byte, split in two ranges when it wraps around
+        let behind = self.next.wrapping_sub(HALF_SEQ_SPACE);
+        let due = match behind <= self.next {
+            true => self.pending.range(behind..=self.next).next(),
+            false => self.pending.range(behind..).next().or_else(|| self.pending.range(..=self.next).next())
+        };
+        due.map(|(&offset, _)| offset)
+    }
+
+    /// Offset of the first pending segment ahead of the next byte, if any
+    fn first_ahead(&self) -> Option<u32> {
+        self.pending.keys().copied().min_by_key(|offset| offset.wrapping_sub(self.next))
+    }
+
+    /// Move the pending segments that became contiguous to `data`
+    fn drain(&mut self, data: &mut Vec<u8>) {
+        while let Some(offset) = self.due() {
+            let segment = self.pending.remove(&offset).unwrap();
+            self.pending_bytes -= segment.len();
+            // Partially or entirely already delivered
+            let skip = self.next.wrapping_sub(offset) as usize;
+            if skip < segment.len() {
+                data.extend_from_slice(&segment[skip..]);
+                self.next = offset.wrapping_add(segment.len() as u32);
+            }
+        }
+    }
+}
+
+/// Memory bounded reassembly of the TCP streams, both directions of a connection are ordered
+/// separately. Retransmitted bytes are delivered only once, the first copy wins on overlaps.
+pub struct StreamTable {
+    pub timeout: Duration,
+    pub max_streams: usize,
+    /// Out of order bytes kept for all the streams together
+    pub max_bytes: usize,
+    /// Missing bytes skipped so far because too much data was waiting behind them
+    pub gaps: u32,
+    buffered_bytes: usize,
+    buffers: HashMap<StreamKey, StreamBuffer>
+}
+
+impl StreamTable {
+    pub fn new(timeout: Duration, max_streams: usize, max_bytes: usize) -> Self {
+        StreamTable {
+            timeout,
+            max_streams,
+            max_bytes,
+            gaps: 0,
+            buffered_bytes: 0,
+            buffers: HashMap::new()
+        }
+    }
+
+    fn remove(&mut self, key: &StreamKey) {
+        if let Some(buffer) = self.buffers.remove(key) {
+            self.buffered_bytes -= buffer.pending_bytes;
+        }
+    }
+
+    /// Drop the stream idle for the longest time, other than `key`. Returns false if there is none
+    fn drop_oldest(&mut self, key: &StreamKey) -> bool {
+        let oldest = self.buffers.iter()
+            .filter(|(other, _)| *other != key)
+            .min_by_key(|(_, buffer)| buffer.last_seen)
+            .map(|(key, _)| key.clone());
+        match oldest {
+            Some(oldest) => {
+                self.remove(&oldest);
+                true
+            },
+            None => false
+        }
+    }
+
+    /// Add a segment to its stream, returning the bytes that became contiguous (if any)
+    pub fn process(&mut self, packet: &Packet, connection: &ConnectionKey, from_initiator: bool, payload: &[u8]) -> Option<StreamData> {
+        let flags = packet.tcp_flags?;
+        let seq = packet.tcp_seq?;
+        let key = StreamKey {
+            connection: connection.clone(),
+            source: (packet.src_addr, packet.src_port?)
+        };
+
+        // Pure ACKs (and what follows the close) don't open a stream
+        if !self.buffers.contains_key(&key) && payload.is_empty() && !flags.syn {
+            return None;
+        }
+        if !self.buffers.contains_key(&key) && self.buffers.len() >= self.max_streams {
+            // Make room dropping the stream idle for the longest time
+            self.drop_oldest(&key);
+        }
+
+        let buffer = self.buffers.entry(key.clone()).or_insert_with(|| StreamBuffer {
+            isn: None,
+            next: 0,
+            pending: BTreeMap::new(),
+            pending_bytes: 0,
+            last_seen: packet.timestamp
+        });
+        buffer.last_seen = packet.timestamp;
+        let queued_bytes = buffer.pending_bytes;
+
+        // The SYN takes a sequence number, data starts right after it
+        let data_seq = if flags.syn { seq.wrapping_add(1) } else { seq };
+        if flags.syn {
+            buffer.isn = Some(data_seq);
+            buffer.next = 0;
+            buffer.pending.clear();
+            buffer.pending_bytes = 0;
+        }
+        if payload.is_empty() {
+            self.buffered_bytes = self.buffered_bytes + buffer.pending_bytes - queued_bytes;
+            return None;
+        }
+        // Picked up midstream, the stream starts with the first segment seen
+        let isn = *buffer.isn.get_or_insert(data_seq);
+
+        let mut offset = data_seq.wrapping_sub(isn);
+        let mut segment = payload;
+        if seq_before(offset, buffer.next) {
+            // Starts before the next expected byte: retransmission or overlap
+            let behind = buffer.next.wrapping_sub(offset) as usize;
+            if behind >= segment.len() {
+                self.buffered_bytes = self.buffered_bytes + buffer.pending_bytes - queued_bytes;
+                return None;
+            }
+            segment = &segment[behind..];
+            offset = buffer.next;
+        }
+
+        let mut data = Vec::new();
+        let mut gap = false;
+        if offset == buffer.next {
+            data.extend_from_slice(segment);
+            buffer.next = offset.wrapping_add(segment.len() as u32);
+        } else {
+            buffer.queue(offset, segment);
+            if buffer.pending_bytes > MAX_PENDING_BYTES {
+                // The missing bytes are not coming anymore, go on from the first segment waiting
+                if let Some(first) = buffer.first_ahead() {
+                    buffer.next = first;
+                    self.gaps += 1;
+                    gap = true;
+                }
+            }
+        }
+        buffer.drain(&mut data);
+        self.buffered_bytes = self.buffered_bytes + buffer.pending_bytes - queued_bytes;
+
+        // Past the budget of the whole table the other streams are forgotten, oldest first
+        while self.buffered_bytes > self.max_bytes && self.drop_oldest(&key) {}
+
+        if data.is_empty() {
+            return None;
+        }
+        Some(StreamData {
+            connection: key.connection,
+            source: key.source,
+            from_initiator,
+            timestamp: packet.timestamp,
+            gap,
+            data
+        })
+    }
+
+    /// Forget both directions of a connection that is over
+    pub fn close(&mut self, connection: &ConnectionKey) {
+        let mut buffered_bytes = self.buffered_bytes;
+        self.buffers.retain(|key, buffer| {
+            let open = &key.connection != connection;
+            if !open {
+                buffered_bytes -= buffer.pending_bytes;
+            }
+            open
+        });
+        self.buffered_bytes = buffered_bytes;
+    }
+
+    /// Drop the streams idle for longer than the timeout
+    pub fn expire(&mut self, now: DateTime<Local>) {
+        let timeout = self.timeout;
+        let mut buffered_bytes = self.buffered_bytes;
+        self.buffers.retain(|_, buffer| {
+            let alive = now - buffer.last_seen <= timeout;
+            if !alive {
+                buffered_bytes -= buffer.pending_bytes;
+            }
+            alive
+        });
+        self.buffered_bytes = buffered_bytes;
+    }
+}
+
+/// Writes the reassembled bytes of every connection in its own files, one per direction. Files
+/// stay open until the connection is over or idle for longer than the timeout
+pub struct StreamDumper {
+    pub folder: String,
+    pub timeout: Duration,
+    /// Files kept open at the same time, the least recently written is closed first
+    pub max_files: usize,
+    /// Open file and time of the last write, by connection and direction (true from the initiator)
+    files: HashMap<(ConnectionKey, bool), (File, DateTime<Local>)>
+}
+
+impl StreamDumper {
+    pub fn new(folder: &str, timeout: Duration, max_files: usize) -> Self {
+        if let Err(why) = create_dir_all(folder) {
+            panic!("couldn't create {}: {}", folder, why);
+        }
+
+        StreamDumper {
+            folder: folder.to_string(),
+            timeout,
+            max_files,
+            files: HashMap::new()
+        }
+    }
+
+    fn pathname(&self, connection: &ConnectionKey, from_initiator: bool) -> String {
+        let vlan = match connection.vlan.is_empty() {
+            true => String::new(),
+            false => format!("-vlan{}", join_values(&connection.vlan, "."))
+        };
+        let name = format!("{}{}-{}.{}-{}.{}",
+            connection.interface,
+            vlan,
+            connection.low.0,
+            connection.low.1,
+            connection.high.0,
+            connection.high.1
+        );
+        // IPv6 colons (and whatever else the interface name contains) are not welcome everywhere
+        let name: String = name.chars()
+            .map(|c| if c.is_ascii_alphanumeric() || c == '.' || c == '-' { c } else { '_' })
+            .collect();
+        // Client (the endpoint that opened the connection) to server, or back
+        let direction = if from_initiator { "c2s" } else { "s2c" };
+        format!("{}/{}-{}.stream", self.folder, name, direction)
+    }
+
+    pub fn write(&mut self, stream: &StreamData) {
+        let key = (stream.connection.clone(), stream.from_initiator);
+        if !self.files.contains_key(&key) {
+            if self.files.len() >= self.max_files {
+                // Closed, the file is appended to if the stream goes on
+                let oldest = self.files.iter()
+                    .min_by_key(|(_, (_, last_write))| *last_write)
+                    .map(|(key, _)| key.clone());
+                if let Some(oldest) = oldest {
+                    self.files.remove(&oldest);
+                }
+            }
+            let pathname = self.pathname(&stream.connection, stream.from_initiator);
+            let file = match OpenOptions::new().create(true).append(true).open(&pathname) {
+                Err(why) => panic!("couldn't open {}: {}", pathname, why),
+                Ok(file) => file,
+            };
+            self.files.insert(key.clone(), (file, stream.timestamp));
+        }
+
+        let (file, last_write) = self.files.get_mut(&key).unwrap();
+        file.write_all(&stream.data).unwrap();
+        *last_write = stream.timestamp;
+    }
+
+    /// Close the files of both directions of a connection that is over
+    pub fn close(&mut self, connection: &ConnectionKey) {
+        self.files.retain(|(key, _), _| key != connection);
+    }
+
+    /// Close the files not written for longer than the timeout
+    pub fn expire(&mut self, now: DateTime<Local>) {
+        let timeout = self.timeout;
+        self.files.retain(|_, (_, last_write)| now - *last_write <= timeout);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{read, remove_dir_all};
+
+    use pcap::Linktype;
+
+    use crate::parser::{Endpoints, Frame};
+    use crate::tcp::TcpFlags;
+
+    const CLIENT: (&str, u16) = ("192.0.2.1", 40000);
+    const SERVER: (&str, u16) = ("192.0.2.2", 80);
+
+    fn connection() -> ConnectionKey {
+        ConnectionKey::new("eth0", &[], (CLIENT.0.parse().unwrap(), CLIENT.1), (SERVER.0.parse().unwrap(), SERVER.1))
+    }
+
+    fn segment(seq: u32, syn: bool, payload: &[u8]) -> Packet {
+        let wire_len = 54 + payload.len() as u32;
+        let frame = Frame { caplen: wire_len, wire_len, ..Frame::fixture(Linktype::ETHERNET, Vec::new()) };
+        let endpoints = Endpoints::with_ports(CLIENT.0.parse().unwrap(), CLIENT.1, SERVER.0.parse().unwrap(), SERVER.1);
+        let mut packet = Packet::new(&frame, endpoints, payload.len() as u32, "TCP".to_string());
+        packet.tcp_flags = Some(TcpFlags { syn, ack: !syn, ..TcpFlags::default() });
+        packet.tcp_seq = Some(seq);
+        packet
+    }
+
+    fn process(table: &mut StreamTable, seq: u32, syn: bool, payload: &[u8]) -> Option<Vec<u8>> {
+        table.process(&segment(seq, syn, payload), &connection(), true, payload).map(|stream| stream.data)
+    }
+
+    #[test]
+    fn in_order_segments() {
+        let mut table = StreamTable::new(Duration::seconds(120), 16, 16 * 1024 * 1024);
+
+        assert_eq!(process(&mut table, 1000, true, b""), None);
+        assert_eq!(process(&mut table, 1001, false, b"hello").as_deref(), Some(&b"hello"[..]));
+        assert_eq!(process(&mut table, 1006, false, b" world").as_deref(), Some(&b" world"[..]));
+    }
+
+    #[test]
+    fn out_of_order_segments_wait_for_the_missing_ones() {
+        let mut table = StreamTable::new(Duration::seconds(120), 16, 16 * 1024 * 1024);
+
+        process(&mut table, 1000, true, b"");
+        assert_eq!(process(&mut table, 1006, false, b" world"), None);
+        assert_eq!(process(&mut table, 1001, false, b"hello").as_deref(), Some(&b"hello world"[..]));
+    }
+
+    #[test]
+    fn retransmissions_are_delivered_once() {
+        let mut table = StreamTable::new(Duration::seconds(120), 16, 16 * 1024 * 1024);
+
+        process(&mut table, 1000, true, b"");
+        process(&mut table, 1001, false, b"hello");
+        assert_eq!(process(&mut table, 1001, false, b"hello"), None);
+    }
+
+    #[test]
+    fn overlaps_keep_the_first_copy() {
+        let mut table = StreamTable::new(Duration::seconds(120), 16, 16 * 1024 * 1024);
+
+        process(&mut table, 1000, true, b"");
+        process(&mut table, 1001, false, b"hello");
+        // Starts before the next expected byte, only the new bytes are delivered
+        assert_eq!(process(&mut table, 1003, false, b"LLO W").as_deref(), Some(&b" W"[..]));
+
+        // Overlapping segments waiting for the same hole, "wo" is all the second one adds
+        process(&mut table, 1017, false, b"RLD!!");
+        process(&mut table, 1015, false, b"world");
+        assert_eq!(process(&mut table, 1008, false, b"orld, w").as_deref(), Some(&b"orld, wwoRLD!!"[..]));
+    }
+
+    #[test]
+    fn sequence_numbers_wrap() {
+        let mut table = StreamTable::new(Duration::seconds(120), 16, 16 * 1024 * 1024);
+
+        process(&mut table, 0xffff_fffd, true, b"");
+        assert_eq!(process(&mut table, 0xffff_fffe, false, b"ab").as_deref(), Some(&b"ab"[..]));
+        assert_eq!(process(&mut table, 0, false, b"cd").as_deref(), Some(&b"cd"[..]));
+    }
+
+    #[test]
+    fn offsets_wrap_after_4_gb() {
+        let mut table = StreamTable::new(Duration::seconds(120), 16, 16 * 1024 * 1024);
+        process(&mut table, 999, true, b"");
+        // As if 4 GB had been delivered already
+        table.buffers.values_mut().next().unwrap().next = u32::MAX - 1;
+
+        // Ahead of the next byte, past the wrap, then across it
+        assert_eq!(process(&mut table, 1000 + 1, false, b"de"), None);
+        assert_eq!(process(&mut table, 1000 + 4, false, b"gh"), None);
+        assert_eq!(process(&mut table, 1000 + 2, false, b"efgh"), None);
+        assert_eq!(process(&mut table, 1000u32.wrapping_add(u32::MAX - 1), false, b"abc"), Some(b"abcdefgh".to_vec()));
+        assert_eq!(table.buffered_bytes, 0);
+    }
+
+    #[test]
+    fn retransmission_from_before_the_isn() {
+        let mut table = StreamTable::new(Duration::seconds(120), 16, 16 * 1024 * 1024);
+
+        process(&mut table, 999, true, b"");
+        // The SYN is seq 999, data starts at 1000
+        assert_eq!(process(&mut table, 997, false, b"SYNabc"), Some(b"abc".to_vec()));
+        assert_eq!(process(&mut table, 999, false, b"Nabcdef"), Some(b"def".to_vec()));
+    }
+
+    #[test]
+    fn missing_bytes_are_skipped_past_the_pending_limit() {
+        let mut table = StreamTable::new(Duration::seconds(120), 16, 16 * 1024 * 1024);
+
+        process(&mut table, 1000, true, b"");
+        let pending = vec![7; MAX_PENDING_BYTES + 1];
+        let stream = table.process(&segment(1011, false, &pending), &connection(), true, &pending).unwrap();
+
+        assert!(stream.gap);
+        assert_eq!(stream.data.len(), pending.len());
+        assert_eq!(table.gaps, 1);
+    }
+
+    #[test]
+    fn byte_budget_drops_the_oldest_streams() {
+        let mut table = StreamTable::new(Duration::seconds(120), 16, 8);
+        let other = ConnectionKey::new("eth0", &[], (CLIENT.0.parse().unwrap(), 40001), (SERVER.0.parse().unwrap(), SERVER.1));
+        let mut early = segment(1000, true, b"");
+        early.src_port = Some(40001);
+        early.timestamp = Local::now() - Duration::seconds(1);
+        table.process(&early, &other, true, b"");
+        early.tcp_flags = Some(TcpFlags { ack: true, ..TcpFlags::default() });
+        early.tcp_seq = Some(1006);
+        table.process(&early, &other, true, b"later");
+        assert_eq!(table.buffered_bytes, 5);
+
+        process(&mut table, 2000, true, b"");
+        assert_eq!(process(&mut table, 2006, false, b"world"), None);
+
+        // The stream of the other connection is gone with its bytes
+        assert_eq!(table.buffers.len(), 1);
+        assert_eq!(table.buffered_bytes, 5);
+        assert_eq!(process(&mut table, 2001, false, b"hello"), Some(b"helloworld".to_vec()));
+        assert_eq!(table.buffered_bytes, 0);
+    }
+
+    #[test]
+    fn dumper_writes_each_direction_in_its_own_file() {
+        let folder = std::env::temp_dir().join(format!("swiffer-streams-{}", std::process::id()));
+        let mut dumper = StreamDumper::new(folder.to_str().unwrap(), Duration::seconds(120), 1);
+        let stream = |from_initiator: bool, data: &[u8]| StreamData {
+            connection: connection(),
+            source: (CLIENT.0.parse().unwrap(), CLIENT.1),
+            from_initiator,
+            timestamp: Local::now(),
+            gap: false,
+            data: data.to_vec()
+        };
+
+        dumper.write(&stream(true, b"GET / "));
+        // Closes the request file, appended to afterwards
+        dumper.write(&stream(false, b"HTTP/1.1 200 OK"));
+        dumper.write(&stream(true, b"HTTP/1.1"));
+        dumper.close(&connection());
+
+        let name = "eth0-192.0.2.1.40000-192.0.2.2.80";
+        assert_eq!(read(folder.join(format!("{}-c2s.stream", name))).unwrap(), b"GET / HTTP/1.1");
+        assert_eq!(read(folder.join(format!("{}-s2c.stream", name))).unwrap(), b"HTTP/1.1 200 OK");
+        remove_dir_all(folder).unwrap();
+    }
+}
@@ -0,0 +1,146 @@
+use std::fmt;
+
+use pktparse::tcp::TcpHeader;
+
+/// Control bits of a TCP segment
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct TcpFlags {
+    pub urg: bool,
+    pub ack: bool,
+    pub psh: bool,
+    pub rst: bool,
+    pub syn: bool,
+    pub fin: bool
+}
+
+impl TcpFlags {
+    pub fn from_header(header: &TcpHeader) -> Self {
+        TcpFlags {
+            urg: header.flag_urg,
+            ack: header.flag_ack,
+            psh: header.flag_psh,
+            rst: header.flag_rst,
+            syn: header.flag_syn,
+            fin: header.flag_fin
+        }
+    }
+}
+
+impl fmt::Display for TcpFlags {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let flags = [
+            (self.syn, "SYN"),
+            (self.fin, "FIN"),
+            (self.rst, "RST"),
+            (self.psh, "PSH"),
+            (self.ack, "ACK"),
+            (self.urg, "URG")
+        ];
+        let set: Vec<&str> = flags.iter().filter(|(set, _)| *set).map(|(_, name)| *name).collect();
+        write!(f, "{}", set.join(","))
+    }
+}
+
+/// Options found in a TCP header
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct TcpOptions {
+    pub mss: Option<u16>,
+    pub window_scale: Option<u8>,
+    pub sack_permitted: bool,
+    /// Left and right edges of the selective acknowledgment blocks
+    pub sack_blocks: Vec<(u32, u32)>,
+    /// Timestamp value and echo reply
+    pub timestamp: Option<(u32, u32)>
+}
+
+fn read_u32(bytes: &[u8]) -> u32 {
+    u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
+
+/// Parse the options of a TCP header (the bytes between the fixed header and the data offset).
+/// Unknown options are skipped, parsing stops at the first malformed one.
+pub fn parse_tcp_options(options: &[u8]) -> TcpOptions {
+    let mut parsed = TcpOptions::default();
+    let mut offset = 0;
+
+    while let Some(&kind) = options.get(offset) {
+        match kind {
+            // End of Option List
+            0 => break,
+            // No-Operation
+            1 => {
+                offset += 1;
+                continue;
+            },
+            _ => {}
+        }
+
+        let length = match options.get(offset + 1) {
+            Some(&length) if length >= 2 => length as usize,
+            _ => break
+        };
+        let value = match options.get(offset + 2..offset + length) {
+            Some(value) => value,
+            None => break
+        };
+
+        match (kind, value.len()) {
+            (2, 2) => parsed.mss = Some(u16::from_be_bytes([value[0], value[1]])),
+            (3, 1) => parsed.window_scale = Some(value[0]),
+            (4, 0) => parsed.sack_permitted = true,
+            (5, len) if len % 8 == 0 => {
+                parsed.sack_blocks = value.chunks(8)
+                    .map(|block| (read_u32(&block[..4]), read_u32(&block[4..])))
+                    .collect();
+            },
+            (8, 8) => parsed.timestamp = Some((read_u32(&value[..4]), read_u32(&value[4..]))),
+            _ => {}
+        }
+        offset += length;
+    }
+
+    parsed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn options_of_a_syn() {
+        // MSS 1460, SACK permitted, timestamps, NOP, window scale 7 (Linux SYN)
+        let options = [2, 4, 0x05, 0xb4, 4, 2, 8, 10, 0, 0, 0, 1, 0, 0, 0, 0, 1, 3, 3, 7];
+        let parsed = parse_tcp_options(&options);
+
+        assert_eq!(parsed.mss, Some(1460));
+        assert!(parsed.sack_permitted);
+        assert_eq!(parsed.timestamp, Some((1, 0)));
+        assert_eq!(parsed.window_scale, Some(7));
+        assert!(parsed.sack_blocks.is_empty());
+    }
+
+    #[test]
+    fn sack_blocks() {
+        let options = [1, 1, 5, 18, 0, 0, 0, 10, 0, 0, 0, 20, 0, 0, 0, 30, 0, 0, 0, 40];
+        let parsed = parse_tcp_options(&options);
+
+        assert_eq!(parsed.sack_blocks, [(10, 20), (30, 40)]);
+    }
+
+    #[test]
+    fn parsing_stops_at_end_of_list_and_malformed_options() {
+        assert_eq!(parse_tcp_options(&[0, 2, 4, 0x05, 0xb4]), TcpOptions::default());
+        // Length below 2 and length past the end
+        assert_eq!(parse_tcp_options(&[2, 1, 0x05, 0xb4]), TcpOptions::default());
+        assert_eq!(parse_tcp_options(&[3, 3, 7, 2, 4, 0x05]).window_scale, Some(7));
+        assert_eq!(parse_tcp_options(&[3, 3, 7, 2, 4, 0x05]).mss, None);
+        // Wrong length for the kind, skipped
+        assert_eq!(parse_tcp_options(&[2, 3, 0x05, 3, 3, 7]), TcpOptions { window_scale: Some(7), ..TcpOptions::default() });
+    }
+
+    #[test]
+    fn flags_display() {
+        let flags = TcpFlags { syn: true, ack: true, ..TcpFlags::default() };
+        assert_eq!(flags.to_string(), "SYN,ACK");
+    }
+}
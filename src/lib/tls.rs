@@ -0,0 +1,848 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::net::SocketAddr;
+
+use chrono::{DateTime, Duration, Local};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+use crate::certificate::{parse_certificate, CertificateInfo};
+use crate::connection::ConnectionKey;
+use crate::stream::StreamData;
+
+// Handshake bytes kept for each direction, enough for the usual certificate chains
+const MAX_HANDSHAKE_LEN: usize = 64 * 1024;
+// Largest record allowed (TLSCiphertext), anything bigger is not TLS
+const MAX_RECORD_LEN: usize = 16384 + 2048;
+
+const RECORD_HANDSHAKE: u8 = 22;
+const HANDSHAKE_CLIENT_HELLO: u8 = 1;
+const HANDSHAKE_SERVER_HELLO: u8 = 2;
+const HANDSHAKE_CERTIFICATE: u8 = 11;
+
+const EXTENSION_SERVER_NAME: u16 = 0;
+const EXTENSION_SUPPORTED_GROUPS: u16 = 10;
+const EXTENSION_EC_POINT_FORMATS: u16 = 11;
+const EXTENSION_SIGNATURE_ALGORITHMS: u16 = 13;
+const EXTENSION_ALPN: u16 = 16;
+const EXTENSION_SUPPORTED_VERSIONS: u16 = 43;
+
+/// Big endian reader over a TLS structure, every read fails past the end
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Reader { data, pos: 0 }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.pos >= self.data.len()
+    }
+
+    fn bytes(&mut self, len: usize) -> Option<&'a [u8]> {
+        let bytes = self.data.get(self.pos..self.pos.checked_add(len)?)?;
+        self.pos += len;
+        Some(bytes)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        Some(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        let bytes = self.bytes(2)?;
+        Some(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u24(&mut self) -> Option<usize> {
+        let bytes = self.bytes(3)?;
+        Some((bytes[0] as usize) << 16 | (bytes[1] as usize) << 8 | bytes[2] as usize)
+    }
+
+    /// Sub-structure prefixed by its length, on `len_bytes` bytes
+    fn vector(&mut self, len_bytes: usize) -> Option<Reader<'a>> {
+        let len = match len_bytes {
+            1 => self.u8()? as usize,
+            2 => self.u16()? as usize,
+            _ => self.u24()?
+        };
+        Some(Reader::new(self.bytes(len)?))
+    }
+
+    fn u16_list(&mut self) -> Option<Vec<u16>> {
+        let mut list = Vec::new();
+        while !self.is_empty() {
+            list.push(self.u16()?);
+        }
+        Some(list)
+    }
+}
+
+/// GREASE values (RFC 8701) are random placeholders, left out of the fingerprints
+fn is_grease(value: u16) -> bool {
+    value & 0x0f0f == 0x0a0a && value >> 8 == value & 0xff
+}
+
+fn without_grease(values: &[u16]) -> Vec<u16> {
+    values.iter().copied().filter(|value| !is_grease(*value)).collect()
+}
+
+/// Content of a ClientHello, in the order the client sent it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientHello {
+    /// Version field of the ClientHello (0x0303 for TLS 1.2 and 1.3)
+    pub version: u16,
+    /// Content of the supported_versions extension
+    pub supported_versions: Vec<u16>,
+    pub cipher_suites: Vec<u16>,
+    pub extensions: Vec<u16>,
+    pub server_name: Option<String>,
+    pub alpn: Vec<String>,
+    pub supported_groups: Vec<u16>,
+    pub ec_point_formats: Vec<u8>,
+    pub signature_algorithms: Vec<u16>
+}
+
+impl ClientHello {
+    /// Highest version offered by the client
+    pub fn max_version(&self) -> u16 {
+        without_grease(&self.supported_versions).into_iter().max().unwrap_or(self.version)
+    }
+
+    /// The string hashed by JA3: version, ciphers, extensions, groups and point formats
+    pub fn ja3_string(&self) -> String {
+        let join = |values: Vec<String>| values.join("-");
+        format!("{},{},{},{},{}",
+            self.version,
+            join(without_grease(&self.cipher_suites).iter().map(|value| value.to_string()).collect()),
+            join(without_grease(&self.extensions).iter().map(|value| value.to_string()).collect()),
+            join(without_grease(&self.supported_groups).iter().map(|value| value.to_string()).collect()),
+            join(self.ec_point_formats.iter().map(|value| value.to_string()).collect())
+        )
+    }
+
+    /// JA3 fingerprint, the MD5 of `ja3_string`
+    pub fn ja3(&self) -> String {
+        format!("{:x}", md5::compute(self.ja3_string()))
+    }
+
+    /// JA4 fingerprint (TCP): version, SNI, counts and ALPN, then the truncated SHA256 of
+    /// the sorted ciphers and of the sorted extensions followed by the signature algorithms
+    pub fn ja4(&self) -> String {
+        let version = match self.max_version() {
+            0x0304 => "13",
+            0x0303 => "12",
+            0x0302 => "11",
+            0x0301 => "10",
+            0x0300 => "s3",
+            _ => "00"
+        };
+        let sni = match self.server_name {
+            Some(_) => 'd',
+            None => 'i'
+        };
+        let alpn = match self.alpn.first().map(|alpn| alpn.as_bytes()) {
+            Some([first, .., last]) if first.is_ascii_alphanumeric() && last.is_ascii_alphanumeric() => format!("{}{}", *first as char, *last as char),
+            Some([first, .., last]) => format!("{:x}{:x}", first >> 4, last & 0x0f),
+            Some([single]) => format!("{}{}", *single as char, *single as char),
+            _ => "00".to_string()
+        };
+
+        let mut ciphers = without_grease(&self.cipher_suites);
+        ciphers.sort_unstable();
+        let mut extensions = without_grease(&self.extensions);
+        let extension_count = extensions.len();
+        extensions.retain(|extension| *extension != EXTENSION_SERVER_NAME && *extension != EXTENSION_ALPN);
+        extensions.sort_unstable();
+
+        let hex_list = |values: &[u16]| values.iter().map(|value| format!("{:04x}", value)).collect::<Vec<String>>().join(",");
+        let truncated_hash = |text: String| match text.is_empty() {
+            true => "000000000000".to_string(),
+            false => format!("{:x}", Sha256::digest(text.as_bytes()))[..12].to_string()
+        };
+        let mut extensions_text = hex_list(&extensions);
+        if !extensions_text.is_empty() && !self.signature_algorithms.is_empty() {
+            extensions_text = format!("{}_{}", extensions_text, hex_list(&without_grease(&self.signature_algorithms)));
+        }
+
+        format!("t{}{}{:02}{:02}{}_{}_{}",
+            version,
+            sni,
+            ciphers.len().min(99),
+            extension_count.min(99),
+            alpn,
+            truncated_hash(hex_list(&ciphers)),
+            truncated_hash(extensions_text)
+        )
+    }
+}
+
+/// Parse the body of a ClientHello handshake message
+pub fn parse_client_hello(body: &[u8]) -> Option<ClientHello> {
+    let mut reader = Reader::new(body);
+    let version = reader.u16()?;
+    let _random = reader.bytes(32)?;
+    let _session_id = reader.vector(1)?;
+    let cipher_suites = reader.vector(2)?.u16_list()?;
+    let _compression_methods = reader.vector(1)?;
+
+    let mut hello = ClientHello {
+        version,
+        supported_versions: Vec::new(),
+        cipher_suites,
+        extensions: Vec::new(),
+        server_name: None,
+        alpn: Vec::new(),
+        supported_groups: Vec::new(),
+        ec_point_formats: Vec::new(),
+        signature_algorithms: Vec::new()
+    };
+    // Extensions are optional before TLS 1.2
+    if reader.is_empty() {
+        return Some(hello);
+    }
+
+    let mut extensions = reader.vector(2)?;
+    while !extensions.is_empty() {
+        let extension = extensions.u16()?;
+        let mut data = extensions.vector(2)?;
+        hello.extensions.push(extension);
+
+        match extension {
+            EXTENSION_SERVER_NAME => {
+                let mut names = data.vector(2)?;
+                while !names.is_empty() {
+                    let name_type = names.u8()?;
+                    let name = names.vector(2)?;
+                    // host_name is the only type defined
+                    if name_type == 0 {
+                        hello.server_name = Some(String::from_utf8_lossy(name.data).to_string());
+                    }
+                }
+            },
+            EXTENSION_ALPN => {
+                let mut protocols = data.vector(2)?;
+                while !protocols.is_empty() {
+                    hello.alpn.push(String::from_utf8_lossy(protocols.vector(1)?.data).to_string());
+                }
+            },
+            EXTENSION_SUPPORTED_GROUPS => hello.supported_groups = data.vector(2)?.u16_list()?,
+            EXTENSION_EC_POINT_FORMATS => hello.ec_point_formats = data.vector(1)?.data.to_vec(),
+            EXTENSION_SIGNATURE_ALGORITHMS => hello.signature_algorithms = data.vector(2)?.u16_list()?,
+            EXTENSION_SUPPORTED_VERSIONS => hello.supported_versions = data.vector(1)?.u16_list()?,
+            _ => ()
+        }
+    }
+    Some(hello)
+}
+
+pub fn version_name(version: u16) -> String {
+    match version {
+        0x0300 => "SSLv3".to_string(),
+        0x0301 => "TLSv1.0".to_string(),
+        0x0302 => "TLSv1.1".to_string(),
+        0x0303 => "TLSv1.2".to_string(),
+        0x0304 => "TLSv1.3".to_string(),
+        other => format!("0x{:04x}", other)
+    }
+}
+
+/// IANA name of the most common cipher suites, the code for the others
+pub fn cipher_name(cipher_suite: u16) -> String {
+    let name = match cipher_suite {
+        0x000a => "TLS_RSA_WITH_3DES_EDE_CBC_SHA",
+        0x002f => "TLS_RSA_WITH_AES_128_CBC_SHA",
+        0x0035 => "TLS_RSA_WITH_AES_256_CBC_SHA",
+        0x003c => "TLS_RSA_WITH_AES_128_CBC_SHA256",
+        0x003d => "TLS_RSA_WITH_AES_256_CBC_SHA256",
+        0x009c => "TLS_RSA_WITH_AES_128_GCM_SHA256",
+        0x009d => "TLS_RSA_WITH_AES_256_GCM_SHA384",
+        0x009e => "TLS_DHE_RSA_WITH_AES_128_GCM_SHA256",
+        0x009f => "TLS_DHE_RSA_WITH_AES_256_GCM_SHA384",
+        0x1301 => "TLS_AES_128_GCM_SHA256",
+        0x1302 => "TLS_AES_256_GCM_SHA384",
+        0x1303 => "TLS_CHACHA20_POLY1305_SHA256",
+        0x1304 => "TLS_AES_128_CCM_SHA256",
+        0x1305 => "TLS_AES_128_CCM_8_SHA256",
+        0xc009 => "TLS_ECDHE_ECDSA_WITH_AES_128_CBC_SHA",
+        0xc00a => "TLS_ECDHE_ECDSA_WITH_AES_256_CBC_SHA",
+        0xc013 => "TLS_ECDHE_RSA_WITH_AES_128_CBC_SHA",
+        0xc014 => "TLS_ECDHE_RSA_WITH_AES_256_CBC_SHA",
+        0xc023 => "TLS_ECDHE_ECDSA_WITH_AES_128_CBC_SHA256",
+        0xc024 => "TLS_ECDHE_ECDSA_WITH_AES_256_CBC_SHA384",
+        0xc027 => "TLS_ECDHE_RSA_WITH_AES_128_CBC_SHA256",
+        0xc028 => "TLS_ECDHE_RSA_WITH_AES_256_CBC_SHA384",
+        0xc02b => "TLS_ECDHE_ECDSA_WITH_AES_128_GCM_SHA256",
+        0xc02c => "TLS_ECDHE_ECDSA_WITH_AES_256_GCM_SHA384",
+        0xc02f => "TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256",
+        0xc030 => "TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384",
+        0xcca8 => "TLS_ECDHE_RSA_WITH_CHACHA20_POLY1305_SHA256",
+        0xcca9 => "TLS_ECDHE_ECDSA_WITH_CHACHA20_POLY1305_SHA256",
+        other => return format!("0x{:04x}", other)
+    };
+    name.to_string()
+}
+
+/// Version and cipher suite chosen by the server
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ServerHello {
+    /// Negotiated version, from the supported_versions extension if present
+    pub version: u16,
+    pub cipher_suite: u16
+}
+
+/// Parse the body of a ServerHello handshake message
+pub fn parse_server_hello(body: &[u8]) -> Option<ServerHello> {
+    let mut reader = Reader::new(body);
+    let mut version = reader.u16()?;
+    let _random = reader.bytes(32)?;
+    let _session_id = reader.vector(1)?;
+    let cipher_suite = reader.u16()?;
+    let _compression_method = reader.u8()?;
+
+    if !reader.is_empty() {
+        let mut extensions = reader.vector(2)?;
+        while !extensions.is_empty() {
+            let extension = extensions.u16()?;
+            let mut data = extensions.vector(2)?;
+            // TLS 1.3 keeps 1.2 in the version field
+            if extension == EXTENSION_SUPPORTED_VERSIONS {
+                version = data.u16()?;
+            }
+        }
+    }
+    Some(ServerHello { version, cipher_suite })
+}
+
+/// Parse the body of a Certificate handshake message (TLS 1.2 and earlier), the certificates
+/// that can't be decoded are skipped
+pub fn parse_certificates(body: &[u8]) -> Option<Vec<CertificateInfo>> {
+    let mut list = Reader::new(body).vector(3)?;
+    let mut certificates = Vec::new();
+    while !list.is_empty() {
+        if let Some(certificate) = parse_certificate(list.vector(3)?.data) {
+            certificates.push(certificate);
+        }
+    }
+    Some(certificates)
+}
+
+/// Check if the data starts with a TLS (or SSL 3.0) handshake record
+pub fn looks_like_tls(data: &[u8]) -> bool {
+    matches!(data, [RECORD_HANDSHAKE, 3, minor, ..] if *minor <= 4)
+}
+
+/// Handshake message decoded from a stream
+#[derive(Debug, Clone)]
+pub enum TlsHandshake {
+    ClientHello(ClientHello),
+    ServerHello(ServerHello),
+    /// Certificate chain, the server certificate first
+    Certificate(Vec<CertificateInfo>)
+}
+
+struct TlsStream {
+    /// Bytes of the record being received
+    records: Vec<u8>,
+    /// Content of the handshake records, up to a whole message
+    handshake: Vec<u8>,
+    /// Not TLS, or the handshake in clear text is over
+    done: bool,
+    /// Server name asked in the ClientHello sent on this stream
+    server_name: Option<String>,
+    last_seen: DateTime<Local>
+}
+
+impl TlsStream {
+    /// Move the complete records to the handshake buffer, returning the complete messages
+    fn read_records(&mut self) -> Vec<(u8, Vec<u8>)> {
+        let mut messages = Vec::new();
+        loop {
+            // The header is not there yet
+            if self.records.len() < 5 {
+                break;
+            }
+            let content_type = self.records[0];
+            let len = (self.records[3] as usize) << 8 | self.records[4] as usize;
+            if self.records[1] != 3 || len > MAX_RECORD_LEN {
+                self.done = true;
+                break;
+            }
+            if self.records.len() < 5 + len {
+                break;
+            }
+
+            let record: Vec<u8> = self.records.drain(..5 + len).skip(5).collect();
+            match content_type {
+                RECORD_HANDSHAKE => self.handshake.extend_from_slice(&record),
+                // What follows ChangeCipherSpec is encrypted, the same goes for alerts and application data
+                _ => self.done = true
+            }
+
+            // Handshake messages can span records, and a record can hold more messages
+            while let [message_type, a, b, c, ..] = self.handshake.as_slice() {
+                let message_len = (*a as usize) << 16 | (*b as usize) << 8 | *c as usize;
+                if self.handshake.len() < 4 + message_len {
+                    break;
+                }
+                let message_type = *message_type;
+                let body: Vec<u8> = self.handshake.drain(..4 + message_len).skip(4).collect();
+                messages.push((message_type, body));
+            }
+            if self.done || self.handshake.len() > MAX_HANDSHAKE_LEN {
+                self.done = true;
+                break;
+            }
+        }
+        messages
+    }
+}
+
+/// TLS server seen during the session, with what its last handshake showed
+#[derive(Debug, Clone, Serialize)]
+pub struct TlsInventoryEntry {
+    pub server: SocketAddr,
+    /// Server name asked by the client, "-" without SNI
+    pub server_name: String,
+    pub version: String,
+    pub cipher: String,
+    pub subject: String,
+    pub sans: String,
+    pub issuer: String,
+    pub not_before: Option<DateTime<Local>>,
+    pub not_after: Option<DateTime<Local>>,
+    /// "valid", or what is wrong with the certificate ("expired", "self-signed", ...), "-"
+    /// if the certificate was not seen (e.g. encrypted by TLS 1.3)
+    pub status: String,
+    pub sessions: u32,
+    pub first_seen: DateTime<Local>,
+    pub last_seen: DateTime<Local>
+}
+
+impl fmt::Display for TlsInventoryEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let not_before = self.not_before.map(|time| time.to_string()).unwrap_or("-".to_string());
+        let not_after = self.not_after.map(|time| time.to_string()).unwrap_or("-".to_string());
+        write!(f, "| {0: <25}\t| {1: <30}\t| {2: <7}\t| {3: <40}\t| {4: <30}\t| {5}\t| {6: <30}\t| {7: <15}\t| {8: <15}\t| {9: <12}\t| {10: <4}\t| {11: <15}\t| {12: <15}", self.server, self.server_name, self.version, self.cipher, self.subject, self.sans, self.issuer, not_before, not_after, self.status, self.sessions, self.first_seen, self.last_seen)
+    }
+}
+
+/// TLS servers seen during the session, by address and server name
+pub struct TlsInventory {
+    pub max_entries: usize,
+    entries: HashMap<(SocketAddr, String), TlsInventoryEntry>
+}
+
+impl TlsInventory {
+    pub fn new(max_entries: usize) -> Self {
+        TlsInventory {
+            max_entries,
+            entries: HashMap::new()
+        }
+    }
+
+    fn entry(&mut self, server: SocketAddr, server_name: &str, timestamp: DateTime<Local>) -> &mut TlsInventoryEntry {
+        let key = (server, server_name.to_string());
+        if !self.entries.contains_key(&key) && self.entries.len() >= self.max_entries {
+            // Make room dropping the server seen the longest time ago
+            let oldest = self.entries.iter()
+                .min_by_key(|(_, entry)| entry.last_seen)
+                .map(|(key, _)| key.clone());
+            if let Some(oldest) = oldest {
+                self.entries.remove(&oldest);
+            }
+        }
+
+        let entry = self.entries.entry(key).or_insert_with(|| TlsInventoryEntry {
+            server,
+            server_name: server_name.to_string(),
+            version: "-".to_string(),
+            cipher: "-".to_string(),
+            subject: "-".to_string(),
+            sans: String::new(),
+            issuer: "-".to_string(),
+            not_before: None,
+            not_after: None,
+            status: "-".to_string(),
+            sessions: 0,
+            first_seen: timestamp,
+            last_seen: timestamp
+        });
+        entry.last_seen = timestamp;
+        entry
+    }
+
+    /// Record the parameters negotiated in a new session
+    pub fn add_session(&mut self, server: SocketAddr, server_name: &str, hello: &ServerHello, timestamp: DateTime<Local>) {
+        let entry = self.entry(server, server_name, timestamp);
+        entry.version = version_name(hello.version);
+        entry.cipher = cipher_name(hello.cipher_suite);
+        entry.sessions += 1;
+    }
+
+    /// Record the certificate of the server, checking its validity at the time of the handshake
+    pub fn add_certificate(&mut self, server: SocketAddr, server_name: &str, certificate: &CertificateInfo, timestamp: DateTime<Local>) {
+        let mut problems = Vec::new();
+        if certificate.is_expired(timestamp) {
+            problems.push("expired");
+        }
+        if certificate.is_not_yet_valid(timestamp) {
+            problems.push("not-yet-valid");
+        }
+        if certificate.self_signed {
+            problems.push("self-signed");
+        }
+
+        let entry = self.entry(server, server_name, timestamp);
+        entry.subject = certificate.subject.clone();
+        entry.sans = certificate.sans.join(" ");
+        entry.issuer = certificate.issuer.clone();
+        entry.not_before = Some(certificate.not_before);
+        entry.not_after = Some(certificate.not_after);
+        entry.status = match problems.is_empty() {
+            true => "valid".to_string(),
+            false => problems.join(",")
+        };
+    }
+
+    /// Every server of the inventory, ordered by server name
+    pub fn entries(&self) -> Vec<TlsInventoryEntry> {
+        let mut entries: Vec<TlsInventoryEntry> = self.entries.values().cloned().collect();
+        entries.sort_by(|a, b| a.server_name.cmp(&b.server_name).then(a.server.cmp(&b.server)));
+        entries
+    }
+}
+
+/// TLS handshake analyzer fed with the reassembled TCP streams, on any port
+pub struct TlsTable {
+    pub timeout: Duration,
+    pub max_streams: usize,
+    pub inventory: TlsInventory,
+    streams: HashMap<(ConnectionKey, bool), TlsStream>
+}
+
+impl TlsTable {
+    pub fn new(timeout: Duration, max_streams: usize, max_servers: usize) -> Self {
+        TlsTable {
+            timeout,
+            max_streams,
+            inventory: TlsInventory::new(max_servers),
+            streams: HashMap::new()
+        }
+    }
+
+    /// Go on reading the handshake of a direction of a connection, returning the messages
+    /// completed. The ServerHello and the certificates are added to the inventory
+    pub fn process(&mut self, stream: &StreamData) -> Vec<TlsHandshake> {
+        let key = (stream.connection.clone(), stream.from_initiator);
+        if !self.streams.contains_key(&key) {
+            // A stream is TLS only if it starts like TLS, the others are not remembered
+            if stream.gap || !looks_like_tls(&stream.data) {
+                return Vec::new();
+            }
+            if self.streams.len() >= self.max_streams {
+                // Make room dropping the stream idle for the longest time
+                let oldest = self.streams.iter()
+                    .min_by_key(|(_, tls_stream)| tls_stream.last_seen)
+                    .map(|(key, _)| key.clone());
+                if let Some(oldest) = oldest {
+                    self.streams.remove(&oldest);
+                }
+            }
+        }
+        let tls_stream = self.streams.entry(key).or_insert_with(|| TlsStream {
+            records: Vec::new(),
+            handshake: Vec::new(),
+            done: false,
+            server_name: None,
+            last_seen: stream.timestamp
+        });
+        tls_stream.last_seen = stream.timestamp;
+        // Records can't be followed across missing bytes
+        if tls_stream.done || stream.gap {
+            tls_stream.done = true;
+            return Vec::new();
+        }
+
+        tls_stream.records.extend_from_slice(&stream.data);
+        let messages = tls_stream.read_records();
+        if tls_stream.done {
+            tls_stream.records = Vec::new();
+            tls_stream.handshake = Vec::new();
+        }
+
+        let handshakes: Vec<TlsHandshake> = messages.into_iter()
+            .filter_map(|(message_type, body)| match message_type {
+                HANDSHAKE_CLIENT_HELLO => parse_client_hello(&body).map(TlsHandshake::ClientHello),
+                HANDSHAKE_SERVER_HELLO => parse_server_hello(&body).map(TlsHandshake::ServerHello),
+                HANDSHAKE_CERTIFICATE => parse_certificates(&body).map(TlsHandshake::Certificate),
+                _ => None
+            })
+            .collect();
+
+        // The server name is in the stream of the client, the rest in the one of the server
+        let client_key = (stream.connection.clone(), true);
+        let server = SocketAddr::new(stream.source.0, stream.source.1);
+        for handshake in &handshakes {
+            match handshake {
+                TlsHandshake::ClientHello(hello) => {
+                    if let Some(client_stream) = self.streams.get_mut(&client_key) {
+                        client_stream.server_name = hello.server_name.clone();
+                    }
+                },
+                TlsHandshake::ServerHello(hello) => {
+                    let server_name = self.server_name(&client_key);
+                    self.inventory.add_session(server, &server_name, hello, stream.timestamp);
+                },
+                TlsHandshake::Certificate(chain) => {
+                    let server_name = self.server_name(&client_key);
+                    if let Some(certificate) = chain.first() {
+                        self.inventory.add_certificate(server, &server_name, certificate, stream.timestamp);
+                    }
+                }
+            }
+        }
+        handshakes
+    }
+
+    fn server_name(&self, client_key: &(ConnectionKey, bool)) -> String {
+        self.streams.get(client_key)
+            .and_then(|client_stream| client_stream.server_name.clone())
+            .unwrap_or("-".to_string())
+    }
+
+    /// Forget both directions of a connection that is over
+    pub fn close(&mut self, connection: &ConnectionKey) {
+        self.streams.retain(|(key, _), _| key != connection);
+    }
+
+    /// Drop the streams idle for longer than the timeout
+    pub fn expire(&mut self, now: DateTime<Local>) {
+        let timeout = self.timeout;
+        self.streams.retain(|_, stream| now - stream.last_seen <= timeout);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Body of a ClientHello with the given ciphers and extensions (type and data)
+    fn client_hello_body(version: u16, ciphers: &[u16], extensions: &[(u16, Vec<u8>)]) -> Vec<u8> {
+        let mut body = version.to_be_bytes().to_vec();
+        body.extend_from_slice(&[0; 32]);
+        body.push(0);
+        body.extend_from_slice(&((ciphers.len() * 2) as u16).to_be_bytes());
+        for cipher in ciphers {
+            body.extend_from_slice(&cipher.to_be_bytes());
+        }
+        body.extend_from_slice(&[1, 0]);
+
+        let mut data = Vec::new();
+        for (extension, value) in extensions {
+            data.extend_from_slice(&extension.to_be_bytes());
+            data.extend_from_slice(&(value.len() as u16).to_be_bytes());
+            data.extend_from_slice(value);
+        }
+        body.extend_from_slice(&(data.len() as u16).to_be_bytes());
+        body.extend_from_slice(&data);
+        body
+    }
+
+    fn u16_vector(values: &[u16]) -> Vec<u8> {
+        let mut data = ((values.len() * 2) as u16).to_be_bytes().to_vec();
+        for value in values {
+            data.extend_from_slice(&value.to_be_bytes());
+        }
+        data
+    }
+
+    fn server_name(name: &str) -> Vec<u8> {
+        let mut data = ((name.len() + 3) as u16).to_be_bytes().to_vec();
+        data.push(0);
+        data.extend_from_slice(&(name.len() as u16).to_be_bytes());
+        data.extend_from_slice(name.as_bytes());
+        data
+    }
+
+    #[test]
+    fn ja3_of_the_reference_client_hello() {
+        // Example of the JA3 README
+        let body = client_hello_body(
+            769,
+            &[47, 53, 5, 10, 49161, 49162, 49171, 49172, 50, 56, 19, 4],
+            &[(0, server_name("example.com")), (10, u16_vector(&[23, 24, 25])), (11, vec![1, 0])]
+        );
+        let hello = parse_client_hello(&body).unwrap();
+
+        assert_eq!(hello.server_name.as_deref(), Some("example.com"));
+        assert_eq!(hello.ja3_string(), "769,47-53-5-10-49161-49162-49171-49172-50-56-19-4,0-10-11,23-24-25,0");
+        assert_eq!(hello.ja3(), "ada70206e40642a3e4461f35503241d5");
+    }
+
+    #[test]
+    fn ja3_ignores_grease() {
+        let body = client_hello_body(
+            769,
+            &[0x0a0a, 47, 53],
+            &[(0x1a1a, Vec::new()), (10, u16_vector(&[0x2a2a, 23])), (11, vec![1, 0])]
+        );
+        let hello = parse_client_hello(&body).unwrap();
+
+        assert_eq!(hello.ja3_string(), "769,47-53,10-11,23,0");
+    }
+
+    #[test]
+    fn ja4_of_the_reference_client_hello() {
+        // Chrome ClientHello of the JA4 technical details, GREASE values included
+        let hello = ClientHello {
+            version: 0x0303,
+            supported_versions: vec![0x3a3a, 0x0304, 0x0303],
+            cipher_suites: vec![0x4a4a, 0x1301, 0x1302, 0x1303, 0xc02b, 0xc02f, 0xc02c, 0xc030, 0xcca9, 0xcca8, 0xc013, 0xc014, 0x009c, 0x009d, 0x002f, 0x0035],
+            extensions: vec![0x5a5a, 0x001b, 0x0000, 0x0033, 0x0010, 0x4469, 0x0017, 0x002d, 0x000d, 0x0005, 0x0023, 0x0012, 0x002b, 0xff01, 0x000b, 0x000a, 0x0015],
+            server_name: Some("example.com".to_string()),
+            alpn: vec!["h2".to_string(), "http/1.1".to_string()],
+            supported_groups: vec![0x6a6a, 0x001d, 0x0017, 0x0018],
+            ec_point_formats: vec![0],
+            signature_algorithms: vec![0x0403, 0x0804, 0x0401, 0x0503, 0x0805, 0x0501, 0x0806, 0x0601]
+        };
+
+        assert_eq!(hello.max_version(), 0x0304);
+        assert_eq!(hello.ja4(), "t13d1516h2_8daaf6152771_e5627efa2ab1");
+    }
+
+    #[test]
+    fn ja4_without_server_name_and_alpn() {
+        let body = client_hello_body(0x0303, &[0x002f], &[]);
+        let hello = parse_client_hello(&body).unwrap();
+
+        assert!(hello.ja4().starts_with("t12i010000_"));
+        assert!(hello.ja4().ends_with("_000000000000"));
+    }
+
+    /// Stream of a client on port `port` carrying `data`
+    fn stream(port: u16, data: &[u8]) -> StreamData {
+        let client = ("192.0.2.1".parse().unwrap(), port);
+        StreamData {
+            connection: ConnectionKey::new("eth0", &[], client, ("192.0.2.2".parse().unwrap(), 443)),
+            source: client,
+            from_initiator: true,
+            timestamp: Local::now(),
+            gap: false,
+            data: data.to_vec()
+        }
+    }
+
+    /// ClientHello in its handshake record
+    fn client_hello_record(name: &str) -> Vec<u8> {
+        let body = client_hello_body(0x0303, &[0x1301], &[(0, server_name(name))]);
+        let mut record = vec![RECORD_HANDSHAKE, 3, 1];
+        record.extend_from_slice(&((body.len() + 4) as u16).to_be_bytes());
+        record.push(HANDSHAKE_CLIENT_HELLO);
+        record.extend_from_slice(&(body.len() as u32).to_be_bytes()[1..]);
+        record.extend_from_slice(&body);
+        record
+    }
+
+    #[test]
+    fn client_hello_read_from_the_stream() {
+        let mut table = TlsTable::new(Duration::seconds(120), 16, 16);
+        let record = client_hello_record("example.com");
+
+        // Split across two segments
+        assert!(table.process(&stream(40000, &record[..20])).is_empty());
+        let handshakes = table.process(&stream(40000, &record[20..]));
+
+        assert!(matches!(&handshakes[..], [TlsHandshake::ClientHello(hello)] if hello.server_name.as_deref() == Some("example.com")));
+    }
+
+    #[test]
+    fn streams_that_do_not_start_like_tls_are_not_remembered() {
+        let mut table = TlsTable::new(Duration::seconds(120), 16, 16);
+
+        assert!(table.process(&stream(40000, b"GET / HTTP/1.1\r\n\r\n")).is_empty());
+        assert!(table.streams.is_empty());
+    }
+
+    #[test]
+    fn full_table_drops_the_stream_idle_for_the_longest_time() {
+        let mut table = TlsTable::new(Duration::seconds(120), 2, 16);
+        let record = client_hello_record("example.com");
+
+        let now = Local::now();
+        for (port, seconds) in [(40000, 0), (40001, 1), (40002, 2)] {
+            // Every handshake still waits for the end of its record
+            let data = StreamData { timestamp: now + Duration::seconds(seconds), ..stream(port, &record[..20]) };
+            table.process(&data);
+        }
+
+        assert_eq!(table.streams.len(), 2);
+        assert!(!table.streams.keys().any(|(connection, _)| connection.low.1 == 40000));
+    }
+
+    fn certificate(not_before: DateTime<Local>, not_after: DateTime<Local>, self_signed: bool) -> CertificateInfo {
+        CertificateInfo {
+            subject: "www.example.com".to_string(),
+            sans: vec!["www.example.com".to_string(), "example.com".to_string()],
+            issuer: "Example Root CA".to_string(),
+            not_before,
+            not_after,
+            self_signed
+        }
+    }
+
+    #[test]
+    fn inventory_status_of_the_certificates() {
+        let mut inventory = TlsInventory::new(16);
+        let now = Local::now();
+        let year = Duration::days(365);
+        let server = |port| SocketAddr::new("192.0.2.2".parse().unwrap(), port);
+
+        inventory.add_certificate(server(1), "valid", &certificate(now - year, now + year, false), now);
+        inventory.add_certificate(server(2), "expired", &certificate(now - year * 2, now - year, false), now);
+        inventory.add_certificate(server(3), "future", &certificate(now + year, now + year * 2, false), now);
+        inventory.add_certificate(server(4), "own", &certificate(now - year * 2, now - year, true), now);
+        let status: Vec<(String, String)> = inventory.entries().into_iter().map(|entry| (entry.server_name, entry.status)).collect();
+
+        assert_eq!(status, [
+            ("expired".to_string(), "expired".to_string()),
+            ("future".to_string(), "not-yet-valid".to_string()),
+            ("own".to_string(), "expired,self-signed".to_string()),
+            ("valid".to_string(), "valid".to_string())
+        ]);
+    }
+
+    #[test]
+    fn inventory_keeps_sessions_and_certificate_of_a_server() {
+        let mut inventory = TlsInventory::new(16);
+        let now = Local::now();
+        let server = SocketAddr::new("192.0.2.2".parse().unwrap(), 443);
+        let hello = ServerHello { version: 0x0303, cipher_suite: 0xc02f };
+
+        inventory.add_session(server, "www.example.com", &hello, now);
+        inventory.add_certificate(server, "www.example.com", &certificate(now - Duration::days(1), now + Duration::days(1), false), now);
+        inventory.add_session(server, "www.example.com", &hello, now + Duration::seconds(1));
+        let entries = inventory.entries();
+
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].sessions, 2);
+        assert_eq!(entries[0].subject, "www.example.com");
+        assert_eq!(entries[0].sans, "www.example.com example.com");
+        assert_eq!(entries[0].issuer, "Example Root CA");
+        assert_eq!(entries[0].last_seen, now + Duration::seconds(1));
+        // No certificate seen (TLS 1.3) for the same address under another name
+        inventory.add_session(server, "-", &hello, now);
+        assert_eq!(inventory.entries()[0].status, "-");
+    }
+
+    #[test]
+    fn truncated_client_hello() {
+        let body = client_hello_body(0x0303, &[0x002f, 0x0035], &[(0, server_name("example.com"))]);
+
+        assert!(parse_client_hello(&body[..body.len() - 1]).is_none());
+        assert!(parse_client_hello(&body[..40]).is_none());
+    }
+}
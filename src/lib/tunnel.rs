@@ -0,0 +1,269 @@
+use std::fmt;
+use std::net::IpAddr;
+
+use serde::Serializer;
+
+use crate::utils::join_values;
+
+/// IP protocol number of GRE, missing from pktparse
+pub const IPPROTO_GRE: u8 = 47;
+
+/// IANA port of VXLAN and the one used by the Linux kernel before it was assigned
+pub const VXLAN_PORTS: [u16; 2] = [4789, 8472];
+pub const GENEVE_PORT: u16 = 6081;
+
+const ETHERTYPE_IPV4: u16 = 0x0800;
+const ETHERTYPE_IPV6: u16 = 0x86dd;
+const ETHERTYPE_TRANSPARENT_BRIDGING: u16 = 0x6558;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TunnelKind {
+    Gre,
+    Vxlan,
+    Geneve,
+    IpInIp,
+    /// IPv6 in IPv4 (protocol 41)
+    SixInFour
+}
+
+impl TunnelKind {
+    /// Tunnels carried by UDP, the datagram is reported as such when they can't be decapsulated
+    pub fn over_udp(&self) -> bool {
+        matches!(self, TunnelKind::Vxlan | TunnelKind::Geneve)
+    }
+}
+
+impl fmt::Display for TunnelKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TunnelKind::Gre => write!(f, "GRE"),
+            TunnelKind::Vxlan => write!(f, "VXLAN"),
+            TunnelKind::Geneve => write!(f, "Geneve"),
+            TunnelKind::IpInIp => write!(f, "IP-in-IP"),
+            TunnelKind::SixInFour => write!(f, "6in4")
+        }
+    }
+}
+
+/// Tunnel a packet was carried in, by its outer endpoints
+#[derive(Debug, Clone)]
+pub struct Tunnel {
+    pub kind: TunnelKind,
+    pub outer_src: IpAddr,
+    pub outer_dest: IpAddr,
+    /// VNI of VXLAN and Geneve, key of GRE
+    pub id: Option<u32>
+}
+
+impl fmt::Display for Tunnel {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {} -> {}", self.kind, self.outer_src, self.outer_dest)?;
+        match (self.kind, self.id) {
+            (TunnelKind::Gre, Some(key)) => write!(f, " key {}", key),
+            (_, Some(vni)) => write!(f, " VNI {}", vni),
+            (_, None) => Ok(())
+        }
+    }
+}
+
+/// Serialize the tunnels of a packet as a single field, outermost first
+pub fn serialize_tunnels<S: Serializer>(tunnels: &[Tunnel], serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&join_values(tunnels, ", "))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InnerProtocol {
+    Ethernet,
+    IPv4,
+    IPv6
+}
+
+/// Packet found inside a tunnel
+pub struct Decapsulated<'a> {
+    pub inner: InnerProtocol,
+    pub id: Option<u32>,
+    pub payload: &'a [u8]
+}
+
+/// Tunnel carried by a UDP datagram, by its destination port (the source one is picked for load balancing)
+pub fn udp_tunnel(datagram: &[u8]) -> Option<TunnelKind> {
+    if datagram.len() < 8 {
+        return None;
+    }
+    match u16::from_be_bytes([datagram[2], datagram[3]]) {
+        port if VXLAN_PORTS.contains(&port) => Some(TunnelKind::Vxlan),
+        GENEVE_PORT => Some(TunnelKind::Geneve),
+        _ => None
+    }
+}
+
+fn inner_by_ethertype(ethertype: u16) -> Option<InnerProtocol> {
+    match ethertype {
+        ETHERTYPE_IPV4 => Some(InnerProtocol::IPv4),
+        ETHERTYPE_IPV6 => Some(InnerProtocol::IPv6),
+        ETHERTYPE_TRANSPARENT_BRIDGING => Some(InnerProtocol::Ethernet),
+        _ => None
+    }
+}
+
+fn parse_gre(packet: &[u8]) -> Option<Decapsulated<'_>> {
+    if packet.len() < 4 {
+        return None;
+    }
+    let flags = u16::from_be_bytes([packet[0], packet[1]]);
+    // Version 1 is the enhanced GRE of PPTP, the routing field (RFC 1701) is obsolete
+    if flags & 0x0007 != 0 || flags & 0x4000 != 0 {
+        return None;
+    }
+    let inner = inner_by_ethertype(u16::from_be_bytes([packet[2], packet[3]]))?;
+
+    let mut offset = 4;
+    // Checksum and reserved
+    if flags & 0x8000 != 0 {
+        offset += 4;
+    }
+    let mut id = None;
+    if flags & 0x2000 != 0 {
+        let key = packet.get(offset..offset + 4)?;
+        id = Some(u32::from_be_bytes([key[0], key[1], key[2], key[3]]));
+        offset += 4;
+    }
+    // Sequence number
+    if flags & 0x1000 != 0 {
+        offset += 4;
+    }
+
+    Some(Decapsulated { inner, id, payload: packet.get(offset..)? })
+}
+
+fn parse_vxlan(payload: &[u8]) -> Option<Decapsulated<'_>> {
+    // The I flag tells the VNI is valid
+    if payload.len() < 8 || payload[0] & 0x08 == 0 {
+        return None;
+    }
+    Some(Decapsulated {
+        inner: InnerProtocol::Ethernet,
+        id: Some(u32::from_be_bytes([0, payload[4], payload[5], payload[6]])),
+        payload: &payload[8..]
+    })
+}
+
+fn parse_geneve(payload: &[u8]) -> Option<Decapsulated<'_>> {
+    if payload.len() < 8 || payload[0] >> 6 != 0 {
+        return None;
+    }
+    // Options length in 4-octet units
+    let header_len = 8 + (payload[0] & 0x3f) as usize * 4;
+    Some(Decapsulated {
+        inner: inner_by_ethertype(u16::from_be_bytes([payload[2], payload[3]]))?,
+        id: Some(u32::from_be_bytes([0, payload[4], payload[5], payload[6]])),
+        payload: payload.get(header_len..)?
+    })
+}
+
+/// Find the packet carried by a tunnel: the GRE or IP payload, or the payload of the UDP datagram
+/// for VXLAN and Geneve
+pub fn decapsulate(kind: TunnelKind, packet: &[u8]) -> Option<Decapsulated<'_>> {
+    match kind {
+        TunnelKind::Gre => parse_gre(packet),
+        TunnelKind::Vxlan => parse_vxlan(packet),
+        TunnelKind::Geneve => parse_geneve(packet),
+        // Protocol 4 and 41 carry IPv4 and IPv6, the version nibble tells which one
+        TunnelKind::IpInIp | TunnelKind::SixInFour => {
+            let inner = match packet.first()? >> 4 {
+                4 => InnerProtocol::IPv4,
+                6 => InnerProtocol::IPv6,
+                _ => return None
+            };
+            Some(Decapsulated { inner, id: None, payload: packet })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const INNER_IPV4: [u8; 4] = [0x45, 0, 0, 20];
+
+    #[test]
+    fn gre_with_checksum_key_and_sequence() {
+        let mut packet = vec![0xb0, 0x00, 0x08, 0x00, 0, 0, 0, 0, 0, 0, 0x30, 0x39, 0, 0, 0, 1];
+        packet.extend_from_slice(&INNER_IPV4);
+        let decapsulated = decapsulate(TunnelKind::Gre, &packet).unwrap();
+
+        assert_eq!(decapsulated.inner, InnerProtocol::IPv4);
+        assert_eq!(decapsulated.id, Some(12345));
+        assert_eq!(decapsulated.payload, INNER_IPV4);
+    }
+
+    #[test]
+    fn gre_transparent_ethernet_bridging() {
+        let packet = [0x00, 0x00, 0x65, 0x58, 0xff, 0xff];
+        let decapsulated = decapsulate(TunnelKind::Gre, &packet).unwrap();
+
+        assert_eq!(decapsulated.inner, InnerProtocol::Ethernet);
+        assert_eq!(decapsulated.id, None);
+        assert_eq!(decapsulated.payload, [0xff, 0xff]);
+    }
+
+    #[test]
+    fn gre_version_1_and_truncated_key() {
+        // Enhanced GRE (PPTP)
+        assert!(decapsulate(TunnelKind::Gre, &[0x30, 0x81, 0x88, 0x0b, 0, 0, 0, 0]).is_none());
+        // Key flag set, key missing
+        assert!(decapsulate(TunnelKind::Gre, &[0x20, 0x00, 0x08, 0x00, 0, 0]).is_none());
+        // Unknown protocol
+        assert!(decapsulate(TunnelKind::Gre, &[0x00, 0x00, 0x88, 0x0b]).is_none());
+    }
+
+    #[test]
+    fn vxlan_vni() {
+        let packet = [0x08, 0, 0, 0, 0x00, 0x00, 0x2a, 0, 0xaa, 0xbb];
+        let decapsulated = decapsulate(TunnelKind::Vxlan, &packet).unwrap();
+
+        assert_eq!(decapsulated.inner, InnerProtocol::Ethernet);
+        assert_eq!(decapsulated.id, Some(42));
+        assert_eq!(decapsulated.payload, [0xaa, 0xbb]);
+        // The I flag is required
+        assert!(decapsulate(TunnelKind::Vxlan, &[0, 0, 0, 0, 0, 0, 0x2a, 0]).is_none());
+    }
+
+    #[test]
+    fn geneve_skips_its_options() {
+        let mut packet = vec![0x01, 0, 0x86, 0xdd, 0x01, 0x02, 0x03, 0, 0xde, 0xad, 0xbe, 0xef];
+        packet.extend_from_slice(&[0x60, 0, 0, 0]);
+        let decapsulated = decapsulate(TunnelKind::Geneve, &packet).unwrap();
+
+        assert_eq!(decapsulated.inner, InnerProtocol::IPv6);
+        assert_eq!(decapsulated.id, Some(0x010203));
+        assert_eq!(decapsulated.payload, [0x60, 0, 0, 0]);
+        // Options longer than the packet
+        assert!(decapsulate(TunnelKind::Geneve, &[0x3f, 0, 0x08, 0x00, 0, 0, 0, 0]).is_none());
+    }
+
+    #[test]
+    fn ip_in_ip_by_version() {
+        assert_eq!(decapsulate(TunnelKind::IpInIp, &INNER_IPV4).unwrap().inner, InnerProtocol::IPv4);
+        assert_eq!(decapsulate(TunnelKind::SixInFour, &[0x60, 0, 0, 0]).unwrap().inner, InnerProtocol::IPv6);
+        assert!(decapsulate(TunnelKind::IpInIp, &[0x50, 0, 0, 0]).is_none());
+        assert!(decapsulate(TunnelKind::IpInIp, &[]).is_none());
+    }
+
+    #[test]
+    fn udp_tunnels_by_destination_port() {
+        assert_eq!(udp_tunnel(&[0xc0, 0x00, 0x12, 0xb5, 0, 16, 0, 0]), Some(TunnelKind::Vxlan));
+        assert_eq!(udp_tunnel(&[0xc0, 0x00, 0x21, 0x18, 0, 16, 0, 0]), Some(TunnelKind::Vxlan));
+        assert_eq!(udp_tunnel(&[0xc0, 0x00, 0x17, 0xc1, 0, 16, 0, 0]), Some(TunnelKind::Geneve));
+        // VXLAN source port, any destination
+        assert_eq!(udp_tunnel(&[0x12, 0xb5, 0x00, 0x35, 0, 16, 0, 0]), None);
+    }
+
+    #[test]
+    fn tunnel_display() {
+        let tunnel = Tunnel { kind: TunnelKind::Vxlan, outer_src: "10.0.0.1".parse().unwrap(), outer_dest: "10.0.0.2".parse().unwrap(), id: Some(42) };
+        assert_eq!(tunnel.to_string(), "VXLAN 10.0.0.1 -> 10.0.0.2 VNI 42");
+        let tunnel = Tunnel { kind: TunnelKind::Gre, id: Some(7), ..tunnel };
+        assert_eq!(tunnel.to_string(), "GRE 10.0.0.1 -> 10.0.0.2 key 7");
+    }
+}
@@ -1,7 +1,10 @@
+use std::fmt::Display;
+
 use pktparse::ethernet::MacAddress;
+use serde::Serializer;
 
 pub fn mac_to_str(addr: MacAddress) -> String {
-    format!("{:x}:{:x}:{:x}:{:x}:{:x}:{:x}",
+    format!("{:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}",
             addr.0[0],
             addr.0[1],
             addr.0[2],
@@ -11,33 +14,19 @@ pub fn mac_to_str(addr: MacAddress) -> String {
     )
 }
 
-pub fn tcp_l7(port: u16) -> String {
-    let app_layer = match  port {
-        80 => "http".to_string(),
-        443 => "https".to_string(),
-        21 => "ssh".to_string(),
-        23 => "telnet".to_string(),
-        25 => "smtp".to_string(),
-        53 => "DNS".to_string(),
-        110 => "POP3".to_string(),
-        143 => "IMAP".to_string(),
-        194 => "IRC".to_string(),
-        853 => "DNSOverTLS".to_string(),
-        _ => "unknown".to_string()
-    };
-
-    app_layer
+/// Serialize a MAC address as a single field, "-" if the link layer has none
+pub fn serialize_mac<S: Serializer>(addr: &Option<MacAddress>, serializer: S) -> Result<S::Ok, S::Error> {
+    match addr {
+        Some(addr) => serializer.serialize_str(&mac_to_str(*addr)),
+        None => serializer.serialize_str("-")
+    }
 }
 
-pub fn udp_l7(port: u16) -> String {
-    let app_layer = match  port {
-        53 => "DNS".to_string(),
-        67..= 68 => "DHCP".to_string(),
-        69 => "TFTP".to_string(),
-        123 => "NTP".to_string(),
-        161..=162 => "SNMP".to_string(),
-        _ => "unknown".to_string()
-    };
+/// Serialize a list as a single field (e.g. VLAN stack "100.20"), so that csv rows keep a fixed number of columns
+pub fn serialize_joined<S: Serializer, T: Display>(values: &[T], serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&join_values(values, "."))
+}
 
-    app_layer
+pub fn join_values<T: Display>(values: &[T], separator: &str) -> String {
+    values.iter().map(|v| v.to_string()).collect::<Vec<String>>().join(separator)
 }
\ No newline at end of file
@@ -10,26 +10,38 @@ use std::io::Write;
 use std::io;
 use std::string::String;
 
-use pcap::{Device, Capture};
-use packet_swiffer::parser::{handle_ethernet_frame, Packet};
+use pcap::{Activated, Capture, Device, Precision};
+use packet_swiffer::parser::{handle_frame, Frame, Packet, ParserState};
 use packet_swiffer::args::Args;
-use packet_swiffer::report::{produce_hashmap, ReportWriter, setup_directory};
-use::packet_swiffer::menu::menu;
+use packet_swiffer::report::{produce_hashmap, ReportWriter, setup_directory, write_table};
+use packet_swiffer::savefile::SavefileWriter;
+use packet_swiffer::stream::StreamDumper;
+use::packet_swiffer::menu::{menu, Settings};
 
 use clap::Parser;
 
 fn main() {
 
     let args = Args::parse();
-    let interface_name = args.interface;
+    let interface_names = args.interface;
     let list_mode = args.list;
     let promisc_mode = args.promisc;
+    let read_file = args.read;
+    let offline_mode = read_file.is_some();
+    let save_mode = args.savefile;
+    let savefile_size = args.savefile_size.map(|mb| mb * 1024 * 1024);
+    let routers = args.router;
+    let dhcp_servers = args.dhcp_server;
+    let dump_streams = args.dump_streams;
+    let dns_cache_mode = args.dns_cache;
+    let services_file = args.services;
+    let oui_file = args.oui;
 
     // Find the network interface with the provided name
     let interfaces = Device::list().unwrap();
 
     // Handle list mode
-    if list_mode && interface_name == "listview__".to_string() {
+    if list_mode && interface_names.is_empty() {
         println!("The following interfaces are available");
         println!("{0: <20} | {1: <20}", "Name", "Description");
         println!("---------------------------------------------------------------------");
@@ -38,38 +50,66 @@ fn main() {
         process::exit(0);
     }
 
-    if !list_mode && interface_name == "listview__".to_string() {
+    if !list_mode && !offline_mode && interface_names.is_empty() {
         eprintln!("Error - Specify at least one of the following arguments\n\t-i, --interface:\tName of the interface to be used for the sniffing");
+        eprintln!("\t-r, --read:\t\tRead packets from a pcap/pcapng file (\"-\" for stdin)");
         eprintln!("\t-l, --list:\t\tShow the net interfaces present in the system without launching the sniffing");
         process::exit(1);
     }
 
-    // Print menu
-    let settings = menu();
+    // Print menu (not when reading a file, stdin may be the capture itself)
+    let settings = if offline_mode { Settings::new() } else { menu() };
     // println!("Filters in main: {}", settings.filters);
     let report_fn = if settings.filename.is_some() { settings.filename.unwrap() } else { args.filename };
     let csv_mode = if settings.csv.is_some() { settings.csv.unwrap() } else { args.csv };
     let timeout = if settings.timeout.is_some() { settings.timeout.unwrap() } else { args.timeout };
 
-    // println!("Promisc mode: {}", promisc_mode);
-    let interface = interfaces
-        .into_iter()
-        .filter(|i| i.name == interface_name)
-        .next()
-        .unwrap_or_else(|| {
-            eprintln!("No such network interface: {}", interface_name);
-            process::exit(1);
-        });
-    let cloned_interface = interface.clone();
+    // Setting up pcap captures, either from a savefile or from one or more live interfaces
+    // Timestamps are requested with nanosecond precision wherever libpcap can provide them
+    let captures: Vec<(Capture<dyn Activated>, String, Precision)> = match &read_file {
+        Some(path) => {
+            let cap = Capture::from_file_with_precision(path, Precision::Nano).unwrap_or_else(|e| {
+                eprintln!("Unable to read {}: {}", path, e);
+                process::exit(1);
+            });
+            vec![(cap.into(), path.to_string(), Precision::Nano)]
+        },
+        None => {
+            // println!("Promisc mode: {}", promisc_mode);
+            interface_names.iter().map(|interface_name| {
+                let interface = interfaces
+                    .iter()
+                    .find(|i| &i.name == interface_name)
+                    .cloned()
+                    .unwrap_or_else(|| {
+                        eprintln!("No such network interface: {}", interface_name);
+                        process::exit(1);
+                    });
+                let name = interface.name.clone();
+                let precision = if cfg!(target_os = "linux") { Precision::Nano } else { Precision::Micro };
+
+                let cap = Capture::from_device(interface).unwrap()
+                    .promisc(promisc_mode)
+                    .immediate_mode(true)
+                    .precision(precision)
+                    .open().unwrap_or_else(|e| {
+                        eprintln!("Unable to open {}: {}", name, e);
+                        process::exit(1);
+                    });
+                (cap.into(), name, precision)
+            }).collect()
+        }
+    };
+    let multi_capture = captures.len() > 1;
+
+    // Create the directory for the sniffing reports
+    let dirname = setup_directory(&report_fn);
 
-    // Setting up pcap capture
-    let mut cap = Capture::from_device(interface).unwrap()
-        .promisc(promisc_mode)
-        .immediate_mode(true)
-        .open().unwrap();
+    // Index of the report being filled, used to align the savefiles with the reports
+    let report_index = Arc::new(Mutex::new(0));
 
-    // Channel used to pass packets between sniffing thread and parsing thread
-    let (tx_thread, rx_thread) = channel::<Vec<u8>>();
+    // Channel used to pass packets between sniffing threads and parsing thread
+    let (tx_thread, rx_thread) = channel::<Frame>();
 
     // Channel used to pass parsed packets to the report_thread
     let (tx_report, rx_report) = channel::<Packet>();
@@ -78,25 +118,62 @@ fn main() {
     let pair2 = Arc::clone(&pair);
     let pair3 = Arc::clone(&pair);
 
-    // Thread used to get packets (calls next() method)
-    let sniffing_thread = thread::spawn(move | | {
-        let (lock, _cvar) = &*pair;
-        // println!("Premi il tasto P per mettere in pausa lo sniffing");
-        if settings.filters != "" {
-            cap.filter(&settings.filters, false).unwrap();
-        }
-        while let Ok(packet) = cap.next_packet() {
-            let pause = lock.lock().unwrap();
-            let owned_packet = packet.to_owned();
-            if !*pause {
-                tx_thread.send(owned_packet.to_vec()).unwrap();
+    // One thread per capture used to get packets (calls next() method), all feeding the same parsing thread
+    let sniffing_threads: Vec<_> = captures.into_iter().map(|(mut cap, capture_name, precision)| {
+        let pair = Arc::clone(&pair);
+        let report_index = Arc::clone(&report_index);
+        let tx_thread = tx_thread.clone();
+        let filters = settings.filters.clone();
+
+        // With more than one interface every savefile gets the name of its interface
+        let savefile_fn = if multi_capture { format!("{}-{}", report_fn, capture_name) } else { report_fn.clone() };
+        let mut savefile = save_mode.then(|| {
+            SavefileWriter::new(cap.get_datalink(), precision, &dirname, &savefile_fn, savefile_size)
+        });
+
+        thread::spawn(move | | {
+            let (lock, _cvar) = &*pair;
+            let linktype = cap.get_datalink();
+            // println!("Premi il tasto P per mettere in pausa lo sniffing");
+            if filters != "" {
+                cap.filter(&filters, false).unwrap();
             }
-            drop(lock);
-        }
-    });
+            // Timestamp of the first frame read from a capture file, and index of the last one
+            let mut first_timestamp = None;
+            let mut offline_index = 0;
+            while let Ok(packet) = cap.next_packet() {
+                let pause = lock.lock().unwrap();
+                if !*pause {
+                    let mut frame = Frame::new(&capture_name, linktype, packet.header, precision, packet.data);
+                    // The same index names the savefile and picks the report of the frame. A capture
+                    // file is cut on the time of its frames, however fast it is read (never going back
+                    // to a report already closed for frames out of order)
+                    let index = match offline_mode {
+                        true => {
+                            let first = *first_timestamp.get_or_insert(frame.timestamp);
+                            offline_index = offline_index.max(((frame.timestamp - first).num_seconds() / timeout) as i32);
+                            offline_index
+                        },
+                        false => *report_index.lock().unwrap()
+                    };
+                    if let Some(savefile) = savefile.as_mut() {
+                        savefile.write(&packet, index);
+                    }
+                    frame.report_index = index;
+                    tx_thread.send(frame).unwrap();
+                }
+                drop(pause);
+            }
+            if let Some(savefile) = savefile.as_mut() {
+                savefile.close();
+            }
+        })
+    }).collect();
+    // Only the sniffing threads keep a sender, the parsing thread stops once all of them are done
+    drop(tx_thread);
 
-    // Thread used to pause/resume
-    let pause_thread = thread::spawn(move || {
+    // Thread used to pause/resume, only meaningful while sniffing live
+    let pause_thread = (!offline_mode).then(|| thread::spawn(move || {
         let (lock, _cvar) = &*pair2;
         let mut buffer = String::new();
         loop {
@@ -121,7 +198,28 @@ fn main() {
             }
             
         }
-    });
+    }));
+    // Parser state (fragments waiting for reassembly, anomalies) shared with the report thread
+    let mut parser_state = ParserState::new();
+    parser_state.routers = routers;
+    parser_state.dhcp_servers = dhcp_servers;
+    if let Some(path) = &services_file {
+        if let Err(e) = parser_state.classifier.services.load(path) {
+            eprintln!("Unable to read {}: {}", path, e);
+            process::exit(1);
+        }
+    }
+    if let Some(path) = &oui_file {
+        if let Err(e) = parser_state.oui.load(path) {
+            eprintln!("Unable to read {}: {}", path, e);
+            process::exit(1);
+        }
+    }
+    // Files are closed along with their stream, at most 512 are open at the same time
+    parser_state.dump = dump_streams.then(|| StreamDumper::new(&format!("{}/streams", dirname), parser_state.streams.timeout, 512));
+    let parser_state = Arc::new(Mutex::new(parser_state));
+    let parser_state_report_clone = parser_state.clone();
+
     let packet_arrived = Arc::new(Mutex::new(false));
     let packet_arrived_parsing_clone = packet_arrived.clone();
     let packet_arrived_report_clone = packet_arrived.clone();
@@ -135,7 +233,7 @@ fn main() {
             *packet_arrived_flag = true;
             drop(packet_arrived_flag);
 
-            let packet_string = handle_ethernet_frame(&cloned_interface, &p);
+            let packet_string = handle_frame(&p, &mut parser_state.lock().unwrap());
             match packet_string {
                 Ok(pk) => {
                     println!("{}", pk);
@@ -147,62 +245,105 @@ fn main() {
         }
     });
 
-    let timer_flag = Arc::new(Mutex::new(false));
-
+    let report_index_timer_clone = report_index.clone();
 
     let report_thread = thread::spawn(move | | {
         let timer = timer::Timer::new();
-        let timer_flag_clone = timer_flag.clone();
         let mut index = 0;
         let filename = format!("{}", report_fn);
 
-        let _guard_timer = timer.schedule_repeating(chrono::Duration::seconds(timeout.into()), move || {
+        // Capture files are cut on the timestamps of their frames, see the sniffing threads
+        let _guard_timer = (!offline_mode).then(|| timer.schedule_repeating(chrono::Duration::seconds(timeout.into()), move || {
             let (lock, _cvar) = &*pair3;
-            let packet_arrived_flag = packet_arrived_report_clone.lock().unwrap();
+            let mut packet_arrived_flag = packet_arrived_report_clone.lock().unwrap();
             let pause_flag = lock.lock().unwrap();
             if *pause_flag == false && *packet_arrived_flag == true {
-                let mut flag = timer_flag_clone.lock().unwrap();
-                *flag = true;
-                drop(flag);
+                // Frames captured from now on belong to the next report
+                *report_index_timer_clone.lock().unwrap() += 1;
+                *packet_arrived_flag = false;
             }
             drop(pause_flag);
             drop(packet_arrived_flag);
-        });
-
-        // Create the directory for the sniffing reports
-        let dirname = setup_directory(&filename);
+        }));
 
+        // First packet of the next report, received while filling the current one
+        let mut next_packet: Option<Packet> = None;
         loop {
-            let mut buffer = Vec::<Packet>::new();
-            
+            let mut buffer: Vec<Packet> = next_packet.take().into_iter().collect();
+            // Stays true if the parsing thread is gone (end of the capture file)
+            let mut finished = true;
+
             while let Ok(packet) = rx_report.recv() {
-                buffer.push(packet);
-                let mut flag = timer_flag.lock().unwrap();
-                if *flag{
-                    *flag = false;
-                    drop(flag);
+                // The report is cut where the savefiles are, on the index the frame was captured with
+                if packet.report_index > index {
+                    next_packet = Some(packet);
+                    finished = false;
                     break;
                 }
-                drop(flag);
+                buffer.push(packet);
             }
 
             let mut rw = ReportWriter::new(csv_mode, &dirname, &filename, index);
             rw.report_init();
 
+            let (now, connection_timeout) = {
+                let state = parser_state_report_clone.lock().unwrap();
+                (state.last_seen.unwrap_or(chrono::offset::Local::now()), state.connections.timeout)
+            };
             let report = produce_hashmap(buffer);
-            for (_, info) in report {
+            for (_, mut info) in report {
+                info.check_timeout(now, connection_timeout);
                 rw.write(info);
             }
 
+            let anomalies = parser_state_report_clone.lock().unwrap().take_anomalies();
+            rw.write_section("anomalies", &["timestamp", "kind", "description"], &anomalies);
+
+            let dns_transactions = parser_state_report_clone.lock().unwrap().take_dns_transactions();
+            rw.write_section("dns", &["timestamp", "client", "server", "id", "name", "type", "rcode", "answers", "latency_ms"], &dns_transactions);
+
+            let http_summary = parser_state_report_clone.lock().unwrap().take_http_summary();
+            rw.write_section("http", &["category", "value", "count"], &http_summary);
+
+            // Rewritten at every report, so that it is up to date whenever the session ends
+            if dns_cache_mode {
+                let dns_cache = parser_state_report_clone.lock().unwrap().dns_cache.entries();
+                write_table(csv_mode, &dirname, &format!("{}-dns-cache", filename), &["address", "name", "ttl", "first_seen", "last_seen"], &dns_cache);
+            }
+
+            let dhcp_leases = parser_state_report_clone.lock().unwrap().dhcp_leases.entries();
+            if !dhcp_leases.is_empty() {
+                write_table(csv_mode, &dirname, &format!("{}-dhcp-leases", filename), &["client_mac", "address", "hostname", "vendor_class", "server", "lease_time", "state", "first_seen", "last_seen"], &dhcp_leases);
+            }
+
+            let arp_table = parser_state_report_clone.lock().unwrap().arp.entries();
+            if !arp_table.is_empty() {
+                write_table(csv_mode, &dirname, &format!("{}-arp-table", filename), &["interface", "vlan", "address", "mac", "changes", "first_seen", "last_seen"], &arp_table);
+            }
+
+            let tls_inventory = parser_state_report_clone.lock().unwrap().tls.inventory.entries();
+            if !tls_inventory.is_empty() {
+                write_table(csv_mode, &dirname, &format!("{}-tls-inventory", filename), &["server", "server_name", "version", "cipher", "subject", "sans", "issuer", "not_before", "not_after", "status", "sessions", "first_seen", "last_seen"], &tls_inventory);
+            }
+
             println!("[{}] Report #{} generated", chrono::offset::Local::now().naive_local(), index);
             rw.close();
-            index += 1;
+            index = next_packet.as_ref().map(|packet| packet.report_index).unwrap_or(index + 1);
+
+            if finished {
+                println!("[{}] End of capture reached", chrono::offset::Local::now().naive_local());
+                break;
+            }
         }
     });
 
     // joining the threads as a last thing to do
-    pause_thread.join().unwrap();
-    sniffing_thread.join().unwrap();
+    if let Some(pause_thread) = pause_thread {
+        pause_thread.join().unwrap();
+    }
+    for sniffing_thread in sniffing_threads {
+        sniffing_thread.join().unwrap();
+    }
     parsing_thread.join().unwrap();
     report_thread.join().unwrap();
 }